@@ -1,15 +1,20 @@
 use crate::eval::{Scope, Value};
+use crate::jobs;
+use crate::pipe::{self, Input, Output, Streams};
 use lazy_static::lazy_static;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fs;
-use std::process::Command;
+use std::io::{self, Write};
+use std::process::{Child, Command, ExitStatus, Stdio};
 use std::rc::Rc;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use which::which;
 mod flags;
-use flags::CommandFlags;
+pub use flags::CommandFlags;
 
+mod alias;
 mod basename;
 mod cat;
 mod cd;
@@ -21,6 +26,8 @@ mod exit;
 mod find;
 mod grep;
 mod help;
+mod history;
+mod job_control;
 mod ls;
 mod mkdir;
 mod mv;
@@ -30,18 +37,78 @@ mod run;
 mod vars;
 mod wc;
 
-pub trait Exec {
-    fn exec(&self, name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String>;
+pub use alias::{get_alias, list_aliases};
+pub use cd::dir_stack_depth;
+
+/// The kind of arguments a command expects, used by TAB completion
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ArgType {
+    Path,
+    Dir,
+    Command,
+}
+
+/// Commands are shared by all threads via the registry, and builtins may run in
+/// threads of their own as pipeline stages.
+pub trait Exec: Send + Sync {
+    fn exec(
+        &self,
+        name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String>;
     fn is_external(&self) -> bool;
+
+    /// Is this a function defined in the interpreter language?
+    fn is_function(&self) -> bool {
+        false
+    }
+
+    /// Start executing without waiting for completion, so that the command
+    /// can run concurrently with other pipeline stages (external programs only).
+    fn spawn(
+        &self,
+        _args: &Vec<String>,
+        _scope: &Rc<Scope>,
+        _streams: &mut Streams,
+    ) -> Result<Child, String> {
+        Err("Only external programs can be spawned".to_string())
+    }
+
+    /// Can the command run in a thread of its own, as a pipeline stage? Commands that
+    /// use or change the state of the interpreter, e.g. its variables or jobs, cannot.
+    fn is_thread_safe(&self) -> bool {
+        !self.is_external()
+    }
+
+    /// Flags accepted by the command (builtins only).
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        None
+    }
+
+    /// The source text that defines the command (functions only), used to
+    /// define it in another shell process.
+    fn definition(&self) -> Option<String> {
+        None
+    }
+
+    fn arg_type(&self) -> ArgType {
+        ArgType::Path
+    }
 }
 
 #[derive(Clone)]
 pub struct ShellCommand {
     name: String,
-    inner: Rc<dyn Exec>,
+    inner: Arc<dyn Exec>,
 }
 
 impl ShellCommand {
+    pub fn new(name: String, inner: Arc<dyn Exec>) -> Self {
+        Self { name, inner }
+    }
+
     pub fn name(&self) -> &String {
         &self.name
     }
@@ -54,16 +121,43 @@ impl Debug for ShellCommand {
 }
 
 impl Exec for ShellCommand {
-    fn exec(&self, name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String> {
-        self.inner.exec(name, args, scope)
+    fn exec(
+        &self,
+        name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        self.inner.exec(name, args, scope, streams)
     }
     fn is_external(&self) -> bool {
         self.inner.is_external()
     }
+    fn is_function(&self) -> bool {
+        self.inner.is_function()
+    }
+    fn is_thread_safe(&self) -> bool {
+        self.inner.is_thread_safe()
+    }
+    fn spawn(
+        &self,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Child, String> {
+        self.inner.spawn(args, scope, streams)
+    }
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        self.inner.cli_flags()
+    }
+    fn arg_type(&self) -> ArgType {
+        self.inner.arg_type()
+    }
+    fn definition(&self) -> Option<String> {
+        self.inner.definition()
+    }
 }
 
-unsafe impl Send for ShellCommand {}
-
 lazy_static! {
     pub static ref COMMAND_REGISTRY: Mutex<HashMap<String, ShellCommand>> =
         Mutex::new(HashMap::new());
@@ -82,7 +176,7 @@ pub fn get_command(name: &str) -> Option<ShellCommand> {
         if let Some(path) = locate_executable(name) {
             register_command(ShellCommand {
                 name: name.to_string(),
-                inner: Rc::new(External { path }),
+                inner: Arc::new(External { path }),
             });
             cmd = COMMAND_REGISTRY.lock().unwrap().get(name).cloned();
         }
@@ -90,6 +184,16 @@ pub fn get_command(name: &str) -> Option<ShellCommand> {
     cmd
 }
 
+/// The shell itself, as an external program: background jobs that are not external
+/// programs run in a new shell process, since forking a shell that runs threads is unsafe.
+pub fn shell_program() -> io::Result<ShellCommand> {
+    let path = std::env::current_exe()?.to_string_lossy().to_string();
+    Ok(ShellCommand {
+        name: path.clone(),
+        inner: Arc::new(External { path }),
+    })
+}
+
 pub fn list_registered_commands(internal: bool) -> Vec<String> {
     let registry = COMMAND_REGISTRY.lock().unwrap();
 
@@ -147,44 +251,51 @@ struct External {
 }
 
 impl Exec for External {
-    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String> {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        let child = self.spawn(args, scope, streams)?;
+        check_exit_status(wait_child(child, streams)?)?;
+        Ok(Value::success())
+    }
+
+    fn spawn(
+        &self,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Child, String> {
         let mut command = Command::new(&self.path);
 
         command.args(args);
 
-        // Override existing environment variables
+        // Only exported variables are passed to the program
         command.env_clear();
+        command.envs(scope.exported_vars());
 
-        let mut current_scope = scope;
-        loop {
-            for (key, variable) in current_scope.vars.borrow().iter() {
-                command.env(key, variable.value().to_string());
-            }
-            // Walk up the enclosing scope
-            match &current_scope.parent {
-                None => {
-                    break;
-                }
-                Some(scope) => {
-                    current_scope = scope;
-                }
-            }
-        }
+        // Connect the streams; in-memory input and output go through pipes.
+        let input = child_stdin(&mut streams.stdin, &mut command);
+        command.stdout(child_stdio(&streams.stdout)?);
+        command.stderr(child_stdio(&streams.stderr)?);
+        jobs::configure(&mut command);
 
-        match command.spawn() {
-            Ok(mut child) => match &child.wait() {
-                Ok(status) => {
-                    if let Some(code) = status.code() {
-                        if code != 0 {
-                            return Err(format!("exit code: {}", code));
-                        }
-                    }
-                    return Ok(Value::success());
-                }
-                Err(e) => Err(format!("Failed to wait on child process: {}", e)),
-            },
-            Err(e) => Err(format!("Failed to execute command: {}", e)),
+        let mut child = command
+            .spawn()
+            .map_err(|e| format!("Failed to execute command: {}", e))?;
+        jobs::started(&child);
+
+        if let (Some(data), Some(mut stdin)) = (input, child.stdin.take()) {
+            // Feed the input from another thread, so that the child is not blocked
+            // writing its output while we are blocked writing its input.
+            thread::spawn(move || {
+                let _ = stdin.write_all(&data);
+            });
         }
+        Ok(child)
     }
 
     fn is_external(&self) -> bool {
@@ -192,6 +303,81 @@ impl Exec for External {
     }
 }
 
+/// Connect the input stream to a child process. Return the data to be
+/// fed to the child, if the input is in memory.
+fn child_stdin(input: &mut Input, command: &mut Command) -> Option<Vec<u8>> {
+    input.resolve();
+    if let Input::Pipe(_) = input {
+        // Hand the reading end of the pipe over to the child.
+        if let Input::Pipe(reader) = std::mem::replace(input, Input::empty()) {
+            command.stdin(reader);
+        }
+        return None;
+    }
+    let data = input.take_data();
+    if data.is_some() {
+        command.stdin(Stdio::piped());
+    }
+    data
+}
+
+/// Connect an output stream to a child process. Memory buffers are connected
+/// via pipes, and the output is copied into the stream by wait_child.
+fn child_stdio(output: &Output) -> Result<Stdio, String> {
+    Ok(match output {
+        Output::Inherit => match pipe::redirect_file() {
+            Some(file) => Stdio::from(file.map_err(|e| e.to_string())?),
+            None if pipe::is_capturing() => Stdio::piped(),
+            None => Stdio::inherit(),
+        },
+        Output::Stderr => Stdio::from(io::stderr()),
+        Output::File(file) => Stdio::from(file.try_clone().map_err(|e| e.to_string())?),
+        Output::Buffer(_) => Stdio::piped(),
+        Output::Pipe(writer) => Stdio::from(writer.try_clone().map_err(|e| e.to_string())?),
+        Output::Null => Stdio::null(),
+    })
+}
+
+/// Wait for a child process started with Exec::spawn to complete, copying its
+/// piped output (if any) into the streams.
+pub fn wait_child(child: Child, streams: &mut Streams) -> Result<ExitStatus, String> {
+    let status = if child.stdout.is_some() || child.stderr.is_some() {
+        let output = child
+            .wait_with_output()
+            .map_err(|e| format!("Failed to wait on child process: {}", e))?;
+        streams
+            .stdout
+            .write_all(&output.stdout)
+            .map_err(|e| e.to_string())?;
+        streams
+            .stderr
+            .write_all(&output.stderr)
+            .map_err(|e| e.to_string())?;
+        output.status
+    } else {
+        jobs::wait(child).map_err(|e| format!("Failed to wait on child process: {}", e))?
+    };
+    Ok(status)
+}
+
+/// Programs that exit with a non-zero code fail. Note that programs
+/// terminated by a signal do not, e.g. the left side of: yes | head
+pub fn check_exit_status(status: ExitStatus) -> Result<(), String> {
+    match status.code() {
+        Some(code) if code != 0 => Err(format!("exit code: {}", code)),
+        _ => Ok(()),
+    }
+}
+
+/// The exit status of a failed command: the exit code of the program, if the error
+/// comes from check_exit_status, and 1 otherwise.
+pub fn exit_code(error: &str) -> i32 {
+    error
+        .rsplit_once("exit code: ")
+        .and_then(|(_, code)| code.parse().ok())
+        .unwrap_or(1)
+}
+
 struct Which {
     flags: CommandFlags,
 }
@@ -206,15 +392,21 @@ impl Which {
 }
 
 impl Exec for Which {
-    fn exec(&self, _name: &str, args: &Vec<String>, _: &Rc<Scope>) -> Result<Value, String> {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: which [COMMAND]...");
-            println!("Locate a command and display its path.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: which [COMMAND]...")?;
+            my_println!(streams.stdout, "Locate a command and display its path.")?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -223,13 +415,22 @@ impl Exec for Which {
         }
 
         for command in args {
+            if let Some(expansion) = get_alias(command) {
+                if !flags.is_present("external") {
+                    my_println!(streams.stdout, "{}: alias for \"{}\"", command, expansion)?;
+                }
+            }
             if let Some(cmd) = get_command(command) {
-                if !cmd.is_external() && !flags.is_present("external") {
-                    my_println!("{}: built-in", command)?;
+                if !flags.is_present("external") {
+                    if cmd.is_function() {
+                        my_println!(streams.stdout, "{}: function", command)?;
+                    } else if !cmd.is_external() {
+                        my_println!(streams.stdout, "{}: built-in", command)?;
+                    }
                 }
             }
             if let Some(path) = locate_executable(command) {
-                my_println!("{}", path)?;
+                my_println!(streams.stdout, "{}", path)?;
             }
         }
 
@@ -239,12 +440,20 @@ impl Exec for Which {
     fn is_external(&self) -> bool {
         false
     }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+
+    fn arg_type(&self) -> ArgType {
+        ArgType::Command
+    }
 }
 
 #[ctor::ctor]
 fn register() {
     register_command(ShellCommand {
         name: "which".to_string(),
-        inner: Rc::new(Which::new()),
+        inner: Arc::new(Which::new()),
     });
 }
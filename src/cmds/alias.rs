@@ -0,0 +1,159 @@
+use super::{register_command, Exec, ShellCommand};
+use crate::cmds::flags::CommandFlags;
+use crate::eval::{Scope, Value};
+use crate::pipe::Streams;
+use std::collections::BTreeMap;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+// Aliases defined in the session, by name
+static ALIASES: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+/// The expansion of an alias, if defined
+pub fn get_alias(name: &str) -> Option<String> {
+    ALIASES.lock().unwrap().get(name).cloned()
+}
+
+/// Define an alias; the ALIAS command calls this when it runs.
+pub fn set_alias(name: &str, expansion: &str) {
+    ALIASES
+        .lock()
+        .unwrap()
+        .insert(name.to_string(), expansion.to_string());
+}
+
+pub fn list_aliases() -> Vec<String> {
+    ALIASES.lock().unwrap().keys().cloned().collect()
+}
+
+struct Alias {
+    flags: CommandFlags,
+}
+
+struct Unalias {
+    flags: CommandFlags,
+}
+
+impl Alias {
+    fn new() -> Self {
+        let mut flags = CommandFlags::new();
+        flags.add_flag('?', "help", "Display this help message");
+        Self { flags }
+    }
+}
+
+impl Exec for Alias {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        let mut flags = self.flags.clone();
+        let parsed_args = flags.parse(args)?;
+
+        if flags.is_present("help") {
+            my_println!(streams.stdout, "Usage: alias [NAME [= EXPANSION]]")?;
+            my_println!(
+                streams.stdout,
+                "Define NAME as a shorthand for EXPANSION, or show aliases (all when NAME is missing)."
+            )?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
+            return Ok(Value::success());
+        }
+
+        match parsed_args.as_slice() {
+            [] => {
+                for (name, expansion) in ALIASES.lock().unwrap().iter() {
+                    my_println!(streams.stdout, "alias {} = \"{}\"", name, expansion)?;
+                }
+            }
+            [name] => match get_alias(name) {
+                Some(expansion) => {
+                    my_println!(streams.stdout, "alias {} = \"{}\"", name, expansion)?
+                }
+                None => return Err(format!("{}: alias not found", name)),
+            },
+            // The parser drops the equal sign of: alias NAME = EXPANSION
+            [name, expansion] => set_alias(name, expansion),
+            _ => return Err("Too many arguments".to_string()),
+        }
+
+        Ok(Value::success())
+    }
+
+    fn is_external(&self) -> bool {
+        false
+    }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+}
+
+impl Unalias {
+    fn new() -> Self {
+        let mut flags = CommandFlags::new();
+        flags.add_flag('?', "help", "Display this help message");
+        flags.add_flag('a', "all", "Remove all aliases");
+        Self { flags }
+    }
+}
+
+impl Exec for Unalias {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        let mut flags = self.flags.clone();
+        let parsed_args = flags.parse(args)?;
+
+        if flags.is_present("help") {
+            my_println!(streams.stdout, "Usage: unalias [-a] NAME...")?;
+            my_println!(streams.stdout, "Remove the aliases with the given names.")?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
+            return Ok(Value::success());
+        }
+
+        let mut aliases = ALIASES.lock().unwrap();
+        if flags.is_present("all") {
+            aliases.clear();
+        } else if parsed_args.is_empty() {
+            return Err("Missing NAME argument".to_string());
+        } else {
+            for name in &parsed_args {
+                if aliases.remove(name).is_none() {
+                    return Err(format!("{}: alias not found", name));
+                }
+            }
+        }
+
+        Ok(Value::success())
+    }
+
+    fn is_external(&self) -> bool {
+        false
+    }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+}
+
+#[ctor::ctor]
+fn register() {
+    register_command(ShellCommand {
+        name: "alias".to_string(),
+        inner: Arc::new(Alias::new()),
+    });
+    register_command(ShellCommand {
+        name: "unalias".to_string(),
+        inner: Arc::new(Unalias::new()),
+    });
+}
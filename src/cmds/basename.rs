@@ -2,8 +2,10 @@ use super::{register_command, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value};
 use crate::my_println;
+use crate::pipe::Streams;
 use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct Basename {
     flags: CommandFlags,
@@ -18,15 +20,21 @@ impl Basename {
 }
 
 impl Exec for Basename {
-    fn exec(&self, _name: &str, args: &Vec<String>, _: &Rc<Scope>) -> Result<Value, String> {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: basename [OPTION]... [NAME]...");
-            println!("Print the base name of each FILE.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: basename [OPTION]... [NAME]...")?;
+            my_println!(streams.stdout, "Print the base name of each FILE.")?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -40,7 +48,7 @@ impl Exec for Basename {
                 .file_name()
                 .ok_or_else(|| "Failed to get file name".to_string())?;
 
-            my_println!("{}", base.to_string_lossy())?;
+            my_println!(streams.stdout, "{}", base.to_string_lossy())?;
         }
 
         Ok(Value::success())
@@ -49,12 +57,16 @@ impl Exec for Basename {
     fn is_external(&self) -> bool {
         false
     }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
 }
 
 #[ctor::ctor]
 fn register() {
     register_command(ShellCommand {
         name: "basename".to_string(),
-        inner: Rc::new(Basename::new()),
+        inner: Arc::new(Basename::new()),
     });
 }
@@ -2,10 +2,12 @@ use super::{register_command, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value};
 use crate::my_println;
+use crate::pipe::Streams;
 use std::collections::VecDeque;
 use std::fs::File;
-use std::io::{self, BufRead};
+use std::io::{self, BufRead, Write};
 use std::rc::Rc;
+use std::sync::Arc;
 
 enum Mode {
     Cat,
@@ -40,15 +42,21 @@ impl CatHeadTail {
 }
 
 impl Exec for CatHeadTail {
-    fn exec(&self, name: &str, args: &Vec<String>, _: &Rc<Scope>) -> Result<Value, String> {
+    fn exec(
+        &self,
+        name: &str,
+        args: &Vec<String>,
+        _: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         let filenames = flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: {} [OPTION]... [FILE]...", name);
-            println!("{}", self.mode_specific_help());
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: {} [OPTION]... [FILE]...", name)?;
+            my_println!(streams.stdout, "{}", self.mode_specific_help())?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -59,13 +67,24 @@ impl Exec for CatHeadTail {
             .unwrap_or(Ok(10))?;
 
         if filenames.is_empty() {
-            let stdin = io::stdin();
-            process_input(&mut stdin.lock(), &self.mode, line_numbers, lines)?;
+            process_input(
+                &mut streams.stdin.reader(),
+                &mut streams.stdout,
+                &self.mode,
+                line_numbers,
+                lines,
+            )?;
         } else {
             for filename in &filenames {
                 let file = File::open(&filename).map_err(|e| e.to_string())?;
                 let mut buf_reader = io::BufReader::new(file);
-                process_input(&mut buf_reader, &self.mode, line_numbers, lines)?;
+                process_input(
+                    &mut buf_reader,
+                    &mut streams.stdout,
+                    &self.mode,
+                    line_numbers,
+                    lines,
+                )?;
             }
         }
         Ok(Value::success())
@@ -74,46 +93,65 @@ impl Exec for CatHeadTail {
     fn is_external(&self) -> bool {
         false
     }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
 }
 
 fn process_input<R: BufRead>(
     reader: &mut R,
+    out: &mut dyn Write,
     mode: &Mode,
     line_numbers: bool,
     lines: usize,
 ) -> Result<(), String> {
     match mode {
-        Mode::Cat => print_all(reader, line_numbers),
-        Mode::Head => print_head(reader, line_numbers, lines),
-        Mode::Tail => print_tail(reader, line_numbers, lines),
+        Mode::Cat => print_all(reader, out, line_numbers),
+        Mode::Head => print_head(reader, out, line_numbers, lines),
+        Mode::Tail => print_tail(reader, out, line_numbers, lines),
     }
 }
 
-fn print_all<R: BufRead>(reader: &mut R, line_numbers: bool) -> Result<(), String> {
+fn print_all<R: BufRead>(
+    reader: &mut R,
+    out: &mut dyn Write,
+    line_numbers: bool,
+) -> Result<(), String> {
     for (i, line) in reader.lines().enumerate() {
         let line = line.map_err(|e| e.to_string())?;
         if line_numbers {
-            my_println!("{:>6}: {}", i + 1, line)?;
+            my_println!(out, "{:>6}: {}", i + 1, line)?;
         } else {
-            my_println!("{}", line)?;
+            my_println!(out, "{}", line)?;
         }
     }
     Ok(())
 }
 
-fn print_head<R: BufRead>(reader: &mut R, line_numbers: bool, lines: usize) -> Result<(), String> {
+fn print_head<R: BufRead>(
+    reader: &mut R,
+    out: &mut dyn Write,
+    line_numbers: bool,
+    lines: usize,
+) -> Result<(), String> {
     for (i, line) in reader.lines().enumerate().take(lines) {
         let line = line.map_err(|e| e.to_string())?;
         if line_numbers {
-            my_println!("{:>6}: {}", i + 1, line)?;
+            my_println!(out, "{:>6}: {}", i + 1, line)?;
         } else {
-            my_println!("{}", line)?;
+            my_println!(out, "{}", line)?;
         }
     }
     Ok(())
 }
 
-fn print_tail<R: BufRead>(reader: &mut R, line_numbers: bool, lines: usize) -> Result<(), String> {
+fn print_tail<R: BufRead>(
+    reader: &mut R,
+    out: &mut dyn Write,
+    line_numbers: bool,
+    lines: usize,
+) -> Result<(), String> {
     let mut buffer = VecDeque::with_capacity(lines);
 
     for (i, line) in reader.lines().enumerate() {
@@ -125,9 +163,9 @@ fn print_tail<R: BufRead>(reader: &mut R, line_numbers: bool, lines: usize) -> R
     }
     for (i, line) in buffer {
         if line_numbers {
-            my_println!("{:>6}: {}", i + 1, line)?;
+            my_println!(out, "{:>6}: {}", i + 1, line)?;
         } else {
-            my_println!("{}", line)?;
+            my_println!(out, "{}", line)?;
         }
     }
     Ok(())
@@ -137,14 +175,14 @@ fn print_tail<R: BufRead>(reader: &mut R, line_numbers: bool, lines: usize) -> R
 fn register() {
     register_command(ShellCommand {
         name: "cat".to_string(),
-        inner: Rc::new(CatHeadTail::new(Mode::Cat)),
+        inner: Arc::new(CatHeadTail::new(Mode::Cat)),
     });
     register_command(ShellCommand {
         name: "head".to_string(),
-        inner: Rc::new(CatHeadTail::new(Mode::Head)),
+        inner: Arc::new(CatHeadTail::new(Mode::Head)),
     });
     register_command(ShellCommand {
         name: "tail".to_string(),
-        inner: Rc::new(CatHeadTail::new(Mode::Tail)),
+        inner: Arc::new(CatHeadTail::new(Mode::Tail)),
     });
 }
@@ -1,16 +1,25 @@
-use super::{register_command, Exec, ShellCommand};
+use super::{register_command, ArgType, Exec, ShellCommand};
 use crate::{
     cmds::flags::CommandFlags,
-    current_dir, my_dbg,
+    current_dir,
     eval::{Scope, Value},
+    my_dbg,
+    pipe::Streams,
 };
 
-use std::cell::RefCell;
 use std::env;
 use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+
+// Directories saved by pushd
+static DIR_STACK: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Number of directories saved by pushd, shown in the prompt
+pub fn dir_stack_depth() -> usize {
+    DIR_STACK.lock().unwrap().len()
+}
 
 struct ChangeDir {
-    stack: RefCell<Vec<String>>,
     flags: CommandFlags,
 }
 
@@ -22,34 +31,43 @@ impl ChangeDir {
     fn new() -> Self {
         let mut flags = CommandFlags::new();
         flags.add_flag('?', "help", "Display this help message");
-        Self {
-            stack: RefCell::new(Vec::new()),
-            flags,
-        }
+        Self { flags }
     }
 
-    fn chdir(&self, name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String> {
+    fn chdir(
+        &self,
+        name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         let parsed_args = flags.parse(args)?;
 
         if flags.is_present("help") {
             match name {
                 "cd" | "chdir" => {
-                    println!("Usage: {} [DIR]", name);
-                    println!("Change the current directory to DIR.");
+                    my_println!(streams.stdout, "Usage: {} [DIR]", name)?;
+                    my_println!(streams.stdout, "Change the current directory to DIR.")?;
                 }
                 "pushd" => {
-                    println!("Usage: pushd [DIR]");
-                    println!("Push the current directory onto the stack and change to DIR.");
+                    my_println!(streams.stdout, "Usage: pushd [DIR]")?;
+                    my_println!(
+                        streams.stdout,
+                        "Push the current directory onto the stack and change to DIR."
+                    )?;
                 }
                 "popd" => {
-                    println!("Usage: popd");
-                    println!("Pop the top directory from the stack and change to it.");
+                    my_println!(streams.stdout, "Usage: popd")?;
+                    my_println!(
+                        streams.stdout,
+                        "Pop the top directory from the stack and change to it."
+                    )?;
                 }
                 _ => unreachable!(),
             }
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -73,15 +91,16 @@ impl ChangeDir {
                 } else {
                     parsed_args.join(" ")
                 };
-                self.stack.borrow_mut().push(current_dir()?);
+                DIR_STACK.lock().unwrap().push(current_dir()?);
                 env::set_current_dir(&new_dir)
                     .map_err(|e| format!("Change dir to \"{}\": {}", &new_dir, e))?;
             }
             "popd" => {
-                if self.stack.borrow().is_empty() {
-                    return Err("popd: directory stack empty".to_string());
-                }
-                let old_dir = self.stack.borrow_mut().pop().unwrap();
+                let old_dir = DIR_STACK
+                    .lock()
+                    .unwrap()
+                    .pop()
+                    .ok_or("popd: directory stack empty")?;
                 env::set_current_dir(&old_dir)
                     .map_err(|e| format!("Change dir to \"{}\": {}", &old_dir, e))?;
             }
@@ -93,13 +112,27 @@ impl ChangeDir {
 }
 
 impl Exec for ChangeDir {
-    fn exec(&self, name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String> {
-        self.chdir(name, args, scope)
+    fn exec(
+        &self,
+        name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        self.chdir(name, args, scope, streams)
     }
 
     fn is_external(&self) -> bool {
         false
     }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+
+    fn arg_type(&self) -> ArgType {
+        ArgType::Dir
+    }
 }
 
 impl PrintWorkingDir {
@@ -111,48 +144,58 @@ impl PrintWorkingDir {
 }
 
 impl Exec for PrintWorkingDir {
-    fn exec(&self, _name: &str, args: &Vec<String>, _scope: &Rc<Scope>) -> Result<Value, String> {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         let _ = flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: pwd");
-            println!("Print the current working directory.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: pwd")?;
+            my_println!(streams.stdout, "Print the current working directory.")?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
-        println!("{}", current_dir()?);
+        my_println!(streams.stdout, "{}", current_dir()?)?;
         Ok(Value::success())
     }
 
     fn is_external(&self) -> bool {
         false
     }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
 }
 
 #[ctor::ctor]
 fn register() {
-    let chdir = Rc::new(ChangeDir::new());
+    let chdir = Arc::new(ChangeDir::new());
 
     register_command(ShellCommand {
         name: "cd".to_string(),
-        inner: Rc::clone(&chdir) as Rc<dyn Exec>,
+        inner: Arc::clone(&chdir) as Arc<dyn Exec>,
     });
 
     register_command(ShellCommand {
         name: "pushd".to_string(),
-        inner: Rc::clone(&chdir) as Rc<dyn Exec>,
+        inner: Arc::clone(&chdir) as Arc<dyn Exec>,
     });
 
     register_command(ShellCommand {
         name: "popd".to_string(),
-        inner: Rc::clone(&chdir) as Rc<dyn Exec>,
+        inner: Arc::clone(&chdir) as Arc<dyn Exec>,
     });
 
     register_command(ShellCommand {
         name: "pwd".to_string(),
-        inner: Rc::new(PrintWorkingDir::new()),
+        inner: Arc::new(PrintWorkingDir::new()),
     });
 }
@@ -1,8 +1,10 @@
 use super::{register_command, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value};
+use crate::pipe::Streams;
 use clearscreen;
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct Clear {
     flags: CommandFlags,
@@ -17,15 +19,21 @@ impl Clear {
 }
 
 impl Exec for Clear {
-    fn exec(&self, _name: &str, args: &Vec<String>, _: &Rc<Scope>) -> Result<Value, String> {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: clear");
-            println!("Clear the terminal screen.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: clear")?;
+            my_println!(streams.stdout, "Clear the terminal screen.")?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -38,17 +46,21 @@ impl Exec for Clear {
     fn is_external(&self) -> bool {
         false
     }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
 }
 
 #[ctor::ctor]
 fn register() {
     register_command(ShellCommand {
         name: "clear".to_string(),
-        inner: Rc::new(Clear::new()),
+        inner: Arc::new(Clear::new()),
     });
 
     register_command(ShellCommand {
         name: "cls".to_string(),
-        inner: Rc::new(Clear::new()),
+        inner: Arc::new(Clear::new()),
     });
 }
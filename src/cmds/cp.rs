@@ -1,12 +1,14 @@
 use super::{register_command, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value};
+use crate::pipe::Streams;
 use crate::prompt::{confirm, Answer};
 use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::Duration;
 
 // Add the path to the error reported to the caller
@@ -302,15 +304,25 @@ impl Exec for Cp {
         false
     }
 
-    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String> {
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         let args = flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: cp [OPTIONS] SOURCE DEST");
-            println!("Copy SOURCE to DESTination.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: cp [OPTIONS] SOURCE DEST")?;
+            my_println!(streams.stdout, "Copy SOURCE to DESTination.")?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -351,6 +363,6 @@ impl Exec for Cp {
 fn register() {
     register_command(ShellCommand {
         name: "cp".to_string(),
-        inner: Rc::new(Cp::new()),
+        inner: Arc::new(Cp::new()),
     });
 }
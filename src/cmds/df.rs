@@ -1,8 +1,11 @@
 use super::{register_command, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value};
+use crate::pipe::Streams;
+use std::io::Write;
 use std::process::Command;
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct DiskFree {
     flags: CommandFlags,
@@ -15,7 +18,7 @@ impl DiskFree {
         DiskFree { flags }
     }
 
-    fn print_usage(&self, path: &str) {
+    fn print_usage(&self, out: &mut dyn Write, path: &str) -> Result<(), String> {
         let output = if cfg!(target_os = "windows") {
             Command::new("wmic")
                 .args(&["logicaldisk", "get", "size,freespace,caption"])
@@ -30,7 +33,7 @@ impl DiskFree {
         };
 
         let result = String::from_utf8_lossy(&output.stdout);
-        println!("{}", result);
+        my_println!(out, "{}", result)
     }
 }
 
@@ -39,20 +42,30 @@ impl Exec for DiskFree {
         false
     }
 
-    fn exec(&self, _name: &str, args: &Vec<String>, _scope: &Rc<Scope>) -> Result<Value, String> {
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         let args = flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: df [OPTIONS] [PATH]");
-            println!("Display disk space usage for file systems.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: df [OPTIONS] [PATH]")?;
+            my_println!(streams.stdout, "Display disk space usage for file systems.")?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
         let path = args.get(0).map_or("/", |s| s.as_str());
-        self.print_usage(path);
+        self.print_usage(&mut streams.stdout, path)?;
         Ok(Value::success())
     }
 }
@@ -61,6 +74,6 @@ impl Exec for DiskFree {
 fn register() {
     register_command(ShellCommand {
         name: "df".to_string(),
-        inner: Rc::new(DiskFree::new()),
+        inner: Arc::new(DiskFree::new()),
     });
 }
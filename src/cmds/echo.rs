@@ -1,13 +1,21 @@
 use super::{register_command, Exec, ShellCommand};
 use crate::eval::{Scope, Value};
-use std::rc::Rc;
 use crate::my_println;
+use crate::pipe::Streams;
+use std::rc::Rc;
+use std::sync::Arc;
 
 struct Echo;
 
 impl Exec for Echo {
-    fn exec(&self, _name: &str, args: &Vec<String>, _: &Rc<Scope>) -> Result<Value, String> {
-        my_println!("{}", args.join(" "))?;
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        my_println!(streams.stdout, "{}", args.join(" "))?;
         Ok(Value::success())
     }
 
@@ -20,6 +28,6 @@ impl Exec for Echo {
 fn register() {
     register_command(ShellCommand {
         name: "echo".to_string(),
-        inner: Rc::new(Echo),
+        inner: Arc::new(Echo),
     });
 }
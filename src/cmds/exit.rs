@@ -1,12 +1,20 @@
 use super::{register_command, Exec, ShellCommand};
 use crate::eval::{Scope, Value};
+use crate::pipe::Streams;
 use std::process;
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct Exit;
 
 impl Exec for Exit {
-    fn exec(&self, _name: &str, args: &Vec<String>, _: &Rc<Scope>) -> Result<Value, String> {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _: &Rc<Scope>,
+        _streams: &mut Streams,
+    ) -> Result<Value, String> {
         let exit_code = if args.len() > 0 {
             args[0]
                 .parse::<i32>()
@@ -27,6 +35,6 @@ impl Exec for Exit {
 fn register() {
     register_command(ShellCommand {
         name: "exit".to_string(),
-        inner: Rc::new(Exit),
+        inner: Arc::new(Exit),
     });
 }
@@ -2,11 +2,13 @@ use super::{register_command, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value};
 use crate::my_println;
+use crate::pipe::Streams;
 use regex::Regex;
 use std::ffi::OsStr;
 use std::fs;
 use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct Find {
     flags: CommandFlags,
@@ -25,6 +27,7 @@ impl Find {
         file_name: &OsStr,
         path: &Path,
         regex: &Regex,
+        streams: &mut Streams,
     ) -> Result<(), String> {
         if scope.is_interrupted() {
             return Ok(());
@@ -35,16 +38,22 @@ impl Find {
                     for entry in entries {
                         match entry {
                             Ok(entry) => {
-                                self.search(scope, &entry.file_name(), &entry.path(), regex)?;
+                                self.search(
+                                    scope,
+                                    &entry.file_name(),
+                                    &entry.path(),
+                                    regex,
+                                    streams,
+                                )?;
                             }
                             Err(e) => {
-                                eprintln!("{}: {}", path.display(), e);
+                                my_println!(streams.stderr, "{}: {}", path.display(), e)?;
                             }
                         }
                     }
                 }
                 Err(e) => {
-                    eprintln!("{}: {}", path.display(), e);
+                    my_println!(streams.stderr, "{}: {}", path.display(), e)?;
                 }
             }
         } else {
@@ -52,7 +61,7 @@ impl Find {
             match regex.find(&file_name_str.as_str()) {
                 Some(m) => {
                     if m.as_str() == file_name_str {
-                        my_println!("{}", path.display())?;
+                        my_println!(streams.stdout, "{}", path.display())?;
                     }
                 }
                 _ => {}
@@ -67,15 +76,28 @@ impl Exec for Find {
         false
     }
 
-    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String> {
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         let args = flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: find [OPTIONS] [DIRS...] PATTERN");
-            println!("Recursively search and print paths matching PATTERN.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: find [OPTIONS] [DIRS...] PATTERN")?;
+            my_println!(
+                streams.stdout,
+                "Recursively search and print paths matching PATTERN."
+            )?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -93,7 +115,7 @@ impl Exec for Find {
 
         for dir in dirs {
             let path = Path::new(dir);
-            self.search(scope, OsStr::new(dir), &path, &regex)?;
+            self.search(scope, OsStr::new(dir), &path, &regex, streams)?;
         }
 
         Ok(Value::success())
@@ -104,6 +126,6 @@ impl Exec for Find {
 fn register() {
     register_command(ShellCommand {
         name: "find".to_string(),
-        inner: Rc::new(Find::new()),
+        inner: Arc::new(Find::new()),
     });
 }
@@ -1,10 +1,10 @@
 use std::collections::HashMap;
 
 #[derive(Clone)]
-struct Flag {
-    short: char,
-    long: String,
-    help: String,
+pub struct Flag {
+    pub short: char,
+    pub long: String,
+    pub help: String,
     takes_value: bool, // Currently not used, for future proofing
 }
 
@@ -136,6 +136,13 @@ impl CommandFlags {
         self.values.get(name).cloned()
     }
 
+    /// Flag definitions sorted by long name, e.g. for TAB completion
+    pub fn flags(&self) -> Vec<&Flag> {
+        let mut flags: Vec<_> = self.flags.values().collect();
+        flags.sort_by(|a, b| a.long.cmp(&b.long));
+        flags
+    }
+
     pub fn help(&self) -> String {
         let mut help_text = String::new();
         for flag in self.flags.values() {
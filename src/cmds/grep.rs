@@ -1,17 +1,28 @@
 use super::{register_command, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value};
+use crate::pipe::Streams;
 use colored::*;
 use regex::Regex;
 use std::fs;
-use std::io::{self, BufRead, IsTerminal};
+use std::io::{BufRead, Write};
 use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct Grep {
     flags: CommandFlags,
 }
 
+/// Matching and output options shared by every line grep looks at.
+struct LineOptions {
+    regex: Regex,
+    line_number: bool,
+    ignore_case: bool,
+    show_filename: bool,
+    use_color: bool,
+}
+
 impl Grep {
     fn new() -> Self {
         let mut flags = CommandFlags::new();
@@ -40,16 +51,14 @@ impl Grep {
     }
 
     fn process_line(
+        out: &mut dyn Write,
         filename: Option<&str>,
         line_number: usize,
         line: &str,
-        regex: &Regex,
-        line_number_flag: bool,
-        ignore_case: bool,
-        show_filename: bool,
-        use_color: bool,
-    ) {
-        let line_to_check = if ignore_case {
+        opts: &LineOptions,
+    ) -> Result<(), String> {
+        let regex = &opts.regex;
+        let line_to_check = if opts.ignore_case {
             line.to_lowercase()
         } else {
             line.to_string()
@@ -57,16 +66,16 @@ impl Grep {
 
         if regex.is_match(&line_to_check) {
             let mut output = String::new();
-            if show_filename {
+            if opts.show_filename {
                 if let Some(name) = filename {
                     output.push_str(&format!("{}:", name));
                 }
             }
-            if line_number_flag {
+            if opts.line_number {
                 output.push_str(&format!("{}:", line_number + 1));
             }
 
-            if use_color {
+            if opts.use_color {
                 let colored_line = regex.replace_all(line, |caps: &regex::Captures| {
                     caps[0].red().bold().to_string()
                 });
@@ -75,8 +84,9 @@ impl Grep {
                 output.push_str(line);
             }
 
-            println!("{}", output);
+            my_println!(out, "{}", output)?;
         }
+        Ok(())
     }
 }
 
@@ -85,15 +95,28 @@ impl Exec for Grep {
         false
     }
 
-    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String> {
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         let args = flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: grep [OPTIONS] PATTERN [FILE]...");
-            println!("Search for PATTERN in each FILE (or stdin if no FILE is given).");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: grep [OPTIONS] PATTERN [FILE]...")?;
+            my_println!(
+                streams.stdout,
+                "Search for PATTERN in each FILE (or stdin if no FILE is given)."
+            )?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -106,7 +129,7 @@ impl Exec for Grep {
         let line_number_flag = flags.is_present("line-number");
         let no_filename = flags.is_present("no-filename");
         let with_filename = flags.is_present("with-filename");
-        let use_color = scope.lookup("NO_COLOR").is_none() && std::io::stdout().is_terminal();
+        let use_color = scope.lookup("NO_COLOR").is_none() && streams.stdout.is_terminal();
 
         let regex = if ignore_case {
             Regex::new(&format!("(?i){}", pattern)).map_err(|e| e.to_string())?
@@ -123,38 +146,28 @@ impl Exec for Grep {
             false
         };
 
+        let mut opts = LineOptions {
+            regex,
+            line_number: line_number_flag,
+            ignore_case,
+            show_filename: false,
+            use_color,
+        };
+
         if files.is_empty() {
             // Read from stdin if no files are provided
-            let stdin = io::stdin();
-            let reader = stdin.lock();
+            let reader = streams.stdin.reader();
             for (line_number, line) in reader.lines().enumerate() {
                 let line = line.map_err(|e| e.to_string())?;
-                Self::process_line(
-                    None,
-                    line_number,
-                    &line,
-                    &regex,
-                    line_number_flag,
-                    ignore_case,
-                    false,
-                    use_color,
-                );
+                Self::process_line(&mut streams.stdout, None, line_number, &line, &opts)?;
             }
         } else {
+            opts.show_filename = show_filename;
             for file in files {
                 let path = Path::new(file);
                 let content = fs::read_to_string(&path).map_err(|e| e.to_string())?;
                 for (line_number, line) in content.lines().enumerate() {
-                    Self::process_line(
-                        Some(file),
-                        line_number,
-                        line,
-                        &regex,
-                        line_number_flag,
-                        ignore_case,
-                        show_filename,
-                        use_color,
-                    );
+                    Self::process_line(&mut streams.stdout, Some(file), line_number, line, &opts)?;
                 }
             }
         }
@@ -166,6 +179,6 @@ impl Exec for Grep {
 fn register() {
     register_command(ShellCommand {
         name: "grep".to_string(),
-        inner: Rc::new(Grep::new()),
+        inner: Arc::new(Grep::new()),
     });
 }
@@ -1,7 +1,10 @@
-use super::{get_command, list_registered_commands, register_command, Exec, ShellCommand};
+use super::{get_command, list_registered_commands, register_command, ArgType, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
-use crate::eval::{Scope, Value};
+use crate::eval::{Scope, Value, SOURCE_COMMANDS};
+use crate::pipe::Streams;
+use std::io::Write;
 use std::rc::Rc;
+use std::sync::Arc;
 use terminal_size::terminal_size;
 
 struct Help {
@@ -15,26 +18,46 @@ impl Help {
         Help { flags }
     }
 
-    fn print_interpreter_help() {
-        println!("\nThis is a lightweight Unix-like command line interpreter.");
-        println!("It supports various built-in commands and can execute external programs.");
-        println!("\nUsage:");
-        println!("  command [arguments]");
-        println!("\nFor information on a specific command, type 'help <command>'.");
+    fn print_interpreter_help(out: &mut dyn Write) -> Result<(), String> {
+        my_println!(
+            out,
+            "\nThis is a lightweight Unix-like command line interpreter."
+        )?;
+        my_println!(
+            out,
+            "It supports various built-in commands and can execute external programs."
+        )?;
+        my_println!(out, "\nUsage:")?;
+        my_println!(out, "  command [arguments]")?;
+        my_println!(
+            out,
+            "\nFor information on a specific command, type 'help <command>'."
+        )
     }
 
-    fn print_command_help(command: &str, scope: &Rc<Scope>) -> Result<(), String> {
+    fn print_command_help(
+        command: &str,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<(), String> {
         if command == "exit" {
-            println!("exit [<exit code>]\n");
+            my_println!(streams.stdout, "exit [<exit code>]\n")?;
             Ok(())
         } else if command == "echo" {
-            println!("echo [argument]...\n");
+            my_println!(streams.stdout, "echo [argument]...\n")?;
+            Ok(())
+        } else if SOURCE_COMMANDS.contains(&command) {
+            my_println!(streams.stdout, "{} FILE\n", command)?;
+            my_println!(
+                streams.stdout,
+                "Read and evaluate FILE in the current scope, keeping the variables and functions it defines.\n"
+            )?;
             Ok(())
         } else {
             match get_command(command) {
                 Some(cmd) => {
                     let help_args = vec!["-?".to_string()];
-                    cmd.exec(command, &help_args, scope)?;
+                    cmd.exec(command, &help_args, scope, streams)?;
                     Ok(())
                 }
                 None => Err(format!("Unknown command: '{}'", command)),
@@ -42,8 +65,8 @@ impl Help {
         }
     }
 
-    fn print_available_commands(spacing: usize) {
-        println!("\nAvailable commands:");
+    fn print_available_commands(out: &mut dyn Write, spacing: usize) -> Result<(), String> {
+        my_println!(out, "\nAvailable commands:")?;
         let commands = list_registered_commands(true);
 
         let max_width = terminal_size().map_or(80, |s| s.0 .0 as usize);
@@ -53,41 +76,53 @@ impl Help {
 
         let mut current_column = 0;
         for cmd in commands {
-            print!("{:<width$}", cmd, width = column_width);
+            my_print!(out, "{:<width$}", cmd, width = column_width)?;
             current_column += 1;
             if current_column >= num_columns {
-                println!();
+                my_println!(out, "")?;
                 current_column = 0;
             }
         }
         if current_column != 0 {
-            println!();
+            my_println!(out, "")?;
         }
 
-        println!("\nUse 'help COMMAND' for more information about a specific command.");
+        my_println!(
+            out,
+            "\nUse 'help COMMAND' for more information about a specific command."
+        )
     }
 }
 
 impl Exec for Help {
-    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String> {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         let args = flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: help [COMMAND]");
-            println!("Display information about the interpreter or specific commands.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: help [COMMAND]")?;
+            my_println!(
+                streams.stdout,
+                "Display information about the interpreter or specific commands."
+            )?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
         if args.is_empty() {
-            Self::print_interpreter_help();
-            Self::print_available_commands(4);
+            Self::print_interpreter_help(&mut streams.stdout)?;
+            Self::print_available_commands(&mut streams.stdout, 4)?;
         } else {
             for command in args {
-                println!("\n");
-                Self::print_command_help(&command, scope)?;
+                my_println!(streams.stdout, "\n")?;
+                Self::print_command_help(&command, scope, streams)?;
             }
         }
 
@@ -97,19 +132,27 @@ impl Exec for Help {
     fn is_external(&self) -> bool {
         false
     }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+
+    fn arg_type(&self) -> ArgType {
+        ArgType::Command
+    }
 }
 
 #[ctor::ctor]
 fn register() {
-    let help = Rc::new(Help::new());
+    let help = Arc::new(Help::new());
 
     register_command(ShellCommand {
         name: "help".to_string(),
-        inner: Rc::clone(&help) as Rc<dyn Exec>,
+        inner: Arc::clone(&help) as Arc<dyn Exec>,
     });
 
     register_command(ShellCommand {
         name: "man".to_string(),
-        inner: Rc::clone(&help) as Rc<dyn Exec>,
+        inner: Arc::clone(&help) as Arc<dyn Exec>,
     });
 }
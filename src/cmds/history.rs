@@ -0,0 +1,139 @@
+use super::{register_command, Exec, ShellCommand};
+use crate::cmds::flags::CommandFlags;
+use crate::current_dir;
+use crate::eval::{Scope, Value};
+use crate::history::{read_log, Entry};
+use crate::pipe::Streams;
+use crate::prompt::format_duration;
+use chrono::{DateTime, Local};
+use regex::Regex;
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::Arc;
+
+struct History {
+    flags: CommandFlags,
+}
+
+impl History {
+    fn new() -> Self {
+        let mut flags = CommandFlags::new();
+        flags.add_flag('?', "help", "Display this help message");
+        flags.add_value_flag('n', "number", "Show the last N matching entries");
+        flags.add_value_flag('g', "grep", "Show entries that match the PATTERN regex");
+        flags.add_flag(
+            'c',
+            "cwd",
+            "Show entries run in the current directory or below",
+        );
+        flags.add_flag('f', "failed", "Show entries that failed");
+        History { flags }
+    }
+
+    fn format_entry(index: usize, entry: &Entry) -> String {
+        let time = DateTime::from_timestamp(entry.time, 0)
+            .map(|t| {
+                t.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            })
+            .unwrap_or_default();
+        format!(
+            "{:>5}  {}  {:>3}  {:>7}  {}  {}",
+            index,
+            time,
+            entry.status,
+            format_duration(entry.duration),
+            entry.cwd,
+            entry.line
+        )
+    }
+}
+
+impl Exec for History {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        let mut flags = self.flags.clone();
+        let parsed_args = flags.parse(args)?;
+
+        if flags.is_present("help") {
+            my_println!(streams.stdout, "Usage: history [OPTIONS]")?;
+            my_println!(
+                streams.stdout,
+                "List the commands of interactive sessions, with the time they started, exit status,"
+            )?;
+            my_println!(
+                streams.stdout,
+                "duration and working directory. Use !N to evaluate the N-th entry again."
+            )?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
+            return Ok(Value::success());
+        }
+        if !parsed_args.is_empty() {
+            return Err("Too many arguments".to_string());
+        }
+
+        let count = match flags.get_value("number") {
+            Some(n) => Some(
+                n.parse::<usize>()
+                    .map_err(|_| format!("Invalid number of entries: {}", n))?,
+            ),
+            None => None,
+        };
+        let regex = match flags.get_value("grep") {
+            Some(pattern) => Some(Regex::new(&pattern).map_err(|e| e.to_string())?),
+            None => None,
+        };
+        let cwd = if flags.is_present("cwd") {
+            Some(current_dir()?)
+        } else {
+            None
+        };
+        let failed = flags.is_present("failed");
+
+        let (dropped, log) = read_log().map_err(|e| format!("Could not read history: {}", e))?;
+
+        // Number the entries before filtering, so that !N refers to the same entry
+        let entries: Vec<_> = log
+            .iter()
+            .enumerate()
+            .map(|(i, e)| (dropped + i, e))
+            .filter(|(_, e)| !failed || e.status != 0)
+            .filter(|(_, e)| regex.as_ref().is_none_or(|re| re.is_match(&e.line)))
+            .filter(|(_, e)| {
+                cwd.as_ref()
+                    .is_none_or(|cwd| Path::new(&e.cwd).starts_with(cwd))
+            })
+            .collect();
+
+        let skip = count.map_or(0, |n| entries.len().saturating_sub(n));
+
+        for (i, entry) in entries.into_iter().skip(skip) {
+            my_println!(streams.stdout, "{}", Self::format_entry(i + 1, entry))?;
+        }
+
+        Ok(Value::success())
+    }
+
+    fn is_external(&self) -> bool {
+        false
+    }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+}
+
+#[ctor::ctor]
+fn register() {
+    register_command(ShellCommand {
+        name: "history".to_string(),
+        inner: Arc::new(History::new()),
+    });
+}
@@ -0,0 +1,313 @@
+use super::{check_exit_status, register_command, Exec, ShellCommand};
+use crate::cmds::flags::CommandFlags;
+use crate::eval::{Scope, Value};
+use crate::jobs;
+use crate::pipe::Streams;
+use std::rc::Rc;
+use std::sync::Arc;
+
+enum Mode {
+    Jobs,
+    Fg,
+    Bg,
+    Wait,
+}
+
+struct JobControl {
+    flags: CommandFlags,
+    mode: Mode,
+}
+
+impl JobControl {
+    fn new(mode: Mode) -> Self {
+        let mut flags = CommandFlags::new();
+        if matches!(mode, Mode::Jobs) {
+            flags.add_flag(
+                'l',
+                "long",
+                "List process IDs in addition to the normal information",
+            );
+        }
+        flags.add_flag('?', "help", "Display this help message");
+        JobControl { flags, mode }
+    }
+
+    fn mode_specific_help(&self) -> (&str, &str) {
+        match self.mode {
+            Mode::Jobs => ("", "List the background and stopped jobs."),
+            Mode::Fg => (
+                "[%N]",
+                "Bring job N, or the current job, to the foreground.",
+            ),
+            Mode::Bg => (
+                "[%N]",
+                "Continue stopped job N, or the current job, in the background.",
+            ),
+            Mode::Wait => (
+                "[%N]...",
+                "Wait for the specified jobs, or all jobs, to complete.",
+            ),
+        }
+    }
+
+    fn current_job(specs: &[String]) -> Result<usize, String> {
+        match specs.first() {
+            Some(spec) => jobs::parse_spec(spec),
+            None => jobs::parse_spec("%+"),
+        }
+    }
+}
+
+impl Exec for JobControl {
+    fn exec(
+        &self,
+        name: &str,
+        args: &Vec<String>,
+        _: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        let mut flags = self.flags.clone();
+        let specs = flags.parse(args)?;
+
+        if flags.is_present("help") {
+            let (usage, help) = self.mode_specific_help();
+            my_println!(streams.stdout, "Usage: {} {}", name, usage)?;
+            my_println!(streams.stdout, "{}", help)?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
+            return Ok(Value::success());
+        }
+
+        match self.mode {
+            Mode::Jobs => {
+                let mut lines = Vec::new();
+                jobs::update(|job, current| {
+                    let marker = if current { '+' } else { ' ' };
+                    let mut line = format!(
+                        "[{}]{}  {:<10} {}",
+                        job.id,
+                        marker,
+                        job.state(),
+                        job.command
+                    );
+                    if flags.is_present("long") {
+                        let pids = job.pids().iter().map(|p| p.to_string()).collect::<Vec<_>>();
+                        line = format!("{}  ({})", line, pids.join(" "));
+                    }
+                    lines.push(line);
+                });
+                for line in lines {
+                    my_println!(streams.stdout, "{}", line)?;
+                }
+            }
+            Mode::Fg => {
+                let id = Self::current_job(&specs)?;
+                let command = jobs::with_job(id, |job| Ok(job.command.clone()))?;
+                my_println!(streams.stdout, "{}", command)?;
+
+                if let Some(status) = jobs::foreground(id)? {
+                    check_exit_status(status)?;
+                }
+            }
+            Mode::Bg => {
+                let id = Self::current_job(&specs)?;
+                jobs::resume(id)?;
+                let command = jobs::with_job(id, |job| Ok(job.command.clone()))?;
+                my_println!(streams.stdout, "[{}] {} &", id, command)?;
+            }
+            Mode::Wait => {
+                if specs.is_empty() {
+                    for id in jobs::ids() {
+                        jobs::wait_job(id)?;
+                    }
+                } else {
+                    let mut status = None;
+                    for spec in &specs {
+                        status = jobs::wait_job(jobs::parse_spec(spec)?)?;
+                    }
+                    if let Some(status) = status {
+                        check_exit_status(status)?;
+                    }
+                }
+            }
+        }
+        Ok(Value::success())
+    }
+
+    fn is_external(&self) -> bool {
+        false
+    }
+
+    fn is_thread_safe(&self) -> bool {
+        false
+    }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+}
+
+struct Kill {
+    flags: CommandFlags,
+}
+
+impl Kill {
+    fn new() -> Self {
+        let mut flags = CommandFlags::new();
+        flags.add_value_flag(
+            's',
+            "signal",
+            "Signal to send, by name or number (default: TERM)",
+        );
+        flags.add_flag('l', "list", "List the signal names");
+        flags.add_flag('?', "help", "Display this help message");
+        Kill { flags }
+    }
+}
+
+#[cfg(unix)]
+const SIGNALS: [(&str, i32); 12] = [
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+];
+
+#[cfg(not(unix))]
+const SIGNALS: [(&str, i32); 1] = [("KILL", 9)];
+
+fn parse_signal(name: &str) -> Result<i32, String> {
+    let upper = name.to_uppercase();
+    let upper = upper.strip_prefix("SIG").unwrap_or(&upper);
+
+    SIGNALS
+        .iter()
+        .find(|(name, num)| *name == upper || num.to_string() == upper)
+        .map(|(_, num)| *num)
+        .ok_or_else(|| format!("{}: invalid signal specification", name))
+}
+
+#[cfg(unix)]
+fn kill_process(pid: &str, sig: i32) -> Result<(), String> {
+    let pid = pid
+        .parse::<libc::pid_t>()
+        .map_err(|_| format!("{}: arguments must be process or job IDs", pid))?;
+
+    if unsafe { libc::kill(pid, sig) } < 0 {
+        return Err(format!("({}): {}", pid, std::io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn kill_process(pid: &str, _sig: i32) -> Result<(), String> {
+    Err(format!("{}: only jobs can be killed on this platform", pid))
+}
+
+impl Exec for Kill {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        let mut flags = self.flags.clone();
+        let mut args = args.clone();
+
+        // Accept the traditional -SIGNAL form, e.g. kill -9 %1
+        let mut signal = None;
+        if let Some(first) = args.first() {
+            if let Some(name) = first.strip_prefix('-') {
+                if let Ok(sig) = parse_signal(name) {
+                    signal = Some(sig);
+                    args.remove(0);
+                }
+            }
+        }
+        let targets = flags.parse(&args)?;
+
+        if flags.is_present("help") {
+            my_println!(
+                streams.stdout,
+                "Usage: kill [-s SIGNAL | -SIGNAL] %N|PID..."
+            )?;
+            my_println!(streams.stdout, "Send a signal to jobs or processes.")?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
+            return Ok(Value::success());
+        }
+
+        if flags.is_present("list") {
+            let names = SIGNALS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+            my_println!(streams.stdout, "{}", names.join(" "))?;
+            return Ok(Value::success());
+        }
+
+        if let Some(name) = flags.get_value("signal") {
+            signal = Some(parse_signal(&name)?);
+        }
+        let signal = match signal {
+            Some(sig) => sig,
+            None => parse_signal("TERM").or_else(|_| parse_signal("KILL"))?,
+        };
+
+        if targets.is_empty() {
+            return Err("No job or process ID specified".to_string());
+        }
+
+        for target in &targets {
+            if target.starts_with('%') {
+                let id = jobs::parse_spec(target)?;
+                jobs::with_job(id, |job| job.signal(signal))?;
+            } else {
+                kill_process(target, signal)?;
+            }
+        }
+        Ok(Value::success())
+    }
+
+    fn is_external(&self) -> bool {
+        false
+    }
+
+    fn is_thread_safe(&self) -> bool {
+        false
+    }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+}
+
+#[ctor::ctor]
+fn register() {
+    register_command(ShellCommand {
+        name: "jobs".to_string(),
+        inner: Arc::new(JobControl::new(Mode::Jobs)),
+    });
+    register_command(ShellCommand {
+        name: "fg".to_string(),
+        inner: Arc::new(JobControl::new(Mode::Fg)),
+    });
+    register_command(ShellCommand {
+        name: "bg".to_string(),
+        inner: Arc::new(JobControl::new(Mode::Bg)),
+    });
+    register_command(ShellCommand {
+        name: "wait".to_string(),
+        inner: Arc::new(JobControl::new(Mode::Wait)),
+    });
+    register_command(ShellCommand {
+        name: "kill".to_string(),
+        inner: Arc::new(Kill::new()),
+    });
+}
@@ -2,13 +2,14 @@ use super::{register_command, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value};
 use crate::my_println;
+use crate::pipe::Streams;
 use chrono::DateTime;
 use colored::*;
 use core::fmt;
 use std::fs::{self, DirEntry, Metadata};
-use std::io::IsTerminal;
 use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use terminal_size::{terminal_size, Width};
 
@@ -17,10 +18,10 @@ struct ColorScheme {
 }
 
 impl ColorScheme {
-    fn with_scope(scope: &Rc<Scope>) -> Self {
+    fn with_scope(scope: &Rc<Scope>, streams: &Streams) -> Self {
         let color = scope.lookup("NO_COLOR").is_none();
         Self {
-            use_colors: color && std::io::stdout().is_terminal(),
+            use_colors: color && streams.stdout.is_terminal(),
         }
     }
 
@@ -92,8 +93,18 @@ struct CmdArgs {
 }
 
 impl CmdArgs {
-    fn cannot_access<P: fmt::Display, E: fmt::Display>(&self, path: &P, e: &E) {
-        eprintln!("Cannot access '{}: {}", path, self.colors.render_error(e));
+    fn cannot_access<P: fmt::Display, E: fmt::Display>(
+        &self,
+        streams: &mut Streams,
+        path: &P,
+        e: &E,
+    ) -> Result<(), String> {
+        my_println!(
+            streams.stderr,
+            "Cannot access '{}: {}",
+            path,
+            self.colors.render_error(e)
+        )
     }
 }
 
@@ -111,7 +122,12 @@ impl Dir {
         Self { flags }
     }
 
-    fn parse_args(&self, scope: &Rc<Scope>, args: &[String]) -> Result<CmdArgs, String> {
+    fn parse_args(
+        &self,
+        scope: &Rc<Scope>,
+        args: &[String],
+        streams: &Streams,
+    ) -> Result<CmdArgs, String> {
         let mut flags = self.flags.clone();
         let parsed_args = flags.parse(args)?;
 
@@ -125,34 +141,47 @@ impl Dir {
             } else {
                 parsed_args
             },
-            colors: ColorScheme::with_scope(&scope),
+            colors: ColorScheme::with_scope(&scope, streams),
         };
 
         Ok(cmd_args)
     }
 
-    fn print_help(&self) {
-        println!("Usage: ls [OPTION]... [FILE]...");
-        println!("List information about the FILEs (the current directory by default).");
-        println!("\nOptions:");
-        print!("{}", self.flags.help());
+    fn print_help(&self, streams: &mut Streams) -> Result<(), String> {
+        my_println!(streams.stdout, "Usage: ls [OPTION]... [FILE]...")?;
+        my_println!(
+            streams.stdout,
+            "List information about the FILEs (the current directory by default)."
+        )?;
+        my_println!(streams.stdout, "\nOptions:")?;
+        my_print!(streams.stdout, "{}", self.flags.help())
     }
 }
 
 impl Exec for Dir {
-    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String> {
-        let cmd_args = self.parse_args(scope, args)?;
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        let cmd_args = self.parse_args(scope, args, streams)?;
         if cmd_args.help {
-            self.print_help();
+            self.print_help(streams)?;
             return Ok(Value::success());
         }
 
-        list_entries(&cmd_args)
+        list_entries(&cmd_args, streams)
     }
 
     fn is_external(&self) -> bool {
         false
     }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
 }
 
 #[cfg(windows)]
@@ -371,14 +400,14 @@ fn get_owner_and_group(_: PathBuf, _: &fs::Metadata) -> (String, String) {
 #[cfg(windows)]
 use win::{get_owner_and_group, get_permissions};
 
-fn list_entries(args: &CmdArgs) -> Result<Value, String> {
+fn list_entries(args: &CmdArgs, streams: &mut Streams) -> Result<Value, String> {
     for path in &args.paths {
         match fs::metadata(path) {
             Ok(metadata) => {
                 if metadata.is_dir() {
-                    print_dir(path, &args)?;
+                    print_dir(path, &args, streams)?;
                 } else {
-                    print_file(path, &metadata, &args)?;
+                    print_file(path, &metadata, &args, streams)?;
                 }
             }
             Err(e) => return Err(e.to_string()),
@@ -396,7 +425,7 @@ fn make_abspath(path: &str) -> Result<String, String> {
     }
 }
 
-fn print_dir(path: &str, args: &CmdArgs) -> Result<(), String> {
+fn print_dir(path: &str, args: &CmdArgs, streams: &mut Streams) -> Result<(), String> {
     let entries = fs::read_dir(path).map_err(|e| format!("Cannot access '{}': {}", path, e))?;
     let mut entries: Vec<_> = entries
         .collect::<Result<_, _>>()
@@ -404,27 +433,41 @@ fn print_dir(path: &str, args: &CmdArgs) -> Result<(), String> {
     entries.sort_by_key(|e| e.file_name());
 
     if args.paths.len() > 1 {
-        my_println!("\n{}:", make_abspath(path)?)?;
+        my_println!(streams.stdout, "\n{}:", make_abspath(path)?)?;
     }
 
     if args.show_details {
-        print_detailed_entries(&entries, &args)?;
+        print_detailed_entries(&entries, &args, streams)?;
     } else {
-        print_simple_entries(&entries, &args, 4)?;
+        print_simple_entries(&entries, &args, 4, streams)?;
     }
     Ok(())
 }
 
-fn print_file(path: &str, metadata: &Metadata, args: &CmdArgs) -> Result<(), String> {
+fn print_file(
+    path: &str,
+    metadata: &Metadata,
+    args: &CmdArgs,
+    streams: &mut Streams,
+) -> Result<(), String> {
     if args.show_details {
-        print_details(&PathBuf::from(path), metadata, args)?;
+        print_details(&PathBuf::from(path), metadata, args, streams)?;
     } else if args.all_files || !path.starts_with(".") {
-        my_println!("{}", args.colors.render_file_name(path, metadata))?;
+        my_println!(
+            streams.stdout,
+            "{}",
+            args.colors.render_file_name(path, metadata)
+        )?;
     }
     Ok(())
 }
 
-fn print_simple_entries(entries: &Vec<DirEntry>, args: &CmdArgs, spacing: usize) -> Result<(), String> {
+fn print_simple_entries(
+    entries: &Vec<DirEntry>,
+    args: &CmdArgs,
+    spacing: usize,
+    streams: &mut Streams,
+) -> Result<(), String> {
     let max_width = entries
         .iter()
         .filter(|e| args.all_files || !e.file_name().to_string_lossy().starts_with('.'))
@@ -445,7 +488,7 @@ fn print_simple_entries(entries: &Vec<DirEntry>, args: &CmdArgs, spacing: usize)
         }
 
         if current_column >= columns {
-            my_println!("{}", "")?;
+            my_println!(streams.stdout, "{}", "")?;
             current_column = 0;
         }
 
@@ -455,9 +498,15 @@ fn print_simple_entries(entries: &Vec<DirEntry>, args: &CmdArgs, spacing: usize)
         };
 
         if current_column == 0 {
-            my_print!("{:<width$}", file_name, width = column_width)?;
+            my_print!(
+                streams.stdout,
+                "{:<width$}",
+                file_name,
+                width = column_width
+            )?;
         } else {
             my_print!(
+                streams.stdout,
                 " {:<width$}",
                 file_name,
                 width = column_width.saturating_sub(1)
@@ -468,22 +517,27 @@ fn print_simple_entries(entries: &Vec<DirEntry>, args: &CmdArgs, spacing: usize)
     }
 
     if current_column != 0 {
-        my_println!("{}", "")?;
+        my_println!(streams.stdout, "{}", "")?;
     }
 
     Ok(())
 }
 
-fn print_detailed_entries(entries: &Vec<DirEntry>, args: &CmdArgs) -> Result<(), String> {
-    my_println!("total {}", entries.len())?;
+fn print_detailed_entries(
+    entries: &Vec<DirEntry>,
+    args: &CmdArgs,
+    streams: &mut Streams,
+) -> Result<(), String> {
+    my_println!(streams.stdout, "total {}", entries.len())?;
     for entry in entries {
         match entry.metadata() {
             Ok(metadata) => {
-                print_details(&entry.path(), &metadata, args)?;
+                print_details(&entry.path(), &metadata, args, streams)?;
             }
             Err(e) => {
-                args.cannot_access(&entry.file_name().to_string_lossy(), &e);
+                args.cannot_access(streams, &entry.file_name().to_string_lossy(), &e)?;
                 my_println!(
+                    streams.stdout,
                     "?---------  {:OWNER_MAX_LEN$} {:OWNER_MAX_LEN$} {:>12}  {:>12}  {}",
                     "?",
                     "?",
@@ -499,7 +553,12 @@ fn print_detailed_entries(entries: &Vec<DirEntry>, args: &CmdArgs) -> Result<(),
 }
 
 /// Print details for one file entry
-fn print_details(path: &PathBuf, metadata: &Metadata, args: &CmdArgs) -> Result<(), String> {
+fn print_details(
+    path: &PathBuf,
+    metadata: &Metadata,
+    args: &CmdArgs,
+    streams: &mut Streams,
+) -> Result<(), String> {
     let base_name = path
         .file_name()
         .or(Some(path.as_os_str()))
@@ -518,6 +577,7 @@ fn print_details(path: &PathBuf, metadata: &Metadata, args: &CmdArgs) -> Result<
         let (owner, group) = get_owner_and_group(Path::new(path).to_path_buf(), &metadata);
 
         my_println!(
+            streams.stdout,
             "{}{}  {:OWNER_MAX_LEN$} {:OWNER_MAX_LEN$} {:>12}  {}  {}",
             args.colors.render_file_type(format_file_type(&metadata)),
             args.colors.render_permissions(get_permissions(&metadata)),
@@ -573,15 +633,15 @@ fn format_time(time: SystemTime) -> String {
 
 #[ctor::ctor]
 fn register() {
-    let exec = Rc::new(Dir::new());
+    let exec = Arc::new(Dir::new());
 
     register_command(ShellCommand {
         name: "ls".to_string(),
-        inner: Rc::clone(&exec) as Rc<dyn Exec>,
+        inner: Arc::clone(&exec) as Arc<dyn Exec>,
     });
 
     register_command(ShellCommand {
         name: "dir".to_string(),
-        inner: Rc::clone(&exec) as Rc<dyn Exec>,
+        inner: Arc::clone(&exec) as Arc<dyn Exec>,
     });
 }
@@ -1,9 +1,11 @@
-use super::{register_command, Exec, ShellCommand};
+use super::{register_command, ArgType, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value};
+use crate::pipe::Streams;
 use std::fs;
 use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct Mkdir {
     flags: CommandFlags,
@@ -23,15 +25,32 @@ impl Exec for Mkdir {
         false
     }
 
-    fn exec(&self, _name: &str, args: &Vec<String>, _scope: &Rc<Scope>) -> Result<Value, String> {
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+
+    fn arg_type(&self) -> ArgType {
+        ArgType::Dir
+    }
+
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         let args = flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: mkdir [OPTIONS] DIRECTORY...");
-            println!("Create the DIRECTORY(ies), if they do not already exist.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: mkdir [OPTIONS] DIRECTORY...")?;
+            my_println!(
+                streams.stdout,
+                "Create the DIRECTORY(ies), if they do not already exist."
+            )?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -56,15 +75,15 @@ impl Exec for Mkdir {
 
 #[ctor::ctor]
 fn register() {
-    let mkdir = Rc::new(Mkdir::new());
+    let mkdir = Arc::new(Mkdir::new());
 
     register_command(ShellCommand {
         name: "md".to_string(),
-        inner: Rc::clone(&mkdir) as Rc<dyn Exec>,
+        inner: Arc::clone(&mkdir) as Arc<dyn Exec>,
     });
 
     register_command(ShellCommand {
         name: "mkdir".to_string(),
-        inner: Rc::clone(&mkdir) as Rc<dyn Exec>,
+        inner: Arc::clone(&mkdir) as Arc<dyn Exec>,
     });
 }
@@ -1,10 +1,12 @@
 use super::{register_command, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value};
+use crate::pipe::Streams;
 use crate::prompt::{confirm, Answer};
 use std::fs;
 use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct Mv {
     flags: CommandFlags,
@@ -25,15 +27,25 @@ impl Exec for Mv {
         false
     }
 
-    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String> {
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         let args = flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: mv [OPTIONS] SOURCE DEST");
-            println!("Move (rename) SOURCE to DESTination.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: mv [OPTIONS] SOURCE DEST")?;
+            my_println!(streams.stdout, "Move (rename) SOURCE to DESTination.")?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -85,6 +97,6 @@ impl Exec for Mv {
 fn register() {
     register_command(ShellCommand {
         name: "mv".to_string(),
-        inner: Rc::new(Mv::new()),
+        inner: Arc::new(Mv::new()),
     });
 }
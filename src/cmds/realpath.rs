@@ -1,8 +1,10 @@
 use super::{register_command, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value};
+use crate::pipe::Streams;
 use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct Realpath {
     flags: CommandFlags,
@@ -17,15 +19,24 @@ impl Realpath {
 }
 
 impl Exec for Realpath {
-    fn exec(&self, _name: &str, args: &Vec<String>, _: &Rc<Scope>) -> Result<Value, String> {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: realpath [OPTION]... [FILE]...");
-            println!("Print the canonicalized absolute path of each FILE.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: realpath [OPTION]... [FILE]...")?;
+            my_println!(
+                streams.stdout,
+                "Print the canonicalized absolute path of each FILE."
+            )?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -39,22 +50,25 @@ impl Exec for Realpath {
                 .canonicalize()
                 .map_err(|e| format!("Failed to canonicalize path '{}': {}", arg, e))?;
 
-            my_println!("{}", canonical_path.display())?;
+            my_println!(streams.stdout, "{}", canonical_path.display())?;
         }
 
         Ok(Value::success())
-
     }
 
     fn is_external(&self) -> bool {
         false
     }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
 }
 
 #[ctor::ctor]
 fn register() {
     register_command(ShellCommand {
         name: "realpath".to_string(),
-        inner: Rc::new(Realpath::new()),
+        inner: Arc::new(Realpath::new()),
     });
 }
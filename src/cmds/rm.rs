@@ -1,11 +1,13 @@
 use super::{register_command, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value};
+use crate::pipe::Streams;
 use crate::prompt::{confirm, Answer};
 use std::fs;
 use std::io;
 use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct Context {
     interactive: bool,
@@ -91,15 +93,25 @@ impl Exec for Rm {
         false
     }
 
-    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String> {
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         let args = flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: rm [OPTIONS] FILE...");
-            println!("Remove (delete) the specified FILE(s).");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: rm [OPTIONS] FILE...")?;
+            my_println!(streams.stdout, "Remove (delete) the specified FILE(s).")?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -131,6 +143,6 @@ impl Exec for Rm {
 fn register() {
     register_command(ShellCommand {
         name: "rm".to_string(),
-        inner: Rc::new(Rm::new()),
+        inner: Arc::new(Rm::new()),
     });
 }
@@ -1,8 +1,10 @@
-use super::{register_command, Exec, ShellCommand};
+use super::{register_command, ArgType, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::cmds::get_command;
 use crate::eval::{Scope, Value};
+use crate::pipe::Streams;
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct Run {
     flags: CommandFlags,
@@ -18,15 +20,24 @@ impl Run {
 }
 
 impl Exec for Run {
-    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String> {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         let mut command_args = flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: run COMMAND [ARGS]...");
-            println!("Execute the specified command with its arguments.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: run COMMAND [ARGS]...")?;
+            my_println!(
+                streams.stdout,
+                "Execute the specified command with its arguments."
+            )?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -49,7 +60,7 @@ impl Exec for Run {
                 command_args.extend(cmd_flags.split_ascii_whitespace().map(String::from));
             }
 
-            return cmd.exec(cmd_name.as_str(), &command_args, scope);
+            return cmd.exec(cmd_name.as_str(), &command_args, scope, streams);
         }
 
         Err(format!("Command not found: {}", cmd_name))
@@ -58,12 +69,24 @@ impl Exec for Run {
     fn is_external(&self) -> bool {
         false
     }
+
+    fn is_thread_safe(&self) -> bool {
+        false
+    }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+
+    fn arg_type(&self) -> ArgType {
+        ArgType::Command
+    }
 }
 
 #[ctor::ctor]
 fn register() {
     register_command(ShellCommand {
         name: "run".to_string(),
-        inner: Rc::new(Run::new()),
+        inner: Arc::new(Run::new()),
     });
 }
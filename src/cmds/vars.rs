@@ -1,8 +1,10 @@
 use super::{register_command, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value, Variable};
-use std::collections::HashMap;
+use crate::pipe::Streams;
+use std::collections::{BTreeMap, HashMap};
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct Vars {
     flags: CommandFlags,
@@ -34,18 +36,52 @@ impl Vars {
 
         all_vars
     }
+
+    /// Format a map over multiple lines, one key per line, indenting nested maps.
+    fn format_map(map: &BTreeMap<String, Value>, indent: usize) -> String {
+        if map.is_empty() {
+            return "{}".to_string();
+        }
+        let mut result = "{\n".to_string();
+
+        for (key, value) in map {
+            let value = match value {
+                Value::Map(map) => Self::format_map(map, indent + 4),
+                Value::List(_) => format!("[{}]", value),
+                _ => value.to_string(),
+            };
+            result.push_str(&format!(
+                "{:indent$}{}: {}\n",
+                "",
+                key,
+                value,
+                indent = indent + 4
+            ));
+        }
+        result.push_str(&format!("{:indent$}}}", "", indent = indent));
+        result
+    }
 }
 
 impl Exec for Vars {
-    fn exec(&self, _name: &str, args: &Vec<String>, scope: &Rc<Scope>) -> Result<Value, String> {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: vars [-l]");
-            println!("Display variables visible in the current scope.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: vars [-l]")?;
+            my_println!(
+                streams.stdout,
+                "Display variables visible in the current scope."
+            )?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -61,7 +97,12 @@ impl Exec for Vars {
         // Iterate over sorted keys
         for key in keys {
             if let Some(variable) = vars.get(&key) {
-                println!("{}={}", key, variable);
+                match variable.value() {
+                    Value::Map(map) => {
+                        my_println!(streams.stdout, "{}={}", key, Self::format_map(&map, 0))?
+                    }
+                    _ => my_println!(streams.stdout, "{}={}", key, variable)?,
+                }
             }
         }
 
@@ -71,19 +112,180 @@ impl Exec for Vars {
     fn is_external(&self) -> bool {
         false
     }
+
+    fn is_thread_safe(&self) -> bool {
+        false
+    }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+}
+
+struct Export {
+    flags: CommandFlags,
+}
+
+struct Unset {
+    flags: CommandFlags,
+}
+
+impl Export {
+    fn new() -> Self {
+        let mut flags = CommandFlags::new();
+        flags.add_flag('?', "help", "Display this help message");
+        flags.add_flag('n', "remove", "Stop passing NAME to external programs");
+        Export { flags }
+    }
+}
+
+impl Exec for Export {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        let mut flags = self.flags.clone();
+        let parsed_args = flags.parse(args)?;
+
+        if flags.is_present("help") {
+            my_println!(streams.stdout, "Usage: export [-n] [NAME[=VALUE]...]")?;
+            my_println!(
+                streams.stdout,
+                "Pass variables to external programs, or list the exported variables."
+            )?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
+            return Ok(Value::success());
+        }
+
+        if parsed_args.is_empty() {
+            for (name, value) in scope.exported_vars() {
+                my_println!(streams.stdout, "{}={}", name, value)?;
+            }
+            return Ok(Value::success());
+        }
+
+        let remove = flags.is_present("remove");
+
+        for arg in &parsed_args {
+            // The parser turns: export NAME = VALUE into a single NAME=VALUE argument
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value.parse::<Value>().map_err(|e| e.message)?)),
+                None => (arg.as_str(), None),
+            };
+            if remove {
+                let mut current = Some(Rc::clone(scope));
+                while let Some(scope) = current {
+                    if let Some(var) = scope.lookup_local(name) {
+                        var.set_exported(false);
+                    }
+                    current = scope.parent.as_ref().map(Rc::clone);
+                }
+            } else {
+                scope.export_global(name, value)?;
+            }
+        }
+
+        Ok(Value::success())
+    }
+
+    fn is_external(&self) -> bool {
+        false
+    }
+
+    fn is_thread_safe(&self) -> bool {
+        false
+    }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+}
+
+impl Unset {
+    fn new() -> Self {
+        let mut flags = CommandFlags::new();
+        flags.add_flag('?', "help", "Display this help message");
+        Unset { flags }
+    }
+}
+
+impl Exec for Unset {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        let mut flags = self.flags.clone();
+        let parsed_args = flags.parse(args)?;
+
+        if flags.is_present("help") {
+            my_println!(streams.stdout, "Usage: unset NAME...")?;
+            my_println!(
+                streams.stdout,
+                "Remove variables from the current scope and all enclosing scopes."
+            )?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
+            return Ok(Value::success());
+        }
+        if parsed_args.is_empty() {
+            return Err("Missing NAME argument".to_string());
+        }
+
+        for name in &parsed_args {
+            if scope.lookup(name).is_some_and(|var| var.is_readonly()) {
+                return Err(format!("Cannot unset read-only variable: ${}", name));
+            }
+            let mut current = Some(Rc::clone(scope));
+            while let Some(scope) = current {
+                scope.vars.borrow_mut().remove(name);
+                current = scope.parent.as_ref().map(Rc::clone);
+            }
+        }
+
+        Ok(Value::success())
+    }
+
+    fn is_external(&self) -> bool {
+        false
+    }
+
+    fn is_thread_safe(&self) -> bool {
+        false
+    }
+
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
 }
 
 #[ctor::ctor]
 fn register() {
-    let vars = Rc::new(Vars::new());
+    let vars = Arc::new(Vars::new());
 
     register_command(ShellCommand {
         name: "env".to_string(),
-        inner: Rc::clone(&vars) as Rc<dyn Exec>,
+        inner: Arc::clone(&vars) as Arc<dyn Exec>,
     });
 
     register_command(ShellCommand {
         name: "vars".to_string(),
-        inner: Rc::clone(&vars) as Rc<dyn Exec>,
+        inner: Arc::clone(&vars) as Arc<dyn Exec>,
+    });
+
+    register_command(ShellCommand {
+        name: "export".to_string(),
+        inner: Arc::new(Export::new()),
+    });
+
+    register_command(ShellCommand {
+        name: "unset".to_string(),
+        inner: Arc::new(Unset::new()),
     });
 }
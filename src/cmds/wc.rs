@@ -2,12 +2,14 @@ use super::{register_command, Exec, ShellCommand};
 use crate::cmds::flags::CommandFlags;
 use crate::eval::{Scope, Value};
 use crate::my_println;
+use crate::pipe::{Input, Streams};
 use std::fs::{self, File};
-use std::io::{self, BufRead, BufReader};
+use std::io::{self, BufRead, BufReader, Write};
 #[cfg(windows)]
 use std::os::windows::fs::MetadataExt;
 use std::path::Path;
 use std::rc::Rc;
+use std::sync::Arc;
 
 struct WordCount {
     flags: CommandFlags,
@@ -60,9 +62,8 @@ impl WordCount {
         Ok(result)
     }
 
-    fn count_stdin() -> io::Result<CountResult> {
-        let stdin = io::stdin();
-        let reader = stdin.lock();
+    fn count_stdin(stdin: &mut Input) -> io::Result<CountResult> {
+        let reader = stdin.reader();
         let mut result = CountResult {
             lines: 0,
             words: 0,
@@ -82,6 +83,7 @@ impl WordCount {
     }
 
     fn print_result(
+        out: &mut dyn Write,
         result: &CountResult,
         filename: Option<&str>,
         flags: &CommandFlags,
@@ -105,7 +107,7 @@ impl WordCount {
             output.push_str(&format!(" {}", name));
         }
 
-        my_println!("{}", output)?;
+        my_println!(out, "{}", output)?;
         Ok(())
     }
 }
@@ -115,16 +117,29 @@ impl Exec for WordCount {
         false
     }
 
-    fn exec(&self, _name: &str, args: &Vec<String>, _scope: &Rc<Scope>) -> Result<Value, String> {
+    fn cli_flags(&self) -> Option<&CommandFlags> {
+        Some(&self.flags)
+    }
+
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        _scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
         let mut flags = self.flags.clone();
         let args = flags.parse(args)?;
 
         if flags.is_present("help") {
-            println!("Usage: wc [OPTION]... [FILE]...");
-            println!("Print newline, word, and byte counts for each FILE, and a total line if more than one FILE is specified.");
-            println!("\nIf no FILE is specified, read from standard input.");
-            println!("\nOptions:");
-            print!("{}", flags.help());
+            my_println!(streams.stdout, "Usage: wc [OPTION]... [FILE]...")?;
+            my_println!(streams.stdout, "Print newline, word, and byte counts for each FILE, and a total line if more than one FILE is specified.")?;
+            my_println!(
+                streams.stdout,
+                "\nIf no FILE is specified, read from standard input."
+            )?;
+            my_println!(streams.stdout, "\nOptions:")?;
+            my_print!(streams.stdout, "{}", flags.help())?;
             return Ok(Value::success());
         }
 
@@ -137,8 +152,8 @@ impl Exec for WordCount {
 
         if args.is_empty() {
             // Read from stdin
-            match WordCount::count_stdin() {
-                Ok(result) => WordCount::print_result(&result, None, &flags)?,
+            match WordCount::count_stdin(&mut streams.stdin) {
+                Ok(result) => WordCount::print_result(&mut streams.stdout, &result, None, &flags)?,
                 Err(e) => return Err(format!("Error reading stdin: {}", e)),
             }
         } else {
@@ -146,20 +161,20 @@ impl Exec for WordCount {
                 let path = Path::new(&file);
                 match WordCount::count_file(path) {
                     Ok(result) => {
-                        WordCount::print_result(&result, Some(&file), &flags)?;
+                        WordCount::print_result(&mut streams.stdout, &result, Some(&file), &flags)?;
                         total.lines += result.lines;
                         total.words += result.words;
                         total.chars += result.chars;
                         total.bytes += result.bytes;
                     }
                     Err(e) => {
-                        eprintln!("{}: {}", file, e);
+                        my_println!(streams.stderr, "{}: {}", file, e)?;
                     }
                 }
             }
 
             if args.len() > 1 {
-                WordCount::print_result(&total, Some("total"), &flags)?;
+                WordCount::print_result(&mut streams.stdout, &total, Some("total"), &flags)?;
             }
         }
 
@@ -171,6 +186,6 @@ impl Exec for WordCount {
 fn register() {
     register_command(ShellCommand {
         name: "wc".to_string(),
-        inner: Rc::new(WordCount::new()),
+        inner: Arc::new(WordCount::new()),
     });
 }
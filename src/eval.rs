@@ -1,31 +1,44 @@
-use crate::cmds::{get_command, Exec, ShellCommand};
+use crate::cmds::{
+    check_exit_status, exit_code, get_alias, get_command, list_registered_commands,
+    register_command, shell_program, wait_child, Exec, ShellCommand,
+};
+use crate::jobs;
+use crate::pipe::{self, Input, Output, Streams};
 use crate::prompt::{confirm, Answer};
-use gag::{BufferRedirect, Gag, Redirect};
 use glob::glob;
 use regex::Regex;
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::cmp::Ordering;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
 use std::fmt::{self, Debug};
-use std::fs::{File, OpenOptions};
-use std::io::{self, Read};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
 use std::iter::Peekable;
 use std::path::{Path, PathBuf};
-use std::process::{Command as StdCommand, Stdio};
+use std::process::Child;
 use std::rc::Rc;
 use std::str::FromStr;
 use std::sync::atomic::Ordering::SeqCst;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread::{self, JoinHandle};
 
-pub const KEYWORDS: [&str; 8] = [
-    "BREAK", "CONTINUE", "ELSE", "FOR", "IF", "IN", "QUIT", "WHILE",
+pub const KEYWORDS: [&str; 15] = [
+    "BREAK", "CATCH", "CONTINUE", "DEF", "ELSE", "FINALLY", "FOR", "IF", "IN", "LET", "MATCH",
+    "QUIT", "READONLY", "TRY", "WHILE",
 ];
 
+/// The commands that evaluate a script in the current scope, see SourceExpr.
+pub const SOURCE_COMMANDS: [&str; 2] = ["source", "."];
+
 #[derive(Clone, Debug, PartialEq)]
 enum Op {
     And,
     Append,
     Assign,
+    BitAnd,
+    BitOr,
+    BitXor,
     Div,
     Equals,
     Gt,
@@ -36,11 +49,14 @@ enum Op {
     Mul,
     Lt,
     Lte,
+    Match,
     Not,
     NotEquals,
     Or,
     Pipe,
     Plus,
+    Shl,
+    Shr,
     Write,
 }
 
@@ -50,6 +66,9 @@ impl fmt::Display for Op {
             Op::And => write!(f, "&&"),
             Op::Append => write!(f, "=>>"),
             Op::Assign => write!(f, "="),
+            Op::BitAnd => write!(f, "&"),
+            Op::BitOr => write!(f, "|"),
+            Op::BitXor => write!(f, "^"),
             Op::Div => write!(f, "/"),
             Op::Equals => write!(f, "=="),
             Op::Gt => write!(f, ">"),
@@ -60,20 +79,29 @@ impl fmt::Display for Op {
             Op::Mul => write!(f, "*"),
             Op::Lt => write!(f, "<"),
             Op::Lte => write!(f, "<="),
+            Op::Match => write!(f, "=~"),
             Op::Not => write!(f, "!"),
             Op::NotEquals => write!(f, "!="),
             Op::Or => write!(f, "||"),
             Op::Pipe => write!(f, "|"),
             Op::Plus => write!(f, "+"),
+            Op::Shl => write!(f, "<<"),
+            Op::Shr => write!(f, ">>"),
             Op::Write => write!(f, "=>"),
         }
     }
 }
 
-#[derive(Debug, PartialEq, PartialOrd)]
+/// Operator precedence, lowest first; operators of the same priority associate to the left.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 enum Priority {
     VeryLow,
     Low,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Shift,
+    Additive,
     High,
 }
 
@@ -88,11 +116,15 @@ impl Op {
             | Op::Gte
             | Op::Lt
             | Op::Lte
+            | Op::Match
             | Op::Not
             | Op::NotEquals
-            | Op::Minus
-            | Op::Plus
             | Op::Write => Priority::Low,
+            Op::BitOr => Priority::BitOr,
+            Op::BitXor => Priority::BitXor,
+            Op::BitAnd => Priority::BitAnd,
+            Op::Shl | Op::Shr => Priority::Shift,
+            Op::Minus | Op::Plus => Priority::Additive,
             _ => Priority::High,
         }
     }
@@ -107,10 +139,12 @@ enum Token {
     End,
     Keyword(String),
     Literal((String, bool)),
+    List(String),
     Operator(Op),
     LeftParen,
     RightParen,
     Semicolon,
+    Ampersand,
 }
 
 /// Location information for error reporting
@@ -118,11 +152,15 @@ enum Token {
 pub struct Location {
     pub line: u32,
     pub col: u32,
+    pub file: Option<&'static str>, // None for interactive input and -c commands
 }
 
 impl fmt::Display for Location {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "[{}:{}]", self.line, self.col)
+        match self.file {
+            Some(file) => write!(f, "[{}:{}:{}]", file, self.line, self.col),
+            None => write!(f, "[{}:{}]", self.line, self.col),
+        }
     }
 }
 
@@ -133,7 +171,30 @@ trait HasLocation {
 
 impl Location {
     fn new() -> Self {
-        Self { line: 1, col: 1 }
+        Self {
+            line: 1,
+            col: 1,
+            file: None,
+        }
+    }
+
+    /// Start of a source file. File names are interned, so that Location stays Copy.
+    fn with_file(name: &str) -> Self {
+        static FILE_NAMES: Mutex<Vec<&'static str>> = Mutex::new(Vec::new());
+
+        let mut names = FILE_NAMES.lock().unwrap();
+        let file = match names.iter().find(|&&n| n == name) {
+            Some(&n) => n,
+            None => {
+                let n: &'static str = Box::leak(name.to_string().into_boxed_str());
+                names.push(n);
+                n
+            }
+        };
+        Self {
+            file: Some(file),
+            ..Self::new()
+        }
     }
 
     fn next_line(&mut self) {
@@ -162,6 +223,7 @@ pub struct Status {
     checked: bool,
     cmd: String,
     negated: bool,
+    pipestatus: Option<String>, // Exit codes of the stages, if a pipeline
     result: EvalResult<Value>,
     scope: Rc<Scope>,
 }
@@ -183,6 +245,7 @@ impl Status {
             checked: false,
             cmd,
             negated: false,
+            pipestatus: None,
             result: result.clone(),
             scope: Rc::clone(&scope),
         }))
@@ -192,6 +255,9 @@ impl Status {
         if let Err(e) = &self.result {
             collect_var(scope, "__errors", format!("{}: {}", self.cmd, e.message));
         }
+        if let Some(codes) = &self.pipestatus {
+            scope.insert("__pipestatus".to_string(), Value::Str(codes.clone()));
+        }
 
         self.checked = true;
 
@@ -207,7 +273,11 @@ impl Status {
             Ok(Value::Stat(status)) => {
                 if !status.borrow().checked {
                     status.borrow_mut().checked = true;
-                    return status.borrow().result.clone();
+                    let status = status.borrow();
+                    return status.result.clone().map_err(|mut e| {
+                        e.cmd.get_or_insert_with(|| status.cmd.clone());
+                        e
+                    });
                 }
             }
             _ => {} // Propagate the error
@@ -228,6 +298,8 @@ pub enum Value {
     Real(f64),
     Str(String),
     Stat(Rc<RefCell<Status>>),
+    List(Vec<Value>),
+    Map(BTreeMap<String, Value>),
 }
 
 impl Default for Value {
@@ -251,6 +323,25 @@ impl fmt::Display for Value {
             Value::Stat(s) => {
                 write!(f, "{}", s.borrow())
             }
+            Value::List(items) => {
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                Ok(())
+            }
+            Value::Map(map) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in map.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}: {}", key, value)?;
+                }
+                write!(f, "}}")
+            }
         }
     }
 }
@@ -269,6 +360,27 @@ impl FromStr for Value {
     }
 }
 
+/// Parse integers with a radix prefix: 0x (hexadecimal), 0o (octal) or 0b (binary).
+/// Only operands are parsed so, see eval_operand; not command arguments or output.
+fn parse_radix(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(s) => (true, s),
+        None => (false, s),
+    };
+    let radix = match s.get(..2)?.to_ascii_lowercase().as_str() {
+        "0x" => 16,
+        "0o" => 8,
+        "0b" => 2,
+        _ => return None,
+    };
+    let digits = &s[2..];
+    if !digits.chars().all(|c| c.is_digit(radix)) {
+        return None; // from_str_radix accepts a sign
+    }
+    let i = i64::from_str_radix(digits, radix).ok()?;
+    Some(if negative { -i } else { i })
+}
+
 impl Value {
     pub fn success() -> Self {
         Value::Int(0)
@@ -285,6 +397,7 @@ enum Jump {
 pub struct EvalError {
     pub loc: Location,
     pub message: String,
+    cmd: Option<String>, // The command that failed, if any
     jump: Option<Jump>,
 }
 
@@ -293,23 +406,48 @@ impl EvalError {
         Self {
             loc,
             message,
+            cmd: None,
             jump: None,
         }
     }
 
+    /// Convert to a map for inspection in CATCH blocks
+    fn to_value(&self) -> Value {
+        let mut map = BTreeMap::new();
+        map.insert(
+            "cmd".to_string(),
+            Value::Str(self.cmd.clone().unwrap_or_default()),
+        );
+        map.insert("message".to_string(), Value::Str(self.message.clone()));
+        map.insert("line".to_string(), Value::Int(self.loc.line as _));
+        map.insert("col".to_string(), Value::Int(self.loc.col as _));
+        map.insert(
+            "file".to_string(),
+            Value::Str(self.loc.file.unwrap_or_default().to_string()),
+        );
+        Value::Map(map)
+    }
+
     pub fn show(&self, input: &String) {
         // TODO: deal with wrap around
         let line = self.loc.line as usize;
         let col = self.loc.col as usize;
 
-        // Get the problematic line from the input
-        let lines: Vec<&str> = input.lines().collect();
+        // Get the problematic line from the input, or from the sourced file
+        let text = match self.loc.file {
+            Some(file) => fs::read_to_string(file).unwrap_or_default(),
+            None => input.clone(),
+        };
+        let lines: Vec<&str> = text.lines().collect();
         let error_line = lines.get(line - 1).unwrap_or(&"");
 
         // Create the error indicator
         let indicator = "-".repeat(col - 1) + "^";
 
-        eprintln!("Error at line {}, column {}:", line, col);
+        match self.loc.file {
+            Some(file) => eprintln!("Error in {}, line {}, column {}:", file, line, col),
+            None => eprintln!("Error at line {}, column {}:", line, col),
+        }
         eprintln!("{}", error_line);
         eprintln!("{}", indicator);
         eprintln!("{}", self.message);
@@ -353,8 +491,17 @@ struct Parser<I: Iterator<Item = char>> {
     group: Rc<Expression>,
     group_stack: Vec<Rc<Expression>>,
     globbed_tokens: Vec<String>,
+    pending_tokens: Vec<Token>, // Read ahead, or expanded from an alias; in reverse order
+    is_pending: bool,           // Did the last token come from pending_tokens?
+    env_overrides: Vec<(String, Rc<Expression>)>, // NAME=VALUE before the next command
+    declaration: Option<Declaration>, // LET or READONLY, for the assignment that follows
+    functions: HashSet<String>, // Defined with DEF in the input, see declare_functions
+    aliases: HashSet<String>,   // Defined with ALIAS in the input, see parse_definition
+    sourcing: bool,             // Does the current top-level statement call SOURCE?
+    resume: bool,               // Stopped after a statement that calls SOURCE, see eval
     text: String,
     quoted: bool,
+    source: String, // Consumed input, used for capturing function bodies.
 }
 
 impl<I: Iterator<Item = char>> HasLocation for Parser<I> {
@@ -436,8 +583,17 @@ where
             group: new_group(loc, &scope),
             group_stack: Vec::new(),
             globbed_tokens: Vec::new(),
+            pending_tokens: Vec::new(),
+            is_pending: false,
+            env_overrides: Vec::new(),
+            declaration: None,
+            functions: HashSet::new(),
+            aliases: HashSet::new(),
+            sourcing: false,
+            resume: false,
             text: String::new(),
             quoted: false,
+            source: String::new(),
         }
     }
 
@@ -448,12 +604,17 @@ where
     fn is_delimiter(&self, tok: &str, c: char) -> bool {
         // Forward slashes and dashes need special handling, since they occur in
         // paths and command line options; it is unreasonable to require quotes.
-        if "/-*".contains(c) {
+        // Likewise for percent signs in job specifications, e.g. kill %1,
+        // and for carets in regular expressions, e.g. grep ^fn
+        if "/-*%^".contains(c) {
             if tok.is_empty() {
                 return !self.group.is_args()
                     && !self.current_expr.is_cmd()
                     && !self.current_expr.is_empty();
             }
+            if tok.contains("$(") {
+                return false; // Do not run commands while parsing
+            }
             match parse_value(tok, self.loc, &self.scope) {
                 Ok(Value::Int(_)) | Ok(Value::Real(_)) => true,
                 _ => false,
@@ -464,9 +625,93 @@ where
         }
     }
 
+    /// Is the next token the pattern of a MATCH arm?
+    fn is_match_pattern(&self) -> bool {
+        self.current_expr.is_empty() && self.expr_stack.last().is_some_and(|e| e.is_match())
+    }
+
+    /// Is the expression before an ampersand or a vertical bar an operand of bitwise
+    /// AND and OR, rather than a command to run in the background or a pipe source?
+    fn is_bitwise_operand(&self) -> bool {
+        !self.group.is_args() && self.current_expr.is_arithmetic()
+    }
+
+    /// List and map literals are allowed in expressions and FOR arguments, but not in
+    /// the arguments of commands, where brackets are glob patterns, e.g. ls [a-c]*
+    fn is_list_allowed(&self) -> bool {
+        if self.is_match_pattern() {
+            return false; // MATCH arm patterns may use brackets, e.g. [a-c]*
+        }
+        !self.group.is_args() || self.expr_stack.last().is_some_and(|e| e.is_for())
+    }
+
+    /// Read a command substitution, e.g. $(date), into the current token,
+    /// up to the matching parenthesis. The command runs at evaluation time.
+    fn read_substitution(&mut self) -> EvalResult {
+        let mut depth = 0;
+        let mut quoted = false;
+        let mut escaped = false;
+
+        while let Some(c) = self.chars.peek().cloned() {
+            if c == '\n' {
+                self.loc.next_line();
+            }
+            self.text.push(c);
+            self.next();
+
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '"' => quoted ^= true,
+                '(' if !quoted => depth += 1,
+                ')' if !quoted => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+        }
+        error(self, "Unbalanced parentheses in command substitution")
+    }
+
+    /// Read the rest of a list or map literal, e.g. [1 2 3] or {a: 1}, into the current
+    /// token, up to the matching bracket. The elements are evaluated at evaluation time.
+    fn read_list(&mut self) -> EvalResult {
+        let mut depth = 1; // The opening bracket is already consumed
+        let mut quoted = false;
+        let mut escaped = false;
+
+        while let Some(c) = self.chars.peek().cloned() {
+            if c == '\n' {
+                self.loc.next_line();
+            }
+            self.text.push(c);
+            self.next();
+
+            match c {
+                _ if escaped => escaped = false,
+                '\\' if quoted => escaped = true,
+                '"' => quoted ^= true,
+                '[' | '{' if !quoted => depth += 1,
+                ']' | '}' if !quoted => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+                _ => {}
+            }
+        }
+        error(self, "Unbalanced brackets in list")
+    }
+
     fn next(&mut self) {
         self.loc.col += 1;
-        self.chars.next();
+        if let Some(c) = self.chars.next() {
+            self.source.push(c);
+        }
     }
 
     fn glob_literal(&mut self) -> EvalResult<Token> {
@@ -487,6 +732,11 @@ where
                 }
             }
 
+            // Patterns of MATCH arms are not expanded against the file system
+            if self.is_match_pattern() {
+                return Ok(Token::Literal((self.text.clone(), self.quoted)));
+            }
+
             match glob(&self.text) {
                 Ok(paths) => {
                     self.globbed_tokens = paths
@@ -507,6 +757,10 @@ where
     #[rustfmt::skip]
     pub fn next_token(&mut self) -> EvalResult<Token> {
 
+        self.is_pending = !self.pending_tokens.is_empty();
+        if let Some(tok) = self.pending_tokens.pop() {
+            return Ok(tok);
+        }
         if !self.globbed_tokens.is_empty() {
             return Ok(Token::Literal((self.globbed_tokens.remove(0), false)));
         }
@@ -530,15 +784,83 @@ where
                 self.next();
                 continue;
             }
+            if self.text.is_empty() && !c.is_whitespace() {
+                self.prev_loc = self.loc; // Start of the token
+            }
             match c {
                 '#' => { self.comment = true; self.next(); }
-                '%' => token!(self, tok, Token::Operator(Op::Mod)),
+                '%' => {
+                    if !self.is_delimiter(&self.text, c) {
+                        self.text.push(c);
+                    } else {
+                        check_text!(self, tok);
+                        tok = Token::Operator(Op::Mod)
+                    }
+                    self.next();
+                }
+                '[' if self.text.is_empty() && self.is_list_allowed() => {
+                    // A bracket followed by whitespace is not a list, so that
+                    // the test command, e.g. [ -d /tmp ], keeps working.
+                    self.text.push(c);
+                    self.next();
+                    match self.chars.peek() {
+                        Some(&next_c) if !next_c.is_whitespace() => {
+                            self.read_list()?;
+                            tok = Token::List(self.text.clone());
+                        }
+                        _ => {}
+                    }
+                }
+                '{' if self.text.is_empty() && self.is_list_allowed() => {
+                    self.text.push(c);
+                    self.next();
+                    self.read_list()?;
+                    tok = Token::List(self.text.clone());
+                }
                 '(' => token!(self, tok, Token::LeftParen),
                 ')' => token!(self, tok, Token::RightParen),
                 ';' => token!(self, tok, Token::Semicolon),
                 '+' => token!(self, tok, Token::Operator(Op::Plus)),
-                '&' => token!(self, tok, '&', Token::Operator(Op::And)),
-                '|' => token!(self, tok, '|', Token::Operator(Op::Pipe), Token::Operator(Op::Or)),
+                '&' => {
+                    check_text!(self, tok);
+                    self.next();
+                    if self.chars.peek() == Some(&'&') {
+                        self.next();
+                        tok = Token::Operator(Op::And);
+                        continue;
+                    }
+                    while matches!(self.chars.peek(), Some(' ') | Some('\t')) {
+                        self.next();
+                    }
+                    // A trailing ampersand runs the statement in the background
+                    let trailing = matches!(self.chars.peek(), None | Some(';' | '\n' | '\r' | ')' | '#'));
+                    if !trailing && self.is_bitwise_operand() {
+                        tok = Token::Operator(Op::BitAnd);
+                    } else {
+                        tok = Token::Ampersand;
+                    }
+                }
+                '|' => {
+                    check_text!(self, tok);
+                    self.next();
+                    if self.chars.peek() == Some(&'|') {
+                        self.next();
+                        tok = Token::Operator(Op::Or);
+                    } else if self.is_bitwise_operand() {
+                        tok = Token::Operator(Op::BitOr);
+                    } else {
+                        tok = Token::Operator(Op::Pipe);
+                    }
+                }
+                '^' => {
+                    if !self.is_delimiter(&self.text, c) {
+                        self.text.push(c);
+                    } else {
+                        check_text!(self, tok);
+                        tok = Token::Operator(Op::BitXor)
+                    }
+                    self.next();
+                }
                 '!' => token!(self, tok, '=', Token::Operator(Op::Not), Token::Operator(Op::NotEquals)),
                 '*' => {
                     if !self.is_delimiter(&self.text, c) {
@@ -549,8 +871,24 @@ where
                     }
                     self.next();
                 }
-                '<' => token!(self, tok, '=', Token::Operator(Op::Lt), Token::Operator(Op::Lte)),
-                '>' => token!(self, tok, '=', Token::Operator(Op::Gt), Token::Operator(Op::Gte)),
+                '<' => {
+                    check_text!(self, tok);
+                    self.next();
+                    tok = match self.chars.peek() {
+                        Some('=') => { self.next(); Token::Operator(Op::Lte) }
+                        Some('<') => { self.next(); Token::Operator(Op::Shl) }
+                        _ => Token::Operator(Op::Lt),
+                    };
+                }
+                '>' => {
+                    check_text!(self, tok);
+                    self.next();
+                    tok = match self.chars.peek() {
+                        Some('=') => { self.next(); Token::Operator(Op::Gte) }
+                        Some('>') => { self.next(); Token::Operator(Op::Shr) }
+                        _ => Token::Operator(Op::Gt),
+                    };
+                }
                 '=' => {
                     check_text!(self, tok);
                     self.next();
@@ -560,6 +898,11 @@ where
                             tok = Token::Operator(Op::Equals);
                             continue;
                         }
+                        if next_c == '~' {
+                            self.next();
+                            tok = Token::Operator(Op::Match);
+                            continue;
+                        }
                         if next_c == '>' {
                             self.next();
                             if let Some(&next_c) = self.chars.peek() {
@@ -626,6 +969,8 @@ where
                             self.quoted = true;
                             self.in_quotes ^= true;
                             self.next();
+                        } else if next_c == '(' && self.text.ends_with('$') {
+                            self.read_substitution()?;
                         } else {
                             if self.in_quotes || !self.is_delimiter(&self.text, next_c) {
                                 self.text.push(next_c);
@@ -691,6 +1036,8 @@ where
             Expression::Bin(e) => e.borrow_mut().add_child(expr),
             Expression::Branch(e) => e.borrow_mut().add_child(expr),
             Expression::Cmd(e) => e.borrow_mut().add_child(expr),
+            Expression::Def(e) => e.borrow_mut().add_child(expr),
+            Expression::Source(e) => e.borrow_mut().add_child(expr),
             Expression::Empty => {
                 self.current_expr = Rc::clone(expr);
                 Ok(())
@@ -699,6 +1046,8 @@ where
             Expression::For(e) => e.borrow_mut().add_child(expr),
             Expression::Leaf(_) => error(self, "Unexpected expression after literal"),
             Expression::Loop(e) => e.borrow_mut().add_child(expr),
+            Expression::Match(e) => e.borrow_mut().add_child(expr),
+            Expression::Try(e) => e.borrow_mut().add_child(expr),
         }
     }
 
@@ -717,15 +1066,16 @@ where
         }
     }
 
-    fn pop_binary_ops(&mut self, end_statement: bool) -> EvalResult {
+    fn pop_binary_ops(&mut self, priority: Priority) -> EvalResult {
         while let Some(stack_top) = self.expr_stack.last() {
             // If the expression on the top of the expression stack is a binary
             // expression, pop it; add current expression to it; then make it the
             // new current expression.
 
-            // If not at the end of a statement, do not pop the stack past VeryLow priority ops.
+            // Stop at operators of lower priority; VeryLow ones are popped only
+            // at the end of a statement.
 
-            if stack_top.is_bin() && (end_statement || stack_top.priority() > Priority::VeryLow) {
+            if stack_top.is_bin() && stack_top.priority() >= priority {
                 let expr = Rc::clone(&self.current_expr);
                 self.current_expr = self.expr_stack.pop().unwrap();
 
@@ -757,14 +1107,14 @@ where
         let group = Rc::clone(&self.group);
 
         if let Expression::Args(g) = &*group {
-            self.pop_binary_ops(true)?;
+            self.pop_binary_ops(Priority::VeryLow)?;
             if !self.current_expr.is_empty() {
                 g.borrow_mut().add_child(&self.current_expr)?;
             }
             self.pop_group()?;
         } else if !self.current_expr.is_empty() {
             if let Expression::Group(g) = &*group {
-                self.pop_binary_ops(true)?;
+                self.pop_binary_ops(Priority::VeryLow)?;
                 g.borrow_mut().add_child(&self.current_expr)?;
             } else {
                 panic!("Unexpected group error");
@@ -815,6 +1165,17 @@ where
         Ok(())
     }
 
+    /// Is a parenthesized block, e.g. the body of a loop, still waiting for its closing
+    /// parenthesis? The outermost group is the input itself, and argument lists are
+    /// closed implicitly.
+    fn has_open_block(&self) -> bool {
+        self.group_stack
+            .iter()
+            .skip(1)
+            .chain(self.group_stack.first().map(|_| &self.group))
+            .any(|g| !g.is_args())
+    }
+
     fn pop(&mut self) -> EvalResult {
         self.finalize_groups()?;
         self.pop_group()
@@ -844,6 +1205,11 @@ where
     }
 
     fn parse(&mut self, quit: &mut bool) -> EvalResult<Rc<Expression>> {
+        if self.resume {
+            self.resume = false;
+            self.sourcing = false;
+            self.group = new_group(self.loc, &self.scope);
+        }
         loop {
             let tok = self.next_token()?;
             match &tok {
@@ -858,6 +1224,7 @@ where
                         return error(self, "Unmatched right parenthesis");
                     }
                     self.pop()?;
+                    self.end_def();
                 }
                 Token::Semicolon => {
                     self.finalize_groups()?;
@@ -869,6 +1236,23 @@ where
                     if !self.current_expr.is_for() {
                         self.clear_current();
                     }
+                    if self.sourcing && self.group_stack.is_empty() && self.expr_stack.is_empty() {
+                        self.resume = true;
+                        break;
+                    }
+                }
+                Token::Ampersand => {
+                    // Trailing ampersand: run the statement in the background.
+                    self.finalize_groups()?;
+
+                    match &*self.current_expr {
+                        Expression::Cmd(cmd) => cmd.borrow_mut().background = true,
+                        Expression::Bin(bin) if bin.borrow().op == Op::Pipe => {
+                            bin.borrow_mut().background = true
+                        }
+                        _ => return error(self, "Expecting command or pipeline before &"),
+                    }
+                    self.clear_current();
                 }
                 Token::Keyword(word) => {
                     if word == "QUIT" {
@@ -916,6 +1300,10 @@ where
                         })));
                         self.add_expr(&expr)?;
                         self.current_expr = expr;
+                    } else if word == "DEF" {
+                        let expr = self.parse_def()?;
+                        self.add_expr(&expr)?;
+                        self.current_expr = expr;
                     } else if word == "WHILE" {
                         let expr = Rc::new(Expression::Loop(RefCell::new(LoopExpr {
                             cond: self.empty(),
@@ -924,22 +1312,94 @@ where
                             scope: Rc::clone(&self.scope),
                         })));
                         self.add_expr(&expr)?;
+                    } else if word == "TRY" {
+                        let expr = Rc::new(Expression::Try(RefCell::new(TryExpr {
+                            body: self.empty(),
+                            var: String::default(),
+                            handler: self.empty(),
+                            finally: self.empty(),
+                            expect_catch: false, // becomes true once "catch" keyword is seen
+                            expect_finally: false, // ditto for "finally"
+                            loc: self.prev_loc,
+                        })));
+                        self.add_expr(&expr)?;
+                    } else if word == "CATCH" || word == "FINALLY" {
+                        if let Expression::Try(t) = &*self.current_expr {
+                            let expected = if word == "CATCH" {
+                                t.borrow_mut().is_catch_expected()
+                            } else {
+                                t.borrow_mut().is_finally_expected()
+                            };
+                            if !expected {
+                                return error(self, &format!("Unexpected {}", word));
+                            }
+                            self.prev_loc = self.loc;
+                        } else {
+                            return error(self, &format!("{} without TRY", word));
+                        }
+                    } else if word == "MATCH" {
+                        let expr = Rc::new(Expression::Match(RefCell::new(MatchExpr {
+                            value: self.empty(),
+                            body: self.empty(),
+                            loc: self.prev_loc,
+                            scope: Rc::clone(&self.scope),
+                        })));
+                        self.add_expr(&expr)?;
                     } else if word == "BREAK" || word == "CONTINUE" {
                         let expr = Rc::new(Expression::Leaf(Rc::new(Literal {
                             tok: word.clone(),
                             quoted: false,
+                            list: false,
                             loc: self.prev_loc,
                             scope: Rc::clone(&self.scope),
                         })));
                         self.add_expr(&expr)?;
+                    } else if word == "LET" || word == "READONLY" {
+                        self.parse_declaration(word)?;
                     }
                 }
                 Token::Literal((s, quoted)) => {
+                    if !quoted
+                        && !self.group.is_args()
+                        && !self.is_pending
+                        && self.current_expr.is_empty()
+                        && is_identifier(s)
+                        && self.chars.peek() == Some(&'=')
+                        && self.parse_env_overrides(s)?
+                    {
+                        continue;
+                    }
+                    // Aliases are not expanded again in their own expansion, e.g. ls = "ls -al"
+                    if !quoted && !self.group.is_args() && !self.is_pending {
+                        if let Some(expansion) = self.alias_expansion(s) {
+                            self.expand_alias(&expansion)?;
+                            continue;
+                        }
+                    }
+                    if !quoted && !self.group.is_args() && SOURCE_COMMANDS.contains(&s.as_str()) {
+                        let expr = Rc::new(Expression::Source(RefCell::new(SourceExpr {
+                            name: s.clone(),
+                            args: self.empty(),
+                            loc: self.prev_loc,
+                            scope: Rc::clone(&self.scope),
+                        })));
+                        self.add_expr(&expr)?;
+                        self.sourcing = true;
+
+                        self.current_expr = expr;
+                        self.push(Group::Args)?;
+                        continue;
+                    }
                     if !quoted && !self.group.is_args() {
-                        if let Some(cmd) = get_command(s) {
+                        if let Some(cmd) = self.lookup_command(s) {
+                            if s == "alias" || s == "export" {
+                                self.parse_definition(s)?;
+                            }
                             let expr = Rc::new(Expression::Cmd(RefCell::new(Command {
                                 cmd,
                                 args: self.empty(),
+                                env: std::mem::take(&mut self.env_overrides),
+                                background: false,
                                 loc: self.prev_loc,
                                 scope: Rc::clone(&self.scope),
                             })));
@@ -955,6 +1415,7 @@ where
                     let expr = Rc::new(Expression::Leaf(Rc::new(Literal {
                         tok: s.clone(),
                         quoted: *quoted,
+                        list: false,
                         loc: self.prev_loc,
                         scope: Rc::clone(&self.scope),
                     })));
@@ -962,21 +1423,40 @@ where
                         self.add_expr(&expr)?;
                     }
                 }
+                Token::List(s) => {
+                    let expr = Rc::new(Expression::Leaf(Rc::new(Literal {
+                        tok: s.clone(),
+                        quoted: false,
+                        list: true,
+                        loc: self.prev_loc,
+                        scope: Rc::clone(&self.scope),
+                    })));
+                    self.add_expr(&expr)?;
+                }
                 Token::Operator(op) => {
-                    let is_low_priority = op.priority() <= Priority::Low;
+                    // High priority operators bind to the current expression right away;
+                    // others wait on the stack for the operators of higher priority.
+                    let is_low_priority = op.priority() < Priority::High;
 
                     if is_low_priority {
                         if self.group.is_args() {
                             // Finish the arguments of the left hand-side expression
                             self.add_current_expr_to_group()?;
                         }
-                        self.pop_binary_ops(false)?;
+                        self.pop_binary_ops(op.priority().max(Priority::Low))?;
                     }
 
+                    let decl = if *op == Op::Assign {
+                        self.declaration.take()
+                    } else {
+                        None
+                    };
                     let expr = Rc::new(Expression::Bin(RefCell::new(BinExpr {
                         op: op.clone(),
                         lhs: Rc::clone(&self.current_expr),
                         rhs: self.empty(),
+                        decl,
+                        background: false,
                         loc: self.prev_loc,
                         scope: Rc::clone(&self.scope),
                     })));
@@ -1014,6 +1494,288 @@ where
         Ok(Rc::clone(&self.group))
     }
 
+    /// Parse and evaluate the input. Parsing stops after each top-level statement
+    /// that calls SOURCE, and resumes once it is evaluated, so that the functions
+    /// defined in the sourced file are known when parsing the calls that follow.
+    fn eval(&mut self, quit: &mut bool) -> EvalResult<Value> {
+        let mut first = true;
+        loop {
+            let ast = self.parse(quit)?;
+
+            if self.scope.lookup("DUMP_AST").is_some() {
+                dbg!(&ast);
+            }
+            let result = match &*ast {
+                // Keep the variables assigned before SOURCE
+                Expression::Group(g) if !first => g.borrow().eval_content(),
+                _ => ast.eval(),
+            };
+            let result = Status::check_result(result);
+            if result.is_err() || !self.resume || *quit {
+                return result;
+            }
+            first = false;
+        }
+    }
+
+    /// Parse the head of a function definition: DEF NAME (PARAMS...)
+    /// The body is parsed as a regular group, and captured by end_def.
+    fn parse_def(&mut self) -> EvalResult<Rc<Expression>> {
+        let loc = self.prev_loc;
+
+        let name = match self.next_token()? {
+            Token::Literal((name, false)) => name,
+            _ => return error(self, "Expecting function name after DEF"),
+        };
+        if self.next_token()? != Token::LeftParen {
+            return error(self, "Expecting parenthesized parameter list");
+        }
+        let mut params = Vec::new();
+        loop {
+            match self.next_token()? {
+                Token::RightParen => break,
+                Token::Literal((param, false)) => params.push(param),
+                _ => return error(self, "Expecting parameter name"),
+            }
+        }
+        self.prev_loc = self.loc;
+        // Recursive calls in the body, and calls that follow, parse as commands.
+        self.functions.insert(name.clone());
+
+        let def = DefExpr {
+            name,
+            params,
+            body: self.empty(),
+            body_loc: self.loc,
+            body_start: self.source.len(),
+            source: String::new(),
+            loc,
+        };
+
+        Ok(Rc::new(Expression::Def(RefCell::new(def))))
+    }
+
+    /// Capture the source text of a function body once completely parsed.
+    /// The function is registered when the DEF is evaluated.
+    fn end_def(&mut self) {
+        if let Expression::Def(d) = &*self.current_expr {
+            let mut def = d.borrow_mut();
+            if !def.body.is_empty() && def.source.is_empty() {
+                def.source = self.source[def.body_start..].to_string();
+            }
+        }
+    }
+
+    /// Parse the NAME = VALUE argument of ALIAS and EXPORT. ALIAS gets NAME and EXPANSION
+    /// without the equal sign, and the alias is declared, so that it parses as a command in
+    /// the rest of the input; EXPORT gets a single NAME=VALUE argument. Other forms, e.g.
+    /// ALIAS NAME, are parsed as regular arguments.
+    fn parse_definition(&mut self, command: &str) -> EvalResult {
+        let name = self.next_token()?;
+        if let Token::Literal((name, false)) = &name {
+            let op = self.next_token()?;
+            if op == Token::Operator(Op::Assign) {
+                return match self.next_token()? {
+                    Token::Literal((expansion, _)) if command == "alias" => {
+                        self.aliases.insert(name.clone());
+                        self.pending_tokens.push(Token::Literal((expansion, true)));
+                        self.pending_tokens
+                            .push(Token::Literal((name.clone(), false)));
+                        Ok(())
+                    }
+                    Token::Literal((value, quoted)) => {
+                        self.pending_tokens
+                            .push(Token::Literal((format!("{}={}", name, value), quoted)));
+                        Ok(())
+                    }
+                    _ => error(self, &format!("Expecting {} value", command)),
+                };
+            }
+            self.pending_tokens.push(op);
+        }
+        self.pending_tokens.push(name);
+        Ok(())
+    }
+
+    /// Parse the NAME (with an optional type) of LET NAME: TYPE = VALUE, or READONLY NAME = VALUE.
+    /// The NAME = VALUE assignment is then parsed as usual, and declares the variable.
+    fn parse_declaration(&mut self, keyword: &str) -> EvalResult {
+        if self.group.is_args() || !self.current_expr.is_empty() {
+            return error(self, &format!("Unexpected {}", keyword));
+        }
+        let mut name = match self.next_token()? {
+            Token::Literal((name, false)) => name,
+            _ => return error(self, &format!("Expecting variable name after {}", keyword)),
+        };
+        let mut var_type = None;
+
+        // The type may follow the colon with or without blanks, e.g. n:int or n: int
+        if let Some((var_name, type_name)) = name.clone().split_once(':') {
+            let type_name = if type_name.is_empty() {
+                match self.next_token()? {
+                    Token::Literal((type_name, false)) => type_name,
+                    _ => return error(self, "Expecting type after colon"),
+                }
+            } else {
+                type_name.to_string()
+            };
+            var_type = Some(type_name.parse::<VarType>().or_else(|e| error(self, &e))?);
+            name = var_name.to_string();
+        }
+        if !is_identifier(&name) {
+            return error(self, &format!("Invalid variable name: {}", name));
+        }
+        let op = self.next_token()?;
+        if op != Token::Operator(Op::Assign) {
+            return error(
+                self,
+                &format!("Expecting assignment after {} {}", keyword, name),
+            );
+        }
+        self.pending_tokens.push(op);
+        self.pending_tokens.push(Token::Literal((name, false)));
+
+        self.declaration = Some(Declaration {
+            readonly: keyword == "READONLY",
+            var_type,
+        });
+        Ok(())
+    }
+
+    /// Parse NAME=VALUE assignments (no blanks around the equal sign) that precede a
+    /// command, e.g. FOO=1 BAR=$x cmd, which export the variables to the command only.
+    /// When no command follows, as in x=1; echo $x, the tokens are pushed back, and
+    /// parsed as usual.
+    fn parse_env_overrides(&mut self, name: &str) -> EvalResult<bool> {
+        let mut tokens = Vec::new(); // Read ahead, after the first NAME
+        let mut name = name.to_string();
+        let mut env = Vec::new();
+
+        loop {
+            let op = self.next_token()?;
+            tokens.push(op.clone());
+            if op != Token::Operator(Op::Assign)
+                || self.chars.peek().is_none_or(|c| c.is_whitespace())
+            {
+                break;
+            }
+            let value = self.next_token()?;
+            tokens.push(value.clone());
+
+            let Token::Literal((text, quoted)) = value else {
+                break;
+            };
+            env.push((
+                name.clone(),
+                Rc::new(Expression::Leaf(Rc::new(Literal {
+                    tok: text,
+                    quoted,
+                    list: false,
+                    loc: self.prev_loc,
+                    scope: Rc::clone(&self.scope),
+                }))),
+            ));
+
+            while self.chars.peek().is_some_and(|&c| c == ' ' || c == '\t') {
+                self.next();
+            }
+            // Look ahead no further than the next word
+            if !self.globbed_tokens.is_empty()
+                || !self
+                    .chars
+                    .peek()
+                    .is_some_and(|&c| c.is_alphabetic() || "_./~".contains(c))
+            {
+                break;
+            }
+            let word = self.next_token()?;
+            if let Token::Literal((s, false)) = &word {
+                if let Some(expansion) = self.alias_expansion(s) {
+                    self.env_overrides = env;
+                    self.expand_alias(&expansion)?;
+                    return Ok(true);
+                }
+                if self.lookup_command(s).is_some() {
+                    self.env_overrides = env;
+                    self.pending_tokens.push(word);
+                    return Ok(true);
+                }
+                if is_identifier(s) && self.chars.peek() == Some(&'=') {
+                    name = s.clone();
+                    tokens.push(word);
+                    continue;
+                }
+            }
+            tokens.push(word);
+            break;
+        }
+        tokens.reverse();
+        self.pending_tokens.extend(tokens);
+        Ok(false)
+    }
+
+    /// Functions defined in the input parse as commands wherever they are called,
+    /// including before their DEF; otherwise look up the command registry.
+    fn lookup_command(&self, name: &str) -> Option<ShellCommand> {
+        if self.aliases.contains(name) {
+            let decl = DeclaredAlias {
+                name: name.to_string(),
+            };
+            return Some(ShellCommand::new(name.to_string(), Arc::new(decl)));
+        }
+        if self.functions.contains(name) {
+            let decl = DeclaredFunction {
+                name: name.to_string(),
+            };
+            return Some(ShellCommand::new(name.to_string(), Arc::new(decl)));
+        }
+        get_command(name)
+    }
+
+    /// Collect the names of the functions defined with DEF in the complete input,
+    /// ahead of parsing it, so that calls that precede the definitions (e.g. mutually
+    /// recursive functions) parse as commands.
+    fn declare_functions(&mut self, input: &str) {
+        let mut parser = Parser::new(input.chars(), &self.scope);
+        let mut def = false;
+        loop {
+            match parser.next_token() {
+                Ok(Token::End) | Err(_) => break,
+                Ok(Token::Keyword(word)) => def = word == "DEF",
+                Ok(Token::Literal((name, false))) if def => {
+                    self.functions.insert(name);
+                    def = false;
+                }
+                Ok(_) => def = false,
+            }
+        }
+    }
+
+    /// The expansion of an alias defined before this input was parsed; aliases defined
+    /// in the input itself take effect when ALIAS runs, see DeclaredAlias.
+    fn alias_expansion(&self, name: &str) -> Option<String> {
+        if self.aliases.contains(name) {
+            return None;
+        }
+        get_alias(name)
+    }
+
+    /// Replace an alias with the tokens of its expansion, e.g. ll -> ls -al
+    fn expand_alias(&mut self, expansion: &str) -> EvalResult {
+        let mut parser = Parser::new(expansion.chars(), &self.scope);
+        let mut tokens = Vec::new();
+        loop {
+            match parser.next_token() {
+                Ok(Token::End) => break,
+                Ok(tok) => tokens.push(tok),
+                Err(e) => return error(self, &format!("Alias expansion: {}", e.message)),
+            }
+        }
+        tokens.reverse();
+        self.pending_tokens.extend(tokens);
+        Ok(())
+    }
+
     fn rewrite_pipeline(&mut self, expr: &Rc<Expression>) -> EvalResult<bool> {
         assert!(self.current_expr.is_empty());
 
@@ -1052,6 +1814,8 @@ where
                 op: Op::Pipe,
                 lhs: Rc::clone(&head),
                 rhs: Rc::clone(&expr),
+                decl: None,
+                background: false,
                 loc: expr.loc(),
                 scope: Rc::clone(&self.scope),
             })));
@@ -1061,18 +1825,119 @@ where
     }
 }
 
-#[derive(Clone, Debug, PartialEq)]
+/// Types of variables declared with: LET NAME: TYPE = VALUE
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VarType {
+    Int,
+    Real,
+    Str,
+    List,
+    Map,
+}
+
+impl VarType {
+    fn of(val: &Value) -> Option<Self> {
+        match val {
+            Value::Int(_) => Some(VarType::Int),
+            Value::Real(_) => Some(VarType::Real),
+            Value::Str(_) => Some(VarType::Str),
+            Value::List(_) => Some(VarType::List),
+            Value::Map(_) => Some(VarType::Map),
+            Value::Stat(_) => None,
+        }
+    }
+
+    /// Return the value to assign to the variable NAME of this type. Integers are
+    /// converted to reals, other values have to match the type exactly.
+    fn check(self, name: &str, val: Value) -> Result<Value, String> {
+        if let (VarType::Real, Value::Int(i)) = (self, &val) {
+            return Ok(Value::Real(*i as f64));
+        }
+        match VarType::of(&val) {
+            Some(val_type) if val_type == self => Ok(val),
+            Some(val_type) => Err(format!(
+                "Cannot assign {} to {} variable: ${}",
+                val_type, self, name
+            )),
+            None => Err(format!("Cannot assign to {} variable: ${}", self, name)),
+        }
+    }
+}
+
+impl FromStr for VarType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "int" => Ok(VarType::Int),
+            "real" => Ok(VarType::Real),
+            "str" => Ok(VarType::Str),
+            "list" => Ok(VarType::List),
+            "map" => Ok(VarType::Map),
+            _ => Err(format!("Unknown type: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for VarType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            VarType::Int => "int",
+            VarType::Real => "real",
+            VarType::Str => "str",
+            VarType::List => "list",
+            VarType::Map => "map",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Constraints of variables declared with LET or READONLY, checked on assignment
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Declaration {
+    readonly: bool,
+    var_type: Option<VarType>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct Variable {
     val: Rc<RefCell<Value>>,
+    exported: Rc<Cell<bool>>, // Passed to external programs in their environment
+    decl: Declaration,
 }
 
 impl Variable {
     fn new(val: Value) -> Self {
         Self {
             val: Rc::new(RefCell::new(val)),
+            exported: Rc::new(Cell::new(false)),
+            decl: Declaration::default(),
         }
     }
 
+    pub fn is_readonly(&self) -> bool {
+        self.decl.readonly
+    }
+
+    /// Check that the variable can be assigned the value, and convert it to the declared type.
+    pub fn check_assign(&self, name: &str, val: Value) -> Result<Value, String> {
+        if self.decl.readonly {
+            return Err(format!("Cannot assign to read-only variable: ${}", name));
+        }
+        match self.decl.var_type {
+            Some(var_type) => var_type.check(name, val),
+            None => Ok(val),
+        }
+    }
+
+    pub fn is_exported(&self) -> bool {
+        self.exported.get()
+    }
+
+    pub fn set_exported(&self, exported: bool) {
+        self.exported.set(exported);
+    }
+
     fn assign(&self, val: Value) {
         *self.val.borrow_mut() = val;
     }
@@ -1084,9 +1949,7 @@ impl Variable {
 
 impl From<&str> for Variable {
     fn from(value: &str) -> Self {
-        Variable {
-            val: Rc::new(RefCell::new(value.parse::<Value>().unwrap())),
-        }
+        Variable::new(value.parse::<Value>().unwrap())
     }
 }
 
@@ -1131,6 +1994,9 @@ impl Scope {
             "SHELL".to_string(),
             Variable::from(shell.to_string_lossy().to_string().as_str()),
         );
+        for var in vars.values() {
+            var.set_exported(true);
+        }
 
         Rc::new(Scope {
             parent: None,
@@ -1142,8 +2008,143 @@ impl Scope {
         crate::INTERRUPT.load(SeqCst)
     }
 
+    /// Assign a variable in this scope; the variable it replaces, if any, passes on
+    /// its export flag and declaration.
     pub fn insert(&self, var_name: String, val: Value) {
-        self.vars.borrow_mut().insert(var_name, Variable::new(val));
+        self.replace(var_name, Variable::new(val));
+    }
+
+    fn replace(&self, var_name: String, mut var: Variable) {
+        let mut vars = self.vars.borrow_mut();
+        if let Some(old) = vars.get(&var_name) {
+            var.set_exported(old.is_exported());
+            var.decl = old.decl;
+        }
+        vars.insert(var_name, var);
+    }
+
+    /// Create a variable in this scope, subject to the declaration of the variable it
+    /// replaces or shadows, e.g. HOME = /tmp fails if HOME is read-only.
+    fn assign(&self, var_name: &str, val: Value) -> Result<Value, String> {
+        let val = match self.lookup(var_name) {
+            Some(var) => var.check_assign(var_name, val)?,
+            None => val,
+        };
+        self.insert(var_name.to_string(), val.clone());
+        Ok(val)
+    }
+
+    /// Parse text, e.g. command output, into a value for the variable: the text
+    /// is kept as is if the variable is declared str, e.g. LET s: str = "007"
+    fn parse_for_var(&self, var_name: &str, text: &str) -> Result<Value, String> {
+        match self.lookup(var_name).and_then(|var| var.decl.var_type) {
+            Some(VarType::Str) => Ok(Value::Str(text.to_string())),
+            _ => text.parse::<Value>().map_err(|e| e.to_string()),
+        }
+    }
+
+    /// Create a variable in this scope, with the constraints of LET or READONLY.
+    fn declare(&self, var_name: String, val: Value, decl: Declaration) {
+        self.replace(var_name.clone(), Variable::new(val));
+        if let Some(var) = self.vars.borrow_mut().get_mut(&var_name) {
+            var.decl = decl;
+        }
+    }
+
+    /// Assign a variable in this scope, and pass it to external programs.
+    pub fn export(&self, var_name: String, val: Value) {
+        self.insert(var_name.clone(), val);
+        self.vars.borrow()[&var_name].set_exported(true);
+    }
+
+    /// Pass the variable visible from this scope to external programs, after assigning
+    /// it the value, if any. The variable is shared with the outermost scope, so that it
+    /// outlives the current statement.
+    pub fn export_global(&self, var_name: &str, val: Option<Value>) -> Result<(), String> {
+        let mut global = self;
+        while let Some(parent) = &global.parent {
+            global = parent;
+        }
+        match (self.lookup(var_name), val) {
+            (Some(var), val) => {
+                if let Some(val) = val {
+                    var.assign(var.check_assign(var_name, val)?);
+                }
+                var.set_exported(true);
+                global.vars.borrow_mut().insert(var_name.to_string(), var);
+            }
+            (None, Some(val)) => global.export(var_name.to_string(), val),
+            (None, None) => return Err(format!("Variable not found: ${}", var_name)),
+        }
+        Ok(())
+    }
+
+    /// The environment of external programs: the variables exported in this scope or any
+    /// enclosing scope, with the value of the innermost variable of the same name.
+    pub fn exported_vars(&self) -> Vec<(String, String)> {
+        let mut values = HashMap::new();
+        let mut exported = HashSet::new();
+        let mut scope = Some(self);
+
+        while let Some(current) = scope {
+            for (name, var) in current.vars.borrow().iter() {
+                values
+                    .entry(name.clone())
+                    .or_insert_with(|| var.value().to_string());
+                if var.is_exported() {
+                    exported.insert(name.clone());
+                }
+            }
+            scope = current.parent.as_deref();
+        }
+        let mut vars: Vec<_> = values
+            .into_iter()
+            .filter(|(name, _)| exported.contains(name))
+            .collect();
+        vars.sort();
+        vars
+    }
+
+    /// Can the variables visible in this scope be copied to other threads? Command
+    /// statuses cannot, they refer to the scope of the command.
+    fn can_copy_vars(&self) -> bool {
+        let mut scope = Some(self);
+
+        while let Some(current) = scope {
+            let vars = current.vars.borrow();
+            if vars
+                .values()
+                .any(|var| matches!(*var.val.borrow(), Value::Stat(_)))
+            {
+                return false;
+            }
+            scope = current.parent.as_deref();
+        }
+        true
+    }
+
+    /// The names and values of the variables visible in this scope, and whether
+    /// they are exported; used for running commands in other threads.
+    fn copy_vars(&self) -> Vec<(String, SharedValue, bool)> {
+        let exported: HashSet<_> = self.exported_vars().into_iter().map(|v| v.0).collect();
+        let mut values = HashMap::new();
+        let mut scope = Some(self);
+
+        while let Some(current) = scope {
+            for (name, var) in current.vars.borrow().iter() {
+                values
+                    .entry(name.clone())
+                    .or_insert_with(|| SharedValue::new(&var.val.borrow()));
+            }
+            scope = current.parent.as_deref();
+        }
+        values
+            .into_iter()
+            .filter_map(|(name, value)| {
+                let exported = exported.contains(&name);
+                Some((name, value?, exported))
+            })
+            .collect()
     }
 
     pub fn lookup(&self, var_name: &str) -> Option<Variable> {
@@ -1194,6 +2195,178 @@ impl Scope {
     }
 }
 
+/// Variable references: ${EXPR} or $NAME, optionally followed by indices or slices.
+const VAR_PATTERN: &str = r"\$\{([^}]+)\}|\$((?:[a-zA-Z_][a-zA-Z0-9_]*|[0-9]+)(?:\[[^\]]*\])*)";
+
+fn var_regex() -> &'static Regex {
+    static RE: OnceLock<Regex> = OnceLock::new();
+    RE.get_or_init(|| Regex::new(VAR_PATTERN).unwrap())
+}
+
+fn is_identifier(s: &str) -> bool {
+    s.starts_with(|c: char| c.is_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_alphanumeric() || c == '_')
+}
+
+/// Split a variable reference into the name and the indices, e.g. NAME[0][key]
+fn split_indices(var_expr: &str) -> (&str, Vec<&str>) {
+    match var_expr.find('[') {
+        Some(i) if var_expr.ends_with(']') => (
+            &var_expr[..i],
+            var_expr[i + 1..var_expr.len() - 1].split("][").collect(),
+        ),
+        _ => (var_expr, Vec::new()),
+    }
+}
+
+/// Look up a variable, optionally taking its length, or indexing or slicing it:
+/// #NAME, NAME[0], NAME[-1], NAME[1:3], NAME[key], NAME[0][1]. The value is None if
+/// not found. Unless braced, as in ${NAME[0]}, only lists and maps are indexed: the
+/// brackets that follow other variables are returned as text, e.g. in $prefix[0-9]*
+fn lookup_var<'a>(
+    var_expr: &'a str,
+    braced: bool,
+    loc: Location,
+    scope: &Rc<Scope>,
+) -> EvalResult<(Option<Value>, &'a str)> {
+    if let Some(name) = var_expr.strip_prefix('#') {
+        let len = scope.lookup(name).map(|var| match var.value() {
+            Value::List(items) => Value::Int(items.len() as _),
+            Value::Map(map) => Value::Int(map.len() as _),
+            val => Value::Int(val.to_string().chars().count() as _),
+        });
+        return Ok((len, ""));
+    }
+
+    let (name, indices) = split_indices(var_expr);
+    let value = scope.lookup(name).map(|var| var.value());
+
+    if !braced && !matches!(value, Some(Value::List(_) | Value::Map(_))) {
+        return Ok((value, &var_expr[name.len()..]));
+    }
+    match value {
+        Some(mut value) => {
+            for index in indices {
+                value = index_value(value, &expand_vars(index, loc, scope)?, loc)?;
+            }
+            Ok((Some(value), ""))
+        }
+        None => Ok((None, "")),
+    }
+}
+
+/// Convert a (possibly negative, i.e. counting from the end) index into a list position.
+fn list_index(index: &str, len: usize, loc: Location) -> EvalResult<usize> {
+    match index.trim().parse::<i64>() {
+        Ok(i) => {
+            let i = if i < 0 { i + len as i64 } else { i };
+            if i < 0 || i >= len as i64 {
+                return Err(EvalError::new(
+                    loc,
+                    format!("Index out of range: {}", index),
+                ));
+            }
+            Ok(i as usize)
+        }
+        Err(_) => Err(EvalError::new(loc, format!("Invalid index: {}", index))),
+    }
+}
+
+/// Look up a key in a map, or index or slice a list, or the characters of a string.
+/// Negative indices count from the end; slice bounds are clamped to the length.
+fn index_value(val: Value, index: &str, loc: Location) -> EvalResult<Value> {
+    let (items, is_list) = match val {
+        Value::List(items) => (items, true),
+        Value::Map(mut map) => {
+            return map
+                .remove(index)
+                .ok_or_else(|| EvalError::new(loc, format!("Key not found: {}", index)))
+        }
+        Value::Stat(_) => {
+            return Err(EvalError::new(
+                loc,
+                "Cannot index command status".to_string(),
+            ))
+        }
+        _ => {
+            let chars = val
+                .to_string()
+                .chars()
+                .map(|c| Value::Str(c.into()))
+                .collect();
+            (chars, false)
+        }
+    };
+
+    if let Some((start, end)) = index.split_once(':') {
+        let len = items.len() as i64;
+        let parse_bound = |s: &str, default: i64| -> EvalResult<i64> {
+            let s = s.trim();
+            if s.is_empty() {
+                return Ok(default);
+            }
+            match s.parse::<i64>() {
+                Ok(i) if i < 0 => Ok(i + len),
+                Ok(i) => Ok(i),
+                Err(_) => Err(EvalError::new(loc, format!("Invalid index: {}", s))),
+            }
+        };
+        let start = parse_bound(start, 0)?.clamp(0, len) as usize;
+        let end = parse_bound(end, len)?.clamp(start as i64, len) as usize;
+        let slice = items[start..end].to_vec();
+
+        if is_list {
+            Ok(Value::List(slice))
+        } else {
+            Ok(Value::Str(slice.iter().map(|c| c.to_string()).collect()))
+        }
+    } else {
+        let i = list_index(index, items.len(), loc)?;
+        Ok(items.into_iter().nth(i).unwrap())
+    }
+}
+
+/// Assign the element of a list or map at the given indices, or remove it
+/// if the new value is None. Return the previous value of the element.
+fn update_element(
+    container: &mut Value,
+    indices: &[String],
+    new_value: Option<Value>,
+    loc: Location,
+) -> EvalResult<Value> {
+    let (index, rest) = indices.split_first().expect("Expecting index");
+
+    let element = match container {
+        Value::Map(map) => {
+            let key_error = || EvalError::new(loc, format!("Key not found: {}", index));
+            if rest.is_empty() {
+                return match new_value {
+                    Some(value) => Ok(map.insert(index.clone(), value).unwrap_or_default()),
+                    None => map.remove(index).ok_or_else(key_error),
+                };
+            }
+            map.get_mut(index).ok_or_else(key_error)?
+        }
+        Value::List(items) => {
+            let i = list_index(index, items.len(), loc)?;
+            if rest.is_empty() {
+                return match new_value {
+                    Some(value) => Ok(std::mem::replace(&mut items[i], value)),
+                    None => Ok(items.remove(i)),
+                };
+            }
+            &mut items[i]
+        }
+        _ => {
+            return Err(EvalError::new(
+                loc,
+                format!("Cannot assign elements of value: {}", container),
+            ))
+        }
+    };
+    update_element(element, rest, new_value, loc)
+}
+
 /// Parses and expands shell-like variable expressions in a given string.
 /// # Note
 /// Groups need to be enclosed in quotes, to distinguish from normal parentheses.
@@ -1228,11 +2401,11 @@ impl Scope {
 /// "${UNDEFINED_VAR}"             -> ""
 /// "${UNDEFINED_VAR/foo/bar}"     -> ""
 /// ```
-fn parse_value(s: &str, loc: Location, scope: &Rc<Scope>) -> EvalResult<Value> {
-    let re = Regex::new(r"\$\{([^}]+)\}|\$([a-zA-Z_][a-zA-Z0-9_]*)")
-        .map_err(|e| EvalError::new(loc, e.to_string()))?;
+fn expand_vars(s: &str, loc: Location, scope: &Rc<Scope>) -> EvalResult<String> {
+    let mut err = None;
 
-    let result = re.replace_all(s, |caps: &regex::Captures| {
+    let result = var_regex().replace_all(s, |caps: &regex::Captures| {
+        let braced = caps.get(1).is_some();
         let var_expr = caps
             .get(1)
             .or_else(|| caps.get(2))
@@ -1242,9 +2415,9 @@ fn parse_value(s: &str, loc: Location, scope: &Rc<Scope>) -> EvalResult<Value> {
         let parts: Vec<&str> = var_expr.splitn(3, '/').collect();
         let var_name = parts[0];
 
-        match scope.lookup(var_name) {
-            Some(var) => {
-                let mut value = var.value().to_string();
+        match lookup_var(var_name, braced, loc, scope) {
+            Ok((Some(val), text)) => {
+                let mut value = val.to_string();
 
                 if parts.len() == 3 {
                     let search = parts[1];
@@ -1269,17 +2442,200 @@ fn parse_value(s: &str, loc: Location, scope: &Rc<Scope>) -> EvalResult<Value> {
                     }
                 }
 
-                value
+                value + text
+            }
+            Ok((None, text)) => text.to_string(), // Empty if VAR not found
+            Err(e) => {
+                err.get_or_insert(e);
+                String::default()
             }
-            None => String::default(), // Return empty string if VAR not found
         }
     });
 
-    result
+    match err {
+        Some(e) => Err(e),
+        None => Ok(result.into_owned()),
+    }
+}
+
+/// Expand the command substitutions, e.g. $(date), and the variables in a string,
+/// then parse the result into a value.
+fn parse_value(s: &str, loc: Location, scope: &Rc<Scope>) -> EvalResult<Value> {
+    expand_value(s, loc, scope)?
         .parse::<Value>()
         .map_err(|e| EvalError::new(loc, e.to_string()))
 }
 
+/// Expand the variables and command substitutions in a literal.
+fn expand_value(s: &str, loc: Location, scope: &Rc<Scope>) -> EvalResult<String> {
+    let mut result = String::new();
+    let mut rest = s;
+
+    while let Some(start) = rest.find("$(") {
+        let end = match substitution_end(&rest[start + 1..]) {
+            Some(len) => start + 1 + len,
+            None => {
+                return Err(EvalError::new(
+                    loc,
+                    "Unbalanced parentheses in command substitution".to_string(),
+                ))
+            }
+        };
+        result.push_str(&expand_vars(&rest[..start], loc, scope)?);
+        result.push_str(&eval_substitution(&rest[start + 2..end], loc, scope)?);
+        rest = &rest[end + 1..];
+    }
+    result.push_str(&expand_vars(rest, loc, scope)?);
+    Ok(result)
+}
+
+/// Find the parenthesis that closes the one the string starts with,
+/// skipping over nested parentheses and quoted strings.
+fn substitution_end(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut quoted = false;
+    let mut escaped = false;
+
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted ^= true,
+            '(' if !quoted => depth += 1,
+            ')' if !quoted => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Evaluate the command of a substitution in a nested scope, and return
+/// its standard output, without the trailing newlines.
+fn eval_substitution(input: &str, loc: Location, scope: &Rc<Scope>) -> EvalResult<String> {
+    let scope = Scope::new(Some(Rc::clone(scope)));
+    let mut quit = false;
+
+    let (result, output) = pipe::capture(|| {
+        let mut parser = Parser::new(input.chars(), &scope);
+        parser.declare_functions(input);
+        parser.eval(&mut quit)
+    });
+    result.map_err(|e| EvalError::new(loc, format!("$({}): {}", input, e.message)))?;
+
+    let output = String::from_utf8(output).map_err(|e| {
+        EvalError::new(
+            loc,
+            format!("Failed to convert command output from UTF8: {}", e),
+        )
+    })?;
+    Ok(output.trim_end_matches(['\n', '\r']).to_string())
+}
+
+/// Evaluate a literal token. An unquoted reference to a single variable which
+/// holds a list or map evaluates to it; other literals are expanded and parsed.
+fn eval_literal(tok: &str, quoted: bool, loc: Location, scope: &Rc<Scope>) -> EvalResult<Value> {
+    if !quoted {
+        if let Some(caps) = var_regex().captures(tok) {
+            let braced = caps.get(1).is_some();
+            let var_expr = caps.get(1).or_else(|| caps.get(2)).unwrap().as_str();
+
+            if caps[0].len() == tok.len() && !var_expr.contains('/') {
+                match lookup_var(var_expr, braced, loc, scope)? {
+                    (Some(val @ Value::List(_)), "") | (Some(val @ Value::Map(_)), "") => {
+                        return Ok(val)
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+    parse_value(tok, loc, scope)
+}
+
+/// Evaluate a list or map literal.
+fn eval_container(tok: &str, loc: Location, scope: &Rc<Scope>) -> EvalResult<Value> {
+    if tok.starts_with('{') {
+        eval_map(tok, loc, scope)
+    } else {
+        eval_list(tok, loc, scope)
+    }
+}
+
+/// Evaluate a map literal, e.g. {host: example.com port: 22 "my key": [1 2]}.
+/// The values are tokenized like command arguments, and may be lists or maps.
+fn eval_map(tok: &str, loc: Location, scope: &Rc<Scope>) -> EvalResult<Value> {
+    let inner = &tok[1..tok.len() - 1];
+    let mut parser = Parser::new(inner.chars(), scope);
+    let mut map = BTreeMap::new();
+
+    loop {
+        let key = match parser
+            .next_token()
+            .map_err(|e| EvalError::new(loc, e.message))?
+        {
+            Token::End => break,
+            Token::Literal((s, _)) if s.len() > 1 && s.ends_with(':') => {
+                expand_vars(&s[..s.len() - 1], loc, scope)?
+            }
+            _ => {
+                return Err(EvalError::new(
+                    loc,
+                    format!("Expecting key: value in map: {}", tok),
+                ))
+            }
+        };
+        let value = match parser
+            .next_token()
+            .map_err(|e| EvalError::new(loc, e.message))?
+        {
+            Token::Literal((s, quoted)) => eval_literal(&s, quoted, loc, scope)?,
+            Token::List(s) => eval_container(&s, loc, scope)?,
+            Token::Keyword(word) => Value::Str(word.to_lowercase()),
+            _ => {
+                return Err(EvalError::new(
+                    loc,
+                    format!("Expecting value for key: {}", key),
+                ))
+            }
+        };
+        map.insert(key, value);
+    }
+    Ok(Value::Map(map))
+}
+
+/// Evaluate a list literal, e.g. [1 "two" $three [4 5]]. The elements are
+/// tokenized like command arguments, and may be lists themselves.
+fn eval_list(tok: &str, loc: Location, scope: &Rc<Scope>) -> EvalResult<Value> {
+    let inner = &tok[1..tok.len() - 1];
+    let mut parser = Parser::new(inner.chars(), scope);
+    let mut items = Vec::new();
+
+    loop {
+        let item = match parser
+            .next_token()
+            .map_err(|e| EvalError::new(loc, e.message))?
+        {
+            Token::End => break,
+            Token::Literal((s, quoted)) => eval_literal(&s, quoted, loc, scope)?,
+            Token::List(s) => eval_container(&s, loc, scope)?,
+            Token::Keyword(word) => Value::Str(word.to_lowercase()),
+            _ => {
+                return Err(EvalError::new(
+                    loc,
+                    format!("Unexpected token in list: {}", tok),
+                ))
+            }
+        };
+        items.push(item);
+    }
+    Ok(Value::List(items))
+}
+
 #[derive(Debug)]
 enum Expression {
     Empty,
@@ -1287,10 +2643,14 @@ enum Expression {
     Bin(RefCell<BinExpr>),
     Cmd(RefCell<Command>),
     Branch(RefCell<BranchExpr>),
+    Def(RefCell<DefExpr>),
     For(RefCell<ForExpr>),
     Group(RefCell<GroupExpr>),
     Leaf(Rc<Literal>), // Values and identifiers
     Loop(RefCell<LoopExpr>),
+    Match(RefCell<MatchExpr>),
+    Source(RefCell<SourceExpr>),
+    Try(RefCell<TryExpr>),
 }
 
 impl Expression {
@@ -1316,8 +2676,9 @@ impl Expression {
         matches!(self, Expression::Bin(_))
     }
 
+    /// Commands, and SOURCE, which parses like one.
     fn is_cmd(&self) -> bool {
-        matches!(self, Expression::Cmd(_))
+        matches!(self, Expression::Cmd(_) | Expression::Source(_))
     }
 
     fn is_for(&self) -> bool {
@@ -1332,8 +2693,12 @@ impl Expression {
         matches!(self, Expression::Group(_))
     }
 
+    fn is_match(&self) -> bool {
+        matches!(self, Expression::Match(_))
+    }
+
     fn is_number(&self) -> bool {
-        if self.is_empty() {
+        if self.is_empty() || self.is_substitution() {
             return false;
         }
         match self.eval() {
@@ -1342,6 +2707,31 @@ impl Expression {
         }
     }
 
+    /// Literal, or operation on literals, e.g. ($size + 4095): evaluating it
+    /// produces no output, so it cannot be the source of a pipe.
+    fn is_arithmetic(&self) -> bool {
+        match self {
+            Expression::Leaf(_) => true,
+            Expression::Bin(b) => {
+                let b = b.borrow();
+                !matches!(b.op, Op::Pipe | Op::Write | Op::Append)
+                    && (b.lhs.is_empty() || b.lhs.is_arithmetic())
+                    && b.rhs.is_arithmetic()
+            }
+            Expression::Group(g) => {
+                let g = g.borrow();
+                !g.content.is_empty() && g.content.iter().all(|e| e.is_arithmetic())
+            }
+            _ => false,
+        }
+    }
+
+    /// Unquoted literal with command substitutions, the output of which is
+    /// split into words when used as command arguments.
+    fn is_substitution(&self) -> bool {
+        matches!(self, Expression::Leaf(lit) if !lit.quoted && lit.tok.contains("$("))
+    }
+
     fn is_pipe(&self) -> bool {
         if let Expression::Bin(b) = self {
             b.borrow().op == Op::Pipe
@@ -1355,6 +2745,8 @@ impl Expression {
             Expression::Args(group) => group.borrow().closed,
             Expression::Bin(bin_expr) => !&bin_expr.borrow().rhs.is_empty(),
             Expression::Cmd(cmd) => !&cmd.borrow().args.is_empty(),
+            Expression::Def(def) => !&def.borrow().body.is_empty(),
+            Expression::Source(source) => !&source.borrow().args.is_empty(),
             Expression::Branch(branch) => {
                 let b = branch.borrow();
                 if b.expect_else && b.else_branch.is_empty() {
@@ -1367,17 +2759,41 @@ impl Expression {
             Expression::Empty => false,
             Expression::Leaf(_) => true,
             Expression::Loop(loop_expr) => !&loop_expr.borrow().body.is_empty(),
+            Expression::Match(match_expr) => !&match_expr.borrow().body.is_empty(),
+            Expression::Try(try_expr) => {
+                let t = try_expr.borrow();
+                !t.body.is_empty() && !t.expect_catch && !t.expect_finally
+            }
         }
     }
 
-    /// Argument evaluation
+    /// Argument evaluation, with lists expanded into their elements
     fn to_values(&self) -> EvalResult<Vec<Value>> {
+        let mut values = Vec::new();
+
+        for value in self.eval_args()? {
+            match value {
+                Value::List(items) => values.extend(items),
+                _ => values.push(value),
+            }
+        }
+        Ok(values)
+    }
+
+    fn eval_args(&self) -> EvalResult<Vec<Value>> {
         match &self {
             Expression::Args(args) => {
                 let mut values = Vec::new();
 
-                for v in args.borrow().iter() {
-                    values.push(Status::check_result(v)?);
+                for expr in &args.borrow().content {
+                    let value = Status::check_result(expr.eval())?;
+
+                    if expr.is_substitution() {
+                        let words = value.to_string();
+                        values.extend(words.split_ascii_whitespace().map(|w| Value::Str(w.into())));
+                    } else {
+                        values.push(value);
+                    }
                 }
                 Ok(values)
             }
@@ -1385,21 +2801,31 @@ impl Expression {
         }
     }
 
-    /// Evaluate and tokenize arguments
-    fn tokenize_args(&self) -> EvalResult<Vec<String>> {
+    /// Evaluate and tokenize arguments; the elements of lists are not tokenized
+    fn tokenize_args(&self) -> EvalResult<Vec<Value>> {
         let mut tokens = Vec::new();
 
-        for val in &self.to_values()? {
-            tokens.extend(val.to_string().split_ascii_whitespace().map(String::from));
+        for val in self.eval_args()? {
+            match val {
+                Value::List(items) => tokens.extend(items),
+                _ => {
+                    for tok in val.to_string().split_ascii_whitespace() {
+                        tokens.push(tok.parse::<Value>()?);
+                    }
+                }
+            }
         }
 
-        // Read from stdin if args consist of a single dash
-        if tokens.len() == 1 && tokens[0] == "-" {
+        // Read from stdin (or pipe) if args consist of a single dash
+        if tokens.len() == 1 && tokens[0] == Value::Str("-".to_string()) {
             let mut buffer = String::new();
-            io::stdin()
+            pipe::stdin()
                 .read_to_string(&mut buffer)
                 .map_err(|e| EvalError::new(self.loc(), e.to_string()))?;
-            tokens = buffer.split_ascii_whitespace().map(String::from).collect();
+            tokens = buffer
+                .split_ascii_whitespace()
+                .map(|tok| tok.parse::<Value>())
+                .collect::<Result<_, _>>()?;
         }
 
         Ok(tokens)
@@ -1411,11 +2837,15 @@ impl Expression {
             Expression::Bin(bin_expr) => bin_expr.borrow().op.priority(),
             Expression::Cmd(_) => Priority::High,
             Expression::Branch(_) => Priority::High,
+            Expression::Def(_) => Priority::High,
+            Expression::Source(_) => Priority::High,
             Expression::Group(_) => Priority::High,
             Expression::For(_) => Priority::High,
             Expression::Empty => Priority::High,
             Expression::Leaf(_) => Priority::High,
             Expression::Loop(_) => Priority::High,
+            Expression::Match(_) => Priority::High,
+            Expression::Try(_) => Priority::High,
         }
     }
 }
@@ -1427,11 +2857,15 @@ impl fmt::Display for Expression {
             Expression::Bin(bin_expr) => write!(f, "{}", bin_expr.borrow()),
             Expression::Cmd(cmd) => write!(f, "{}", cmd.borrow()),
             Expression::Branch(branch) => write!(f, "{}", branch.borrow()),
+            Expression::Def(def) => write!(f, "{}", def.borrow()),
+            Expression::Source(source) => write!(f, "{}", source.borrow()),
             Expression::Group(group) => write!(f, "{}", group.borrow()),
             Expression::For(for_expr) => write!(f, "{}", for_expr.borrow()),
             Expression::Empty => write!(f, ""),
             Expression::Leaf(literal) => write!(f, "{}", literal),
             Expression::Loop(loop_expr) => write!(f, "{}", loop_expr.borrow()),
+            Expression::Match(match_expr) => write!(f, "{}", match_expr.borrow()),
+            Expression::Try(try_expr) => write!(f, "{}", try_expr.borrow()),
         }
     }
 }
@@ -1443,11 +2877,15 @@ impl HasLocation for Expression {
             Expression::Bin(bin_expr) => bin_expr.borrow().loc(),
             Expression::Cmd(cmd) => cmd.borrow().loc(),
             Expression::Branch(branch) => branch.borrow().loc(),
+            Expression::Def(def) => def.borrow().loc(),
+            Expression::Source(source) => source.borrow().loc(),
             Expression::Group(group) => group.borrow().loc(),
             Expression::For(for_expr) => for_expr.borrow().loc(),
             Expression::Empty => panic!("Empty expression"),
             Expression::Leaf(literal) => literal.loc(),
             Expression::Loop(loop_expr) => loop_expr.borrow().loc(),
+            Expression::Match(match_expr) => match_expr.borrow().loc(),
+            Expression::Try(try_expr) => try_expr.borrow().loc(),
         }
     }
 }
@@ -1457,6 +2895,8 @@ struct BinExpr {
     op: Op,
     lhs: Rc<Expression>,
     rhs: Rc<Expression>,
+    decl: Option<Declaration>, // Assignment declares a variable with LET or READONLY
+    background: bool,          // Pipeline followed by &
     loc: Location,
     scope: Rc<Scope>, // Scope needed for assignment op.
 }
@@ -1501,6 +2941,8 @@ macro_rules! div_match {
             }
             Value::Str(s) => Ok(Value::Str(format!("{}/{}", $i, s))),
             Value::Stat(_) => error($self, "Cannot divide by command status"),
+            Value::List(_) => error($self, "Cannot divide by list"),
+            Value::Map(_) => error($self, "Cannot divide by map"),
         }
     };
 }
@@ -1517,32 +2959,6 @@ macro_rules! eval_cmp_fn {
     }
 }
 
-fn get_own_path() -> Result<String, String> {
-    match env::current_exe() {
-        Ok(p) => {
-            #[cfg(test)]
-            {
-                let path_str = p.to_string_lossy();
-                #[cfg(windows)]
-                {
-                    let re = Regex::new(r"\\deps\\.*?(\..*)?$").map_err(|e| e.to_string())?;
-                    Ok(re.replace(&path_str, "\\mysh$1").to_string())
-                }
-                #[cfg(not(windows))]
-                {
-                    let re = Regex::new(r"/deps/.+?(\..*)?$").map_err(|e| e.to_string())?;
-                    Ok(re.replace(&path_str, "/mysh$1").to_string())
-                }
-            }
-            #[cfg(not(test))]
-            {
-                Ok(p.to_string_lossy().to_string())
-            }
-        }
-        Err(e) => Err(format!("Failed to get executable name: {}", e)),
-    }
-}
-
 impl BinExpr {
     fn eval_and(&self) -> EvalResult<Value> {
         let lhs_val = self.lhs.eval()?;
@@ -1578,6 +2994,19 @@ impl BinExpr {
         Ok(Value::Int(any as _))
     }
 
+    /// Is the left hand-side a variable declared str, or being declared str?
+    fn assigns_str(&self) -> bool {
+        let var_type = match (&*self.lhs, self.decl) {
+            (_, Some(decl)) => decl.var_type,
+            (Expression::Leaf(lit), None) => {
+                let name = lit.tok.strip_prefix('$').unwrap_or(&lit.tok);
+                lit.scope.lookup(name).and_then(|var| var.decl.var_type)
+            }
+            _ => None,
+        };
+        var_type == Some(VarType::Str)
+    }
+
     fn eval_assign(&self, rhs_in: Value) -> EvalResult<Value> {
         // Does the right hand-side value wrap a command Status?
         let rhs = if let Value::Stat(status) = &rhs_in {
@@ -1591,22 +3020,86 @@ impl BinExpr {
             let var_name = &lit.tok;
 
             if var_name.starts_with('$') {
+                let (name, indices) = split_indices(&var_name[1..]);
+
                 // Assigning to an already-defined variable, as in: $i = $i + 1?
-                if let Some(var) = lit.scope.lookup(&var_name[1..]) {
-                    var.assign(rhs);
-                    return Ok(var.value());
+                if let Some(var) = lit.scope.lookup(name) {
+                    if indices.is_empty() {
+                        let rhs = var
+                            .check_assign(name, rhs)
+                            .map_err(|e| EvalError::new(self.loc, e))?;
+                        var.assign(rhs);
+                        return Ok(var.value());
+                    }
+                    if var.is_readonly() {
+                        return error(
+                            self,
+                            &format!("Cannot assign to read-only variable: ${}", name),
+                        );
+                    }
+                    // Assigning to an element, as in: $hosts[prod] = example.com
+                    self.assign_element(lit, &var, &indices, Some(rhs.clone()))?;
+                    return Ok(rhs);
                 } else {
-                    return error(self, &format!("Variable not found: {}", var_name));
+                    return error(self, &format!("Variable not found: ${}", name));
+                }
+            } else if let Some(decl) = self.decl {
+                // Declare new variable in the current scope, as in: LET n: int = 0
+                if self.scope.lookup(var_name).is_some_and(|v| v.is_readonly()) {
+                    return error(
+                        self,
+                        &format!("Cannot assign to read-only variable: ${}", var_name),
+                    );
                 }
+                let rhs = match decl.var_type {
+                    Some(var_type) => var_type
+                        .check(var_name, rhs)
+                        .map_err(|e| EvalError::new(self.loc, e))?,
+                    None => rhs,
+                };
+                self.scope.declare(var_name.to_owned(), rhs.clone(), decl);
+                return Ok(rhs);
             } else {
                 // Create new variable in the current scope
-                self.scope.insert(var_name.to_owned(), rhs.clone());
-                return Ok(rhs);
+                return self
+                    .scope
+                    .assign(var_name, rhs)
+                    .map_err(|e| EvalError::new(self.loc, e));
             }
         }
         error(self, "Identifier expected on left hand-side of assignment")
     }
 
+    /// Assign (or erase, if the value is None) an element of a list or map variable.
+    fn assign_element(
+        &self,
+        lit: &Literal,
+        var: &Variable,
+        indices: &[&str],
+        value: Option<Value>,
+    ) -> EvalResult<Value> {
+        let indices = indices
+            .iter()
+            .map(|index| expand_vars(index, lit.loc, &lit.scope))
+            .collect::<EvalResult<Vec<_>>>()?;
+
+        let mut container = var.value();
+        let prev = update_element(&mut container, &indices, value, self.loc)?;
+        var.assign(container);
+
+        Ok(prev)
+    }
+
+    /// Match the left value against the regular expression on the right. Store the
+    /// matched text in $__match, and the capture groups in $__match1, $__match2...
+    fn eval_match(&self, lhs: Value, rhs: Value) -> EvalResult<Value> {
+        if let (Value::Stat(_), _) | (_, Value::Stat(_)) = (&lhs, &rhs) {
+            return self.eval_cmp_status();
+        }
+        let matched = regex_match(&lhs.to_string(), &rhs.to_string(), self.loc, &self.scope)?;
+        Ok(Value::Int(matched as _))
+    }
+
     fn eval_cmp_status(&self) -> EvalResult<Value> {
         let message = if self.op == Op::Gt {
             "Command status does not support '>', did you mean redirect '=>' ?"
@@ -1623,15 +3116,21 @@ impl BinExpr {
                 Value::Real(j) => Ok(Value::Real(i as f64 - j)),
                 Value::Str(_) => error(self, "Cannot compare number to string"),
                 Value::Stat(_) => self.eval_cmp_status(),
+                Value::List(_) => error(self, "Cannot compare number to list"),
+                Value::Map(_) => error(self, "Cannot compare number to map"),
             },
             Value::Real(i) => match rhs {
                 Value::Int(j) => Ok(Value::Real(i - j as f64)),
                 Value::Real(j) => Ok(Value::Real(i - j)),
                 Value::Str(_) => error(self, "Cannot compare number to string"),
                 Value::Stat(_) => self.eval_cmp_status(),
+                Value::List(_) => error(self, "Cannot compare number to list"),
+                Value::Map(_) => error(self, "Cannot compare number to map"),
             },
             Value::Str(s1) => match rhs {
                 Value::Int(_) | Value::Real(_) => error(self, "Cannot compare string to number"),
+                Value::List(_) => error(self, "Cannot compare string to list"),
+                Value::Map(_) => error(self, "Cannot compare string to map"),
                 Value::Str(s2) => {
                     let ord = match s1.cmp(&s2) {
                         Ordering::Equal => 0,
@@ -1643,6 +3142,29 @@ impl BinExpr {
                 Value::Stat(_) => self.eval_cmp_status(),
             },
             Value::Stat(_) => self.eval_cmp_status(),
+            Value::List(items1) => match rhs {
+                Value::List(items2) => {
+                    // Compare lexicographically
+                    let len = (items1.len() as f64) - (items2.len() as f64);
+                    for (a, b) in items1.into_iter().zip(items2) {
+                        match self.eval_cmp(a, b)? {
+                            Value::Real(r) if r != 0.0 => return Ok(Value::Real(r)),
+                            _ => {}
+                        }
+                    }
+                    Ok(Value::Real(len))
+                }
+                Value::Stat(_) => self.eval_cmp_status(),
+                Value::Map(_) => error(self, "Cannot compare list to map"),
+                _ => error(self, "Cannot compare list to scalar value"),
+            },
+            Value::Map(map1) => match rhs {
+                // Maps are not ordered: NaN compares as neither less, greater, nor equal
+                Value::Map(map2) => Ok(Value::Real(if map1 == map2 { 0.0 } else { f64::NAN })),
+                Value::Stat(_) => self.eval_cmp_status(),
+                Value::List(_) => error(self, "Cannot compare map to list"),
+                _ => error(self, "Cannot compare map to scalar value"),
+            },
         }
     }
 
@@ -1661,16 +3183,46 @@ impl BinExpr {
                 Value::Int(_) | Value::Real(_) => Ok(Value::Str(format!("{}/{}", s1, rhs))),
                 Value::Str(s2) => Ok(Value::Str(format!("{}/{}", s1, s2))),
                 Value::Stat(_) => error(self, "Cannot divide by command status"),
+                Value::List(_) => error(self, "Cannot divide by list"),
+                Value::Map(_) => error(self, "Cannot divide by map"),
             },
             Value::Stat(_) => error(self, "Cannot divide command status"),
+            Value::List(_) => error(self, "Cannot divide list"),
+            Value::Map(_) => error(self, "Cannot divide map"),
+        }
+    }
+
+    /// Floor division: the quotient is rounded towards negative infinity
+    fn eval_int_div(&self, lhs: Value, rhs: Value) -> EvalResult<Value> {
+        match (lhs, rhs) {
+            (Value::Int(_), Value::Int(0)) => error(self, "Division by zero"),
+            (Value::Int(i), Value::Int(j)) => match i.checked_div(j) {
+                Some(q) if i % j != 0 && (i < 0) != (j < 0) => Ok(Value::Int(q - 1)),
+                Some(q) => Ok(Value::Int(q)),
+                None => error(self, "Integer overflow"),
+            },
+            (lhs, rhs) => {
+                let (i, j) = self.real_operands(lhs, rhs)?;
+                if j == 0.0 {
+                    error(self, "Division by zero")
+                } else {
+                    Ok(Value::Real((i / j).floor()))
+                }
+            }
         }
     }
 
-    fn eval_int_div(&self, _lhs: Value, _rhs: Value) -> EvalResult<Value> {
-        Err(EvalError::new(
-            self.loc,
-            "Integer division not implemented".to_string(),
-        ))
+    /// Convert the operands of integer division and modulo to reals
+    fn real_operands(&self, lhs: Value, rhs: Value) -> EvalResult<(f64, f64)> {
+        let as_real = |val: &Value| match val {
+            Value::Int(i) => Some(*i as f64),
+            Value::Real(r) => Some(*r),
+            _ => None,
+        };
+        match (as_real(&lhs), as_real(&rhs)) {
+            (Some(i), Some(j)) => Ok((i, j)),
+            _ => error(self, "Cannot divide non-numeric values"),
+        }
     }
 
     fn eval_minus(&self, lhs: Value, rhs: Value) -> EvalResult<Value> {
@@ -1680,225 +3232,438 @@ impl BinExpr {
                 Value::Real(j) => Ok(Value::Real(i as f64 - j)),
                 Value::Str(_) => error(self, "Cannot subtract string from number"),
                 Value::Stat(_) => error(self, "Cannot subtract command status from number"),
+                Value::List(_) => error(self, "Cannot subtract list from number"),
+                Value::Map(_) => error(self, "Cannot subtract map from number"),
             },
             Value::Real(i) => match rhs {
                 Value::Int(j) => Ok(Value::Real(i - j as f64)),
                 Value::Real(j) => Ok(Value::Real(i - j)),
                 Value::Str(_) => error(self, "Cannot subtract string from number"),
                 Value::Stat(_) => error(self, "Cannot subtract command status from number"),
+                Value::List(_) => error(self, "Cannot subtract list from number"),
+                Value::Map(_) => error(self, "Cannot subtract map from number"),
             },
             Value::Str(_) => match rhs {
                 Value::Int(_) | Value::Real(_) => error(self, "Cannot subtract number from string"),
                 Value::Str(_) => error(self, "Cannot subtract strings"),
                 Value::Stat(_) => error(self, "Cannot subtract command status from string"),
+                Value::List(_) => error(self, "Cannot subtract list from string"),
+                Value::Map(_) => error(self, "Cannot subtract map from string"),
             },
             Value::Stat(_) => error(self, "Cannot subtract command statuses"),
+            Value::List(_) => error(self, "Cannot subtract from list"),
+            Value::Map(_) => error(self, "Cannot subtract from map"),
         }
     }
 
-    fn eval_mod(&self, _lhs: Value, _rhs: Value) -> EvalResult<Value> {
-        Err(EvalError::new(
-            self.loc,
-            "Modulo operation not implemented".to_string(),
-        ))
-    }
-
-    fn eval_mul(&self, lhs: Value, rhs: Value) -> EvalResult<Value> {
+    /// Modulo, consistent with floor division: the result has the sign of the divisor
+    fn eval_mod(&self, lhs: Value, rhs: Value) -> EvalResult<Value> {
+        match (lhs, rhs) {
+            (Value::Int(_), Value::Int(0)) => error(self, "Division by zero"),
+            (Value::Int(i), Value::Int(j)) => match i.checked_rem(j) {
+                Some(r) if r != 0 && (r < 0) != (j < 0) => Ok(Value::Int(r + j)),
+                Some(r) => Ok(Value::Int(r)),
+                None => error(self, "Integer overflow"),
+            },
+            (lhs, rhs) => {
+                let (i, j) = self.real_operands(lhs, rhs)?;
+                if j == 0.0 {
+                    error(self, "Division by zero")
+                } else {
+                    Ok(Value::Real(i - j * (i / j).floor()))
+                }
+            }
+        }
+    }
+
+    /// Bitwise AND, OR, XOR and shifts, defined for integers only
+    fn eval_bitwise(&self, lhs: Value, rhs: Value) -> EvalResult<Value> {
+        let (i, j) = match (lhs, rhs) {
+            (Value::Int(i), Value::Int(j)) => (i, j),
+            _ => return error(self, &format!("Operator {} expects integers", self.op)),
+        };
+        let shift = |f: fn(i64, u32) -> Option<i64>| {
+            u32::try_from(j)
+                .ok()
+                .and_then(|j| f(i, j))
+                .ok_or_else(|| EvalError::new(self.loc, format!("Invalid shift: {}", j)))
+        };
+        match self.op {
+            Op::BitAnd => Ok(Value::Int(i & j)),
+            Op::BitOr => Ok(Value::Int(i | j)),
+            Op::BitXor => Ok(Value::Int(i ^ j)),
+            Op::Shl => shift(i64::checked_shl).map(Value::Int),
+            Op::Shr => shift(i64::checked_shr).map(Value::Int),
+            _ => panic!("Unexpected bitwise operator"),
+        }
+    }
+
+    fn eval_mul(&self, lhs: Value, rhs: Value) -> EvalResult<Value> {
         match lhs {
             Value::Int(i) => match rhs {
                 Value::Int(j) => Ok(Value::Int(i * j)),
                 Value::Real(j) => Ok(Value::Real(i as f64 * j)),
                 Value::Str(_) => error(self, "Cannot multiply number by string"),
                 Value::Stat(_) => error(self, "Cannot multiply number by command status"),
+                Value::List(_) => error(self, "Cannot multiply number by list"),
+                Value::Map(_) => error(self, "Cannot multiply number by map"),
             },
             Value::Real(i) => match rhs {
                 Value::Int(j) => Ok(Value::Real(i * j as f64)),
                 Value::Real(j) => Ok(Value::Real(i * j)),
                 Value::Str(_) => error(self, "Cannot multiply number by string"),
                 Value::Stat(_) => error(self, "Cannot multiply number by command status"),
+                Value::List(_) => error(self, "Cannot multiply number by list"),
+                Value::Map(_) => error(self, "Cannot multiply number by map"),
             },
             Value::Str(_) => match rhs {
                 Value::Int(_) | Value::Real(_) => error(self, "Cannot multiply string by number"),
                 Value::Str(_) => error(self, "Cannot multiply strings"),
                 Value::Stat(_) => error(self, "Cannot multiply string by command status"),
+                Value::List(_) => error(self, "Cannot multiply string by list"),
+                Value::Map(_) => error(self, "Cannot multiply string by map"),
             },
             Value::Stat(_) => error(self, "Cannot multiply command statuses"),
+            Value::List(_) => error(self, "Cannot multiply list"),
+            Value::Map(_) => error(self, "Cannot multiply map"),
         }
     }
 
-    /// Evaluate expr and redirect output into a String
-    fn eval_redirect(&self, expr: &Rc<Expression>) -> EvalResult<String> {
-        let mut redirect =
-            BufferRedirect::stdout().map_err(|e| EvalError::new(self.loc, e.to_string()))?;
-
-        Status::check_result(expr.eval())?;
-
-        let mut str_buf = String::new();
-        redirect
-            .read_to_string(&mut str_buf)
-            .map_err(|e| EvalError::new(self.loc, e.to_string()))?;
-
-        Ok(str_buf.to_string())
+    /// Collect the stages of a pipeline, which may be parsed as either
+    /// (a | b) | c or a | (b | c), depending on the expressions involved.
+    fn pipeline_stages(expr: &Rc<Expression>, stages: &mut Vec<Rc<Expression>>) {
+        if let Expression::Bin(b) = &**expr {
+            let b = b.borrow();
+            if b.op == Op::Pipe {
+                Self::pipeline_stages(&b.lhs, stages);
+                Self::pipeline_stages(&b.rhs, stages);
+                return;
+            }
+        }
+        stages.push(Rc::clone(expr));
     }
 
-    fn eval_exit_code(&self, cmd: String, status: &std::process::ExitStatus) -> EvalResult<Value> {
-        let exit_code = status.code().unwrap_or_else(|| -1);
-        my_dbg!(exit_code);
+    fn eval_pipe(&self) -> EvalResult<Value> {
+        let mut stages = Vec::new();
+        Self::pipeline_stages(&self.lhs, &mut stages);
+        Self::pipeline_stages(&self.rhs, &mut stages);
 
-        let result = if exit_code == 0 {
-            Ok(Value::success())
-        } else {
-            Err(EvalError::new(
-                self.loc(),
-                format!("{}: exited with code {}", cmd, exit_code),
-            ))
-        };
+        if stages[..stages.len() - 1].iter().any(|s| s.is_empty()) {
+            return error(self, "Expecting pipe input");
+        }
+        if stages.last().unwrap().is_empty() {
+            return error(self, "Expecting right hand-side operand");
+        }
+        if self.background {
+            return self.eval_background(&stages);
+        }
+        let cmd = self.to_string();
 
-        Ok(Value::Stat(Status::new(cmd, &result, &self.scope)))
+        // Piping into a literal? assign standard output capture to string variable.
+        if let Expression::Leaf(lit) = &*Rc::clone(stages.last().unwrap()) {
+            stages.pop();
+            let (result, output) =
+                pipe::capture(|| jobs::launch(cmd, false, || self.eval_pipeline(&stages)));
+            Status::check_result(result)?;
+
+            let output = String::from_utf8(output).map_err(|e| {
+                EvalError::new(
+                    self.loc,
+                    format!("Failed to convert pipe output from UTF8: {}", e),
+                )
+            })?;
+            return self
+                .scope
+                .parse_for_var(&lit.tok, output.trim())
+                .and_then(|value| {
+                    trace(&self.scope, lit.loc, || {
+                        format!("{} = {}", lit.tok, trace_value(&value))
+                    });
+                    self.scope.assign(&lit.tok, value)
+                })
+                .map_err(|e| EvalError::new(self.loc, e));
+        }
+
+        jobs::launch(cmd, false, || self.eval_pipeline(&stages))
     }
 
-    fn eval_pipe_to_var(
-        &self,
-        lhs: &Rc<Expression>,
-        rhs: &Rc<Expression>,
-    ) -> EvalResult<Option<Value>> {
-        // Piping into a literal? assign standard output capture to string variable.
-        if let Expression::Leaf(lit) = &**rhs {
-            // Special case: is the left hand-side expression a pipeline?
-            let output = if lhs.is_pipe() {
-                let program = get_own_path().map_err(|e| EvalError::new(self.loc, e))?;
-
-                // Get the left hand-side expression as a string
-                let lhs_str = lhs.to_string();
-
-                // Start an instance of the interpreter to evaluate the left hand-side of the pipe
-                // println!("Executing pipe LHS: {} -c {}", &program, &lhs_str);
-
-                let mut child = StdCommand::new(&program)
-                    .arg("-c")
-                    .arg(&lhs_str)
-                    .stdout(Stdio::piped())
-                    .spawn()
-                    .map_err(|e| {
-                        EvalError::new(rhs.loc(), format!("Failed to spawn child process: {}", e))
-                    })?;
+    /// Start a pipeline in the background, as a job. Pipelines of external programs
+    /// are spawned directly; others are evaluated in a new shell process.
+    fn eval_background(&self, stages: &[Rc<Expression>]) -> EvalResult<Value> {
+        let mut commands = Vec::new();
+        for stage in stages {
+            match &**stage {
+                Expression::Cmd(cmd) if cmd.borrow().cmd.is_external() => commands.push(cmd),
+                _ => break,
+            }
+        }
 
-                let mut buffer = Vec::new();
-                if let Some(mut stdout) = child.stdout.take() {
-                    stdout.read_to_end(&mut buffer).map_err(|e| {
-                        EvalError::new(rhs.loc(), format!("Failed to read output: {}", e))
-                    })?;
+        let cmd = self.to_string();
+        let result = jobs::launch(cmd.clone(), true, || {
+            if commands.len() < stages.len() {
+                let mut streams = Streams {
+                    stdin: Input::empty(),
+                    stdout: Output::Inherit,
+                    stderr: Output::Stderr,
+                };
+                let script = join_expr(stages, " | ");
+                return eval_detached(self.loc, &script, &[], &self.scope, &mut streams);
+            }
+            let mut input = Input::empty();
+            let mut children = Vec::new();
+            let mut result = Ok(Value::success());
+
+            for (i, command) in commands.iter().enumerate() {
+                let stdin = std::mem::replace(&mut input, Input::empty());
+                let stdout = if i + 1 == commands.len() {
+                    None
+                } else {
+                    match os_pipe::pipe() {
+                        Ok((reader, writer)) => {
+                            input = Input::Pipe(reader);
+                            Some(Output::Pipe(writer))
+                        }
+                        Err(e) => {
+                            result = error(self, &format!("Failed to create pipe: {}", e));
+                            break;
+                        }
+                    }
+                };
+                // Dropping the streams closes the writing end of the pipe in this process.
+                match command.borrow().spawn(stdin, stdout) {
+                    Ok((child, _)) => children.push(child),
+                    Err(e) => {
+                        result = Err(e);
+                        break;
+                    }
                 }
+            }
+            // Keep track of the programs already started, even if others failed.
+            if !children.is_empty() {
+                jobs::background(children);
+            }
+            result
+        });
 
-                // Wait for the child process to complete
-                let exit_status = child.wait().map_err(|e| {
-                    EvalError::new(
-                        rhs.loc(),
-                        format!("Failed to wait for child process output: {}", e),
-                    )
-                })?;
-
-                self.eval_exit_code(lhs_str, &exit_status)?;
+        Ok(Value::Stat(Status::new(cmd, &result, &self.scope)))
+    }
 
-                String::from_utf8(buffer).map_err(|e| {
-                    EvalError::new(
-                        rhs.loc(),
-                        format!("Failed to convert pipe output from UTF8: {}", e),
-                    )
-                })?
+    /// Run the stages of a pipeline concurrently, connected via OS pipes. External programs
+    /// are spawned as child processes, and builtin commands run in threads of their own.
+    /// Other stages are evaluated in this process, one after the other: the input of all
+    /// but the first of them is collected in memory, while the stages before them run.
+    fn eval_pipeline(&self, stages: &[Rc<Expression>]) -> EvalResult<Value> {
+        let mut results: Vec<EvalResult<Value>> =
+            stages.iter().map(|_| Ok(Value::success())).collect();
+        let mut codes = vec![0; stages.len()];
+        let mut upstream = None;
+        let mut children = Vec::new();
+        let mut threads = Vec::new();
+        let mut evals = Vec::new();
+        let mut failed = None;
+
+        for (i, stage) in stages.iter().enumerate() {
+            let last = i + 1 == stages.len();
+            let stdin = upstream.take().map_or(Input::Inherit, Input::Pipe);
+            let stdout = if last {
+                None
             } else {
-                // Base use case, left hand-side is not a pipe expression
-                self.eval_redirect(lhs)?
+                match os_pipe::pipe() {
+                    Ok((reader, writer)) => {
+                        upstream = Some(reader);
+                        Some(Output::Pipe(writer))
+                    }
+                    Err(e) => {
+                        failed = Some(format!("Failed to create pipe: {}", e));
+                        break;
+                    }
+                }
             };
-            let value = Value::from_str(output.trim())?;
-            self.scope.insert(lit.tok.clone(), value.clone());
 
-            return Ok(Some(value));
+            match &**stage {
+                Expression::Cmd(cmd) if cmd.borrow().cmd.is_external() => {
+                    match cmd.borrow().spawn(stdin, stdout) {
+                        Ok((mut child, mut streams)) => {
+                            let mut output = None;
+                            if !last {
+                                // Close the writing end of the pipe, the child has its own copy.
+                                streams.stdout = Output::Null;
+                            } else if !evals.is_empty() {
+                                // Collect the output piped into memory (if any) while this
+                                // process evaluates the stages before, so that the program
+                                // is not blocked writing.
+                                output = child.stdout.take().map(read_in_background);
+                            }
+                            children.push((i, child, streams, output));
+                        }
+                        Err(e) => results[i] = Err(e),
+                    }
+                }
+                // The last stage runs in this process, unless a stage before it does.
+                Expression::Cmd(cmd)
+                    if (!last || !evals.is_empty())
+                        && cmd.borrow().cmd.is_thread_safe()
+                        && cmd.borrow().scope.can_copy_vars() =>
+                {
+                    match cmd.borrow().start(stdin, stdout) {
+                        Ok(stage) => threads.push((i, stage)),
+                        Err(e) => results[i] = Err(e),
+                    }
+                }
+                _ => {
+                    let (stdin, input) = match stdin {
+                        Input::Pipe(reader) if !evals.is_empty() => {
+                            (Input::empty(), Some(read_in_background(reader)))
+                        }
+                        stdin => (stdin, None),
+                    };
+                    evals.push((i, stdin, input, stdout));
+                }
+            }
         }
-        Ok(None)
-    }
 
-    fn eval_pipe(&self, lhs: &Rc<Expression>, rhs: &Rc<Expression>) -> EvalResult<Value> {
-        if lhs.is_empty() {
-            return error(self, "Expecting pipe input");
+        // Do not run the rest of a pipeline that could not be set up, but still wait for
+        // the stages already started: dropping their pipes makes them complete.
+        if failed.is_some() {
+            evals.clear();
         }
-
-        if let Some(val) = self.eval_pipe_to_var(lhs, rhs)? {
-            return Ok(val);
+        for (i, mut stdin, input, stdout) in evals {
+            if let Some(input) = input {
+                stdin = Input::Buffer(io::Cursor::new(input.join().unwrap_or_default()));
+            }
+            let eval = || match stdin {
+                Input::Inherit => Status::check_result(stages[i].eval()),
+                stdin => pipe::with_input(stdin, || Status::check_result(stages[i].eval())),
+            };
+            // Dropping the output when done closes the writing end of the pipe.
+            results[i] = match stdout {
+                Some(stdout) => pipe::redirect(stdout, eval).0,
+                None => eval(),
+            };
         }
 
-        // Create a pipe
-        let (reader, writer) = match os_pipe::pipe() {
-            Ok((r, w)) => (r, w),
-            Err(e) => return error(self, &format!("Failed to create pipe: {}", e)),
-        };
-
-        // Redirect stdout to the pipe
-        let redirect = match Redirect::stdout(writer) {
-            Ok(r) => r,
-            Err(e) => return error(self, &format!("Failed to redirect stdout: {}", e)),
-        };
-
-        // Get our own program name
-        let program = get_own_path().map_err(|e| EvalError::new(self.loc, e))?;
-
-        // Get the right-hand side expression as a string
-        let rhs_str = rhs.to_string();
-
-        // Start a copy of the running program with the arguments "-c" rhs_str
-        // to evaluate the right hand-side of the pipe expression
-
-        // println!("Executing pipe RHS: {} -c {}", &program, &rhs_str);
-
-        let child = StdCommand::new(&program)
-            .arg("-c")
-            .arg(&rhs_str)
-            .stdin(Stdio::from(reader))
-            .stdout(Stdio::piped())
-            .spawn()
-            .map_err(|e| {
-                EvalError::new(rhs.loc(), format!("Failed to spawn child process: {}", e))
-            })?;
-
-        // Left-side evaluation's stdout goes into the pipe.
-        let lhs_result = Status::check_result(lhs.eval());
+        for (i, (thread, output)) in threads {
+            let mut result = thread
+                .join()
+                .unwrap_or_else(|_| Err("Command panicked".to_string()));
+            if let Some(output) = output {
+                let output = output.join().unwrap_or_default();
+                result =
+                    result.and_then(|_| pipe::write_output(&output).map_err(|e| e.to_string()));
+            }
+            if let Err(e) = result {
+                results[i] = Err(EvalError::new(stages[i].loc(), e));
+            }
+        }
 
-        // Drop the redirect to close the write end of the pipe
-        drop(redirect);
+        // Wait for the external programs, downstream first: upstream programs
+        // may be blocked writing into a pipe until the downstream ones complete.
+        for (i, child, mut streams, output) in children.into_iter().rev() {
+            let status = wait_child(child, &mut streams).and_then(|status| {
+                if let Some(output) = output {
+                    let output = output.join().unwrap_or_default();
+                    streams
+                        .stdout
+                        .write_all(&output)
+                        .map_err(|e| e.to_string())?;
+                }
+                Ok(status)
+            });
+            results[i] = status
+                .and_then(|status| {
+                    codes[i] = status.code().unwrap_or(-1);
+                    check_exit_status(status).map(|_| Value::success())
+                })
+                .map_err(|e| EvalError::new(stages[i].loc(), e));
+        }
 
-        // Wait for the child process to complete and get its output
-        let output = child.wait_with_output().map_err(|e| {
-            EvalError::new(
-                rhs.loc(),
-                format!("Failed to get child process output: {}", e),
-            )
-        })?;
+        if let Some(message) = failed {
+            return error(self, &message);
+        }
 
-        lhs_result?; // Check for any left hand-side errors
+        for (code, result) in codes.iter_mut().zip(&results) {
+            if let (0, Err(e)) = (*code, result) {
+                *code = exit_code(&e.message);
+            }
+        }
+        let pipestatus = codes.iter().map(|c| c.to_string()).collect::<Vec<_>>();
+        let pipestatus = pipestatus.join(" ");
+        self.scope
+            .insert("__pipestatus".to_string(), Value::Str(pipestatus.clone()));
+
+        // The status of a pipeline is the status of the last stage; with PIPEFAIL
+        // the status of the last stage that failed, if any.
+        let mut index = stages.len() - 1;
+        if is_enabled(&self.scope, "PIPEFAIL") {
+            index = results.iter().rposition(|r| r.is_err()).unwrap_or(index);
+        }
 
-        // Print the output of the right hand-side expression.
-        print!("{}", String::from_utf8_lossy(&output.stdout));
+        // Do not lose the errors of the other stages.
+        for (i, result) in results.iter().enumerate() {
+            if let (true, Err(e)) = (i != index, result) {
+                collect_var(
+                    &self.scope,
+                    "__errors",
+                    format!("{}: {}", stages[i], e.message),
+                );
+            }
+        }
 
-        self.eval_exit_code(rhs_str, &output.status)
+        let result = results.swap_remove(index);
+        let cmd = stages[index].to_string();
+        let status = Status::new(cmd, &result, &self.scope);
+        status.borrow_mut().pipestatus = Some(pipestatus);
+        Ok(Value::Stat(status))
     }
 
-    /// Binary plus
+    /// Binary plus; concatenates lists, appends or prepends values to lists, merges maps.
     fn eval_plus(&self, lhs: Value, rhs: Value) -> EvalResult<Value> {
+        if let Value::List(mut items) = rhs {
+            return match lhs {
+                Value::List(mut lhs_items) => {
+                    lhs_items.append(&mut items);
+                    Ok(Value::List(lhs_items))
+                }
+                Value::Stat(_) => error(self, "Cannot add command status and list"),
+                _ => {
+                    items.insert(0, lhs);
+                    Ok(Value::List(items))
+                }
+            };
+        }
         match lhs {
             Value::Int(i) => match rhs {
                 Value::Int(j) => Ok(Value::Int(i + j)),
                 Value::Real(j) => Ok(Value::Real(i as f64 + j)),
                 Value::Str(ref s) => Ok(Value::Str(format!("{}{}", i, s))),
                 Value::Stat(_) => error(self, "Cannot add number and command status"),
+                Value::List(_) => unreachable!(),
+                Value::Map(_) => error(self, "Cannot add number and map"),
             },
             Value::Real(i) => match rhs {
                 Value::Int(j) => Ok(Value::Real(i + j as f64)),
                 Value::Real(j) => Ok(Value::Real(i + j)),
                 Value::Str(ref s) => Ok(Value::Str(format!("{}{}", i, s))),
                 Value::Stat(_) => error(self, "Cannot add number and command status"),
+                Value::List(_) => unreachable!(),
+                Value::Map(_) => error(self, "Cannot add number and map"),
             },
             Value::Str(s) => Ok(Value::Str(format!("{}{}", s, rhs))),
             Value::Stat(_) => error(self, "Cannot add command statuses"),
+            Value::List(mut items) => match rhs {
+                Value::Stat(_) => error(self, "Cannot add list and command status"),
+                _ => {
+                    items.push(rhs);
+                    Ok(Value::List(items))
+                }
+            },
+            Value::Map(mut map) => match rhs {
+                Value::Map(other) => {
+                    map.extend(other);
+                    Ok(Value::Map(map))
+                }
+                _ => error(self, "Cannot add map and scalar value"),
+            },
         }
     }
 
@@ -1908,11 +3673,20 @@ impl BinExpr {
             let var_name = &lit.tok;
 
             if var_name.starts_with('$') {
-                if let Some(var) = lit.scope.erase(&var_name[1..]) {
+                let (name, indices) = split_indices(&var_name[1..]);
+
+                if lit.scope.lookup(name).is_some_and(|v| v.is_readonly()) {
+                    return error(self, &format!("Cannot erase read-only variable: ${}", name));
+                }
+                if !indices.is_empty() {
+                    if let Some(var) = lit.scope.lookup(name) {
+                        // Return the erased element
+                        return self.assign_element(lit, &var, &indices, None);
+                    }
+                } else if let Some(var) = lit.scope.erase(name) {
                     return Ok(var.value()); // Return the erased value
-                } else {
-                    return error(self, &format!("Variable not found: {}", var_name));
                 }
+                return error(self, &format!("Variable not found: ${}", name));
             }
         }
         error(self, "Variable expected on left hand-side of assignment")
@@ -1943,21 +3717,31 @@ impl BinExpr {
             .open(&filename)
             .map_err(|e| EvalError::new(self.loc, e.to_string()))?;
 
-        // Redirect stdout to the file
-        let _redirect = Redirect::stdout(file)
-            .map_err(|e| EvalError::new(self.loc, format!("Failed to redirect stdout: {}", e)))?;
-
-        // Evaluate left hand-side expression
-        self.lhs.eval()
+        // Evaluate left hand-side expression, with the output going to the file
+        let (result, _) = pipe::redirect(Output::File(file), || self.lhs.eval());
+        result
     }
 }
 
 macro_rules! eval_bin {
     ($self:expr, $f:ident) => {
-        $self.$f($self.lhs.eval()?, $self.rhs.eval()?)
+        $self.$f(eval_operand(&$self.lhs)?, eval_operand(&$self.rhs)?)
     };
 }
 
+/// Evaluate an operand of an arithmetic, bitwise, comparison or assignment operator.
+/// Unquoted literals may be integers with a radix prefix here, e.g. 0xff & $mode
+fn eval_operand(expr: &Rc<Expression>) -> EvalResult<Value> {
+    if let Expression::Leaf(lit) = &**expr {
+        if !lit.quoted && !lit.list {
+            if let Some(i) = parse_radix(&lit.tok) {
+                return Ok(Value::Int(i));
+            }
+        }
+    }
+    expr.eval()
+}
+
 impl Eval for BinExpr {
     fn eval(&self) -> EvalResult<Value> {
         if self.rhs.is_empty() {
@@ -1967,7 +3751,7 @@ impl Eval for BinExpr {
             error(self, "Expecting right hand-side operand")
         } else if self.lhs.is_empty() {
             if self.op.is_unary_ok() {
-                eval_unary(self, &self.op, self.rhs.eval()?, &self.scope)
+                eval_unary(self, &self.op, eval_operand(&self.rhs)?, &self.scope)
             } else {
                 error(self, "Expecting left hand-side operand")
             }
@@ -1975,7 +3759,20 @@ impl Eval for BinExpr {
             match self.op {
                 Op::And => self.eval_and(),
                 Op::Append => self.eval_write(true),
-                Op::Assign => self.eval_assign(self.rhs.eval()?.clone()),
+                Op::Assign => {
+                    let rhs = match &*self.rhs {
+                        // Keep the text of quoted literals assigned to str variables, e.g. "007"
+                        Expression::Leaf(lit) if lit.quoted && self.assigns_str() => {
+                            Value::Str(expand_value(&lit.tok, lit.loc, &lit.scope)?)
+                        }
+                        _ => eval_operand(&self.rhs)?,
+                    };
+                    trace(&self.scope, self.lhs.loc(), || {
+                        format!("{} = {}", self.lhs, trace_value(&rhs))
+                    });
+                    self.eval_assign(rhs)
+                }
+                Op::BitAnd | Op::BitOr | Op::BitXor => eval_bin!(self, eval_bitwise),
                 Op::Div => eval_bin!(self, eval_div),
                 Op::Gt => eval_bin!(self, eval_gt),
                 Op::Gte => eval_bin!(self, eval_gte),
@@ -1983,14 +3780,16 @@ impl Eval for BinExpr {
                 Op::Equals => eval_bin!(self, eval_equals),
                 Op::Lt => eval_bin!(self, eval_lt),
                 Op::Lte => eval_bin!(self, eval_lte),
+                Op::Match => eval_bin!(self, eval_match),
                 Op::Minus => eval_bin!(self, eval_minus),
                 Op::Mod => eval_bin!(self, eval_mod),
                 Op::Mul => eval_bin!(self, eval_mul),
                 Op::Not => error(self, "Unexpected logical negation operator"),
                 Op::NotEquals => eval_bin!(self, eval_not_equals),
                 Op::Or => self.eval_or(),
-                Op::Pipe => self.eval_pipe(&self.lhs, &self.rhs),
+                Op::Pipe => self.eval_pipe(),
                 Op::Plus => eval_bin!(self, eval_plus),
+                Op::Shl | Op::Shr => eval_bin!(self, eval_bitwise),
                 Op::Write => self.eval_write(false),
             }
         }
@@ -2033,18 +3832,19 @@ impl GroupExpr {
             closed: false,
         }
     }
-
-    fn iter(&self) -> impl Iterator<Item = EvalResult<Value>> + '_ {
-        self.content.iter().map(|expr| expr.eval())
-    }
 }
 
 derive_has_location!(GroupExpr);
 
-impl Eval for GroupExpr {
-    fn eval(&self) -> EvalResult<Value> {
+impl GroupExpr {
+    /// Evaluate with a variable defined in the scope of the group, e.g. the error in CATCH.
+    fn eval_with_var(&self, var_name: &str, val: Value) -> EvalResult<Value> {
         self.scope.clear();
+        self.scope.insert(var_name.to_string(), val);
+        self.eval_content()
+    }
 
+    fn eval_content(&self) -> EvalResult<Value> {
         let mut result = Ok(Value::success());
 
         for e in &self.content {
@@ -2059,6 +3859,7 @@ impl Eval for GroupExpr {
                         result = Err(EvalError {
                             loc: e.loc(),
                             message: "BREAK outside loop".to_string(),
+                            cmd: None,
                             jump: Some(Jump::Break(result.unwrap())),
                         });
                         break;
@@ -2066,6 +3867,7 @@ impl Eval for GroupExpr {
                         result = Err(EvalError {
                             loc: e.loc(),
                             message: "CONTINUE outside loop".to_string(),
+                            cmd: None,
                             jump: Some(Jump::Continue(result.unwrap())),
                         });
                         break;
@@ -2079,6 +3881,13 @@ impl Eval for GroupExpr {
     }
 }
 
+impl Eval for GroupExpr {
+    fn eval(&self) -> EvalResult<Value> {
+        self.scope.clear();
+        self.eval_content()
+    }
+}
+
 impl ExprNode for GroupExpr {
     fn add_child(&mut self, child: &Rc<Expression>) -> EvalResult {
         self.content.push(Rc::clone(child));
@@ -2108,116 +3917,240 @@ impl fmt::Display for GroupExpr {
 struct Command {
     cmd: ShellCommand,
     args: Rc<Expression>,
+    env: Vec<(String, Rc<Expression>)>, // Variables exported to this command only
+    background: bool,                   // Command followed by &
     loc: Location,
     scope: Rc<Scope>,
 }
 
 derive_has_location!(Command);
 
-macro_rules! define_redir_enum {
-    ($name:ident, $other_stream:ident) => {
-        enum $name {
-            #[allow(dead_code)]
-            File(Redirect<File>),
-            #[allow(dead_code)]
-            $other_stream(Redirect<io::$other_stream>),
-            #[allow(dead_code)]
-            Null(Gag),
-            None,
+/// Evaluate the script in a new shell process, as the background job being launched,
+/// with args as its positional arguments ($1, $2...). The process gets the exported
+/// variables, and the functions defined in this one.
+fn eval_detached(
+    loc: Location,
+    script: &str,
+    args: &[String],
+    scope: &Rc<Scope>,
+    streams: &mut Streams,
+) -> EvalResult<Value> {
+    let shell = shell_program()
+        .map_err(|e| EvalError::new(loc, format!("Failed to start background job: {}", e)))?;
+
+    let mut definitions = String::new();
+    for name in list_registered_commands(true) {
+        if let Some(def) = get_command(&name).and_then(|cmd| cmd.definition()) {
+            definitions.push_str(&def);
+            definitions.push_str("; ");
         }
-    };
+    }
+    let mut shell_args = vec![
+        "--norc".to_string(),
+        "-c".to_string(),
+        format!("{}{}", definitions, script),
+    ];
+    shell_args.extend_from_slice(args);
+
+    // Background jobs do not read from the standard input
+    streams.stdin = Input::empty();
+    let child = shell
+        .spawn(&shell_args, scope, streams)
+        .map_err(|e| EvalError::new(loc, e))?;
+    jobs::background(vec![child]);
+    Ok(Value::success())
 }
 
-macro_rules! define_redir_impl {
-    ($name:ident, $self_stream:ident, $other_stream:ident, $other:ident, $lookup_key:expr, $other_key:expr) => {
-        impl $name {
-            fn with_scope(scope: &Rc<Scope>) -> Result<Self, String> {
-                if let Some(v) = scope.lookup_value($lookup_key) {
-                    let path = v.to_string();
-                    if path == $other_key || path == concat!("__", $other_key) {
-                        return Ok($name::$other_stream(
-                            Redirect::$self_stream(io::$other()).map_err(|e| {
-                                format!(
-                                    "Failed to redirect {} to {}: {}",
-                                    $lookup_key, $other_key, e
-                                )
-                            })?,
-                        ));
-                    }
-                    if path.to_ascii_lowercase() == "null" {
-                        return Ok($name::Null(Gag::$self_stream().map_err(|e| e.to_string())?));
-                    }
-                    let file = OpenOptions::new()
-                        .truncate(true)
-                        .read(true)
-                        .create(true)
-                        .write(true)
-                        .open(&path)
-                        .map_err(|e| {
-                            format!(
-                                "Failed to open file for {} redirection '{}': {}",
-                                $lookup_key, path, e
-                            )
-                        })?;
-
-                    return Ok($name::File(Redirect::$self_stream(file).map_err(|e| {
-                        format!(
-                            "Failed to redirect {} to file '{}': {}",
-                            $lookup_key, path, e
-                        )
-                    })?));
-                }
-
-                Ok($name::None)
-            }
+/// Look up a redirect variable ($__stdout or $__stderr) in scope, and open the
+/// output stream for it. Values can name the other stream, "null", or a filename.
+fn redirect_output(
+    scope: &Rc<Scope>,
+    var_name: &str,
+    other: (&[&str], Output),
+    default: Output,
+) -> Result<Output, String> {
+    if let Some(v) = scope.lookup_value(var_name) {
+        let path = v.to_string();
+        if other.0.contains(&path.as_str()) {
+            return Ok(other.1);
         }
-    };
-}
-
-macro_rules! handle_redir_error {
-    ($redir:expr, $loc:expr) => {
-        if let Err(message) = &$redir {
-            if !message.contains("Redirect already exists") {
-                return Err(EvalError::new($loc, message.clone()));
-            }
+        if path.to_ascii_lowercase() == "null" {
+            return Ok(Output::Null);
         }
-    };
-}
+        let file = OpenOptions::new()
+            .truncate(true)
+            .read(true)
+            .create(true)
+            .write(true)
+            .open(&path)
+            .map_err(|e| {
+                format!(
+                    "Failed to open file for {} redirection '{}': {}",
+                    var_name, path, e
+                )
+            })?;
+        return Ok(Output::File(file));
+    }
 
-// Define RedirStderr
-define_redir_enum!(RedirStderr, Stdout);
-define_redir_impl!(RedirStderr, stderr, Stdout, stdout, "__stderr", "1");
+    Ok(default)
+}
 
-// Define RedirStdout
-define_redir_enum!(RedirStdout, Stderr);
-define_redir_impl!(RedirStdout, stdout, Stderr, stderr, "__stdout", "2");
+impl Command {
+    /// Evaluate the command line arguments, and open the output streams.
+    /// Also returns the scope to execute in, see exec_scope.
+    fn prepare(&self) -> EvalResult<(Vec<String>, Streams, Rc<Scope>)> {
+        let scope = self.exec_scope()?;
 
-impl Eval for Command {
-    fn eval(&self) -> EvalResult<Value> {
         // Redirect stdout if a $__stdout variable found in scope.
         // Values can be "2", "__stderr", "null", or a filename.
-        let redir_stdout = RedirStdout::with_scope(&self.scope);
-        handle_redir_error!(&redir_stdout, self.loc);
+        let stdout = redirect_output(
+            &scope,
+            "__stdout",
+            (&["2", "__stderr"], Output::Stderr),
+            Output::Inherit,
+        )
+        .map_err(|e| EvalError::new(self.loc, e))?;
 
         // Redirect stderr if a $__stderr variable found in scope.
         // Values can be "1", "__stdout", "null", or a filename.
-        let redir_stderr = RedirStderr::with_scope(&self.scope);
-        handle_redir_error!(&redir_stderr, self.loc);
+        let stderr = redirect_output(
+            &scope,
+            "__stderr",
+            (&["1", "__stdout"], Output::Inherit),
+            Output::Stderr,
+        )
+        .map_err(|e| EvalError::new(self.loc, e))?;
 
         // Evaluate command line arguments and convert to strings
-        let args = self
+        let args: Vec<String> = self
             .args
             .to_values()?
             .into_iter()
             .map(|v| v.to_string())
             .collect();
-        // Execute command
-        let result = self
+
+        trace(&scope, self.loc, || {
+            let env = self.env.iter().filter_map(|(name, _)| {
+                let var = scope.lookup_local(name)?;
+                Some(format!("{}={}", name, trace_value(&var.value())))
+            });
+            env.chain([self.cmd.name().to_string()])
+                .chain(args.iter().map(|arg| trace_word(arg)))
+                .collect::<Vec<_>>()
+                .join(" ")
+        });
+
+        let streams = Streams {
+            stdin: Input::Inherit,
+            stdout,
+            stderr,
+        };
+        Ok((args, streams, scope))
+    }
+
+    /// The scope to execute in: a child scope with the NAME=VALUE overrides exported,
+    /// if the command has any, so that they go away when the command is done.
+    fn exec_scope(&self) -> EvalResult<Rc<Scope>> {
+        if self.env.is_empty() {
+            return Ok(Rc::clone(&self.scope));
+        }
+        let scope = Scope::new(Some(Rc::clone(&self.scope)));
+        for (name, value) in &self.env {
+            scope.export(name.clone(), value.eval()?);
+        }
+        Ok(scope)
+    }
+
+    /// Start an external program as a pipeline stage, without waiting for it.
+    fn spawn(&self, stdin: Input, stdout: Option<Output>) -> EvalResult<(Child, Streams)> {
+        let (args, mut streams, scope) = self.prepare()?;
+        streams.stdin = stdin;
+        if let Some(stdout) = stdout {
+            streams.stdout = stdout;
+        }
+        let child = self
             .cmd
-            .exec(&self.cmd.name(), &args, &self.scope)
-            .map_err(|e| EvalError::new(self.args.loc(), e));
+            .spawn(&args, &scope, &mut streams)
+            .map_err(|e| EvalError::new(self.args.loc(), e))?;
+
+        Ok((child, streams))
+    }
+
+    /// Start a builtin command as a pipeline stage, in a thread of its own. Scopes cannot
+    /// be shared between threads: the command gets a copy of the variables in scope, so
+    /// commands are started only if can_copy_vars.
+    /// The last stage of a pipeline writes to the output of the pipeline: if that is a
+    /// capture buffer, return the thread collecting the output, to be copied when done.
+    fn start(&self, mut stdin: Input, stdout: Option<Output>) -> EvalResult<BuiltinStage> {
+        let (args, mut streams, scope) = self.prepare()?;
+        stdin.resolve(); // The input of the enclosing pipeline stage is per thread.
+        streams.stdin = stdin;
+
+        let mut output = None;
+        if let Some(stdout) = stdout {
+            streams.stdout = stdout;
+        } else if let Output::Inherit = streams.stdout {
+            streams.stdout = match pipe::share_output() {
+                Some(shared) => shared.map_err(|e| EvalError::new(self.loc, e.to_string()))?,
+                None => {
+                    let (reader, writer) = os_pipe::pipe().map_err(|e| {
+                        EvalError::new(self.loc, format!("Failed to create pipe: {}", e))
+                    })?;
+                    output = Some(read_in_background(reader));
+                    Output::Pipe(writer)
+                }
+            };
+        }
+        let cmd = self.cmd.clone();
+        let vars = scope.copy_vars();
+
+        let thread = thread::spawn(move || {
+            let scope = Scope::new(None);
+            for (name, value, exported) in vars {
+                if exported {
+                    scope.export(name, value.into());
+                } else {
+                    scope.insert(name, value.into());
+                }
+            }
+            cmd.exec(cmd.name(), &args, &scope, &mut streams)
+                .map(|_| ())
+        });
+        Ok((thread, output))
+    }
+}
 
+impl Eval for Command {
+    fn eval(&self) -> EvalResult<Value> {
         let cmd = self.to_string();
+
+        if self.background {
+            let result = jobs::launch(cmd.clone(), true, || {
+                if !self.cmd.is_external() {
+                    // Pass the arguments as they are, expanded in this process
+                    let (args, mut streams, scope) = self.prepare()?;
+                    let mut script = self.cmd.name().clone();
+                    for i in 1..=args.len() {
+                        script.push_str(&format!(" \"${}\"", i));
+                    }
+                    return eval_detached(self.loc, &script, &args, &scope, &mut streams);
+                }
+                let (child, _) = self.spawn(Input::empty(), None)?;
+                jobs::background(vec![child]);
+                Ok(Value::success())
+            });
+            return Ok(Value::Stat(Status::new(cmd, &result, &self.scope)));
+        }
+
+        let (args, mut streams, scope) = self.prepare()?;
+
+        // Execute command
+        let result = jobs::launch(cmd.clone(), false, || {
+            self.cmd
+                .exec(&self.cmd.name(), &args, &scope, &mut streams)
+                .map_err(|e| EvalError::new(self.args.loc(), e))
+        });
+
         Ok(Value::Stat(Status::new(cmd, &result, &self.scope)))
     }
 }
@@ -2233,6 +4166,9 @@ impl ExprNode for Command {
 
 impl fmt::Display for Command {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, value) in &self.env {
+            write!(f, "{}={} ", name, value)?;
+        }
         if self.args.is_no_args() {
             return write!(f, "{}", self.cmd.name());
         }
@@ -2278,14 +4214,109 @@ fn value_as_bool(val: &Value, scope: &Rc<Scope>) -> bool {
         Value::Int(i) => *i != 0,
         Value::Real(r) => *r != 0.0,
         Value::Str(s) => !s.is_empty(), // TODO: maybe not such a good idea?
+        Value::List(items) => !items.is_empty(),
+        Value::Map(map) => !map.is_empty(),
         Value::Stat(s) => s.borrow_mut().as_bool(&scope),
     };
 
     hoist(scope, "__errors");
+    hoist(scope, "__pipestatus");
 
     result
 }
 
+/// Is the variable set to a true value, e.g. PIPEFAIL = 1? Values from the
+/// environment are strings, and are parsed first, so that 0 is false.
+fn is_enabled(scope: &Rc<Scope>, var_name: &str) -> bool {
+    scope.lookup_value(var_name).is_some_and(|val| {
+        let val = match val {
+            Value::Str(s) => s.parse::<Value>().unwrap_or(Value::Str(s)),
+            val => val,
+        };
+        value_as_bool(&val, scope)
+    })
+}
+
+/// A builtin command running as a pipeline stage, and the thread collecting its output.
+type BuiltinStage = (JoinHandle<Result<(), String>>, Option<JoinHandle<Vec<u8>>>);
+
+/// Copy of a variable value, for builtin commands running in threads of their own.
+enum SharedValue {
+    Int(i64),
+    Real(f64),
+    Str(String),
+    List(Vec<SharedValue>),
+    Map(BTreeMap<String, SharedValue>),
+}
+
+impl SharedValue {
+    /// None for command statuses, which cannot be copied.
+    fn new(value: &Value) -> Option<Self> {
+        Some(match value {
+            Value::Int(i) => SharedValue::Int(*i),
+            Value::Real(r) => SharedValue::Real(*r),
+            Value::Str(s) => SharedValue::Str(s.clone()),
+            Value::Stat(_) => return None,
+            Value::List(items) => {
+                SharedValue::List(items.iter().map(Self::new).collect::<Option<_>>()?)
+            }
+            Value::Map(map) => SharedValue::Map(
+                map.iter()
+                    .map(|(k, v)| Some((k.clone(), Self::new(v)?)))
+                    .collect::<Option<_>>()?,
+            ),
+        })
+    }
+}
+
+impl From<SharedValue> for Value {
+    fn from(value: SharedValue) -> Self {
+        match value {
+            SharedValue::Int(i) => Value::Int(i),
+            SharedValue::Real(r) => Value::Real(r),
+            SharedValue::Str(s) => Value::Str(s),
+            SharedValue::List(items) => Value::List(items.into_iter().map(Value::from).collect()),
+            SharedValue::Map(map) => {
+                Value::Map(map.into_iter().map(|(k, v)| (k, v.into())).collect())
+            }
+        }
+    }
+}
+
+/// Read a pipe in another thread until the end, so that the writing side is never blocked.
+fn read_in_background<R: Read + Send + 'static>(mut reader: R) -> JoinHandle<Vec<u8>> {
+    thread::spawn(move || {
+        let mut data = Vec::new();
+        _ = reader.read_to_end(&mut data);
+        data
+    })
+}
+
+/// Print a step of the evaluation to stderr when $TRACE is set, e.g. with the -x command
+/// line flag: commands with their expanded arguments, assignments, and branch decisions.
+fn trace(scope: &Scope, loc: Location, step: impl FnOnce() -> String) {
+    if scope.lookup("TRACE").is_some() {
+        let line = format!("+ {} {}\n", loc, step());
+        _ = pipe::write_error(line.as_bytes());
+    }
+}
+
+/// Quote traced words that would otherwise be ambiguous, e.g. with blanks
+fn trace_word(word: &str) -> String {
+    if word.is_empty() || word.contains(|c: char| c.is_whitespace() || c == '"') {
+        format!("{:?}", word)
+    } else {
+        word.to_string()
+    }
+}
+
+fn trace_value(val: &Value) -> String {
+    match val {
+        Value::List(_) => format!("[{}]", val),
+        _ => trace_word(&val.to_string()),
+    }
+}
+
 fn eval_as_bool(expr: &Rc<Expression>, scope: &Rc<Scope>) -> EvalResult<bool> {
     Ok(value_as_bool(&expr.eval()?, &scope))
 }
@@ -2321,7 +4352,12 @@ impl Eval for BranchExpr {
         } else if self.if_branch.is_empty() {
             return error(self, "Expecting IF block");
         }
-        if eval_as_bool(&self.cond, &self.scope)? {
+        let cond = eval_as_bool(&self.cond, &self.scope)?;
+        trace(&self.scope, self.loc, || {
+            format!("if {} -> {}", self.cond, cond)
+        });
+
+        if cond {
             self.if_branch.eval()
         } else if self.else_branch.is_empty() {
             Ok(Value::success())
@@ -2346,6 +4382,7 @@ impl fmt::Display for BranchExpr {
 struct Literal {
     tok: String,
     quoted: bool,
+    list: bool, // List or map literal, e.g. [1 2 3], {a: 1}
     loc: Location,
     scope: Rc<Scope>,
 }
@@ -2354,7 +4391,11 @@ derive_has_location!(Literal);
 
 impl Eval for Literal {
     fn eval(&self) -> EvalResult<Value> {
-        parse_value(&self.tok, self.loc, &self.scope)
+        if self.list {
+            eval_container(&self.tok, self.loc, &self.scope)
+        } else {
+            eval_literal(&self.tok, self.quoted, self.loc, &self.scope)
+        }
     }
 }
 
@@ -2415,7 +4456,11 @@ impl Eval for LoopExpr {
         }
         let mut result = Ok(Value::success());
         loop {
-            if !eval_as_bool(&self.cond, &self.scope)? {
+            let cond = eval_as_bool(&self.cond, &self.scope)?;
+            trace(&self.scope, self.loc, || {
+                format!("while {} -> {}", self.cond, cond)
+            });
+            if !cond {
                 break;
             }
             eval_iteration!(self, result);
@@ -2446,6 +4491,268 @@ impl fmt::Display for LoopExpr {
     }
 }
 
+/// Match text against a regular expression, and store the captures in
+/// __match (the whole match), __match1, __match2, etc.
+fn regex_match(text: &str, pattern: &str, loc: Location, scope: &Rc<Scope>) -> EvalResult<bool> {
+    let re =
+        Regex::new(pattern).map_err(|e| EvalError::new(loc, format!("Invalid regex: {}", e)))?;
+
+    // Store the captures in the enclosing scope, so that they are visible in the
+    // body of IF and WHILE expressions; the topmost scope is for environment vars.
+    let scope = match &scope.parent {
+        Some(parent) if parent.parent.is_some() => parent,
+        _ => scope,
+    };
+
+    // Clear the captures of the previous match; declared ones are assigned instead
+    let clear = |var_name: &str| match scope.lookup_local(var_name) {
+        Some(var) if var.decl != Declaration::default() => true,
+        Some(_) => scope.erase(var_name).is_some(),
+        None => false,
+    };
+    clear("__match");
+    let mut i = 1;
+    while clear(&format!("__match{}", i)) {
+        i += 1;
+    }
+
+    match re.captures(text) {
+        Some(caps) => {
+            for (i, group) in caps.iter().enumerate() {
+                let var_name = match i {
+                    0 => "__match".to_string(),
+                    _ => format!("__match{}", i),
+                };
+                let text = group.map_or("", |m| m.as_str());
+                scope
+                    .parse_for_var(&var_name, text)
+                    .and_then(|value| scope.assign(&var_name, value))
+                    .map_err(|e| EvalError::new(loc, e))?;
+            }
+            Ok(true)
+        }
+        None => Ok(false),
+    }
+}
+
+#[derive(Debug)]
+struct MatchExpr {
+    value: Rc<Expression>,
+    body: Rc<Expression>,
+    loc: Location,
+    scope: Rc<Scope>,
+}
+
+derive_has_location!(MatchExpr);
+
+impl MatchExpr {
+    /// Split an arm into its pattern and body: PATTERN => (BODY)
+    fn arm(expr: &Rc<Expression>) -> EvalResult<(Rc<Expression>, Rc<Expression>)> {
+        if let Expression::Bin(bin) = &**expr {
+            let b = bin.borrow();
+            if b.op == Op::Write && !b.lhs.is_empty() && b.rhs.is_group() {
+                return Ok((Rc::clone(&b.lhs), Rc::clone(&b.rhs)));
+            }
+        }
+        error(&**expr, "Expecting PATTERN => (BODY) in MATCH arm")
+    }
+
+    /// Check the value against the pattern of an arm, which is either the
+    /// default (_), a regular expression (=~ REGEX), a glob, or a literal.
+    fn is_match(&self, value: &str, pattern: &Rc<Expression>) -> EvalResult<bool> {
+        match &**pattern {
+            Expression::Leaf(lit) if lit.tok == "_" && !lit.quoted => Ok(true),
+            Expression::Bin(bin) if bin.borrow().op == Op::Match && bin.borrow().lhs.is_empty() => {
+                let b = bin.borrow();
+                let regex = Status::check_result(b.rhs.eval())?.to_string();
+                regex_match(value, &regex, b.loc, &b.scope)
+            }
+            _ => {
+                let pattern_str = Status::check_result(pattern.eval())?.to_string();
+                if pattern_str.contains(['*', '?', '[']) {
+                    let glob = glob::Pattern::new(&pattern_str).map_err(|e| {
+                        EvalError::new(pattern.loc(), format!("Invalid pattern: {}", e))
+                    })?;
+                    Ok(glob.matches(value))
+                } else {
+                    Ok(pattern_str == value)
+                }
+            }
+        }
+    }
+}
+
+impl Eval for MatchExpr {
+    fn eval(&self) -> EvalResult<Value> {
+        if self.value.is_empty() {
+            return error(self, "Expecting MATCH value");
+        } else if self.body.is_empty() {
+            return error(self, "Expecting MATCH body");
+        }
+        let value = Status::check_result(self.value.eval())?.to_string();
+
+        if let Expression::Group(group) = &*self.body {
+            for arm in &group.borrow().content {
+                let (pattern, body) = Self::arm(arm)?;
+                if self.is_match(&value, &pattern)? {
+                    trace(&self.scope, self.loc, || {
+                        format!("match {} -> {}", trace_word(&value), pattern)
+                    });
+                    return body.eval();
+                }
+            }
+        }
+        trace(&self.scope, self.loc, || {
+            format!("match {} -> no match", trace_word(&value))
+        });
+        Ok(Value::success())
+    }
+}
+
+impl ExprNode for MatchExpr {
+    fn add_child(&mut self, child: &Rc<Expression>) -> EvalResult {
+        if self.value.is_empty() {
+            self.value = Rc::clone(child);
+        } else if self.body.is_empty() {
+            if let Expression::Group(group) = &**child {
+                for arm in &group.borrow().content {
+                    Self::arm(arm)?;
+                }
+            } else {
+                return error(&**child, "Parentheses are required around MATCH arms");
+            }
+            self.body = Rc::clone(child);
+        } else {
+            return error(&**child, "MATCH already has a body");
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for MatchExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "match {} {}", self.value, self.body)
+    }
+}
+
+#[derive(Debug)]
+struct TryExpr {
+    body: Rc<Expression>,
+    var: String, // Variable that holds the error in the CATCH block
+    handler: Rc<Expression>,
+    finally: Rc<Expression>,
+    expect_catch: bool,
+    expect_finally: bool,
+    loc: Location,
+}
+
+derive_has_location!(TryExpr);
+
+impl TryExpr {
+    fn is_catch_expected(&mut self) -> bool {
+        if !self.body.is_empty()
+            && self.handler.is_empty()
+            && self.finally.is_empty()
+            && !self.expect_catch
+            && !self.expect_finally
+        {
+            self.expect_catch = true;
+            return true;
+        }
+        false
+    }
+
+    fn is_finally_expected(&mut self) -> bool {
+        if !self.body.is_empty()
+            && self.finally.is_empty()
+            && !self.expect_catch
+            && !self.expect_finally
+        {
+            self.expect_finally = true;
+            return true;
+        }
+        false
+    }
+}
+
+impl Eval for TryExpr {
+    fn eval(&self) -> EvalResult<Value> {
+        if self.body.is_empty() {
+            return error(self, "Expecting TRY body");
+        } else if self.expect_catch {
+            return error(self, "Expecting CATCH body");
+        } else if self.expect_finally {
+            return error(self, "Expecting FINALLY body");
+        } else if self.handler.is_empty() && self.finally.is_empty() {
+            return error(self, "Expecting CATCH or FINALLY");
+        }
+
+        let mut result = Status::check_result(self.body.eval());
+
+        // Errors that carry BREAK or CONTINUE are not caught, only passed through FINALLY
+        if let Err(e) = &result {
+            if e.jump.is_none() && !self.handler.is_empty() {
+                result = Status::check_result(match &*self.handler {
+                    Expression::Group(g) if !self.var.is_empty() => {
+                        g.borrow().eval_with_var(&self.var, e.to_value())
+                    }
+                    handler => handler.eval(),
+                });
+            }
+        }
+
+        if !self.finally.is_empty() {
+            // An error in FINALLY takes precedence
+            Status::check_result(self.finally.eval())?;
+        }
+        result
+    }
+}
+
+impl ExprNode for TryExpr {
+    fn add_child(&mut self, child: &Rc<Expression>) -> EvalResult {
+        if self.body.is_empty() {
+            if !child.is_group() {
+                return error(&**child, "Parentheses are required around TRY body");
+            }
+            self.body = Rc::clone(child);
+        } else if self.expect_catch {
+            match &**child {
+                Expression::Leaf(lit) if self.var.is_empty() && !lit.quoted => {
+                    self.var = lit.tok.clone();
+                }
+                Expression::Group(_) => {
+                    self.handler = Rc::clone(child);
+                    self.expect_catch = false;
+                }
+                _ => return error(&**child, "Parentheses are required around CATCH body"),
+            }
+        } else if self.expect_finally {
+            if !child.is_group() {
+                return error(&**child, "Parentheses are required around FINALLY body");
+            }
+            self.finally = Rc::clone(child);
+            self.expect_finally = false;
+        } else {
+            return error(&**child, "TRY already has a body");
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for TryExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "try {}", self.body)?;
+        if !self.handler.is_empty() {
+            write!(f, " catch {} {}", self.var, self.handler)?;
+        }
+        if !self.finally.is_empty() {
+            write!(f, " finally {}", self.finally)?;
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 struct ForExpr {
     var: String,
@@ -2457,6 +4764,31 @@ struct ForExpr {
 
 derive_has_location!(ForExpr);
 
+impl ForExpr {
+    /// Collect the keys of the maps in: FOR VAR IN KEYS $MAP...
+    /// Return None if the arguments do not start with KEYS.
+    fn map_keys(&self) -> EvalResult<Option<Vec<Value>>> {
+        if let Expression::Args(args) = &*self.args {
+            let content = &args.borrow().content;
+
+            if content.len() > 1
+                && matches!(&*content[0], Expression::Leaf(lit)
+                    if !lit.quoted && lit.tok.eq_ignore_ascii_case("keys"))
+            {
+                let mut keys = Vec::new();
+                for expr in &content[1..] {
+                    match Status::check_result(expr.eval())? {
+                        Value::Map(map) => keys.extend(map.into_keys().map(Value::Str)),
+                        _ => return error(&**expr, "Expecting map after KEYS"),
+                    }
+                }
+                return Ok(Some(keys));
+            }
+        }
+        Ok(None)
+    }
+}
+
 impl Eval for ForExpr {
     fn eval(&self) -> EvalResult<Value> {
         if self.var.is_empty() {
@@ -2471,9 +4803,17 @@ impl Eval for ForExpr {
 
         let mut result = Ok(Value::success());
 
-        let args = self.args.tokenize_args()?;
-        for arg in &args {
-            self.scope.insert(self.var.clone(), arg.parse::<Value>()?);
+        let args = match self.map_keys()? {
+            Some(keys) => keys,
+            None => self.args.tokenize_args()?,
+        };
+        for arg in args {
+            trace(&self.scope, self.loc, || {
+                format!("for {} = {}", self.var, trace_value(&arg))
+            });
+            self.scope
+                .assign(&self.var, arg)
+                .map_err(|e| EvalError::new(self.loc, e))?;
             eval_iteration!(self, result);
         }
 
@@ -2513,6 +4853,262 @@ impl fmt::Display for ForExpr {
     }
 }
 
+#[derive(Debug)]
+struct DefExpr {
+    name: String,
+    params: Vec<String>,
+    body: Rc<Expression>,
+    body_loc: Location,
+    body_start: usize, // Offset of the body in the parser's source
+    source: String,
+    loc: Location,
+}
+
+derive_has_location!(DefExpr);
+
+impl DefExpr {
+    fn register(&self) {
+        register_command(ShellCommand::new(
+            self.name.clone(),
+            Arc::new(Function {
+                name: self.name.clone(),
+                params: self.params.clone(),
+                body: self.source.clone(),
+                loc: self.body_loc,
+            }),
+        ));
+    }
+}
+
+impl Eval for DefExpr {
+    fn eval(&self) -> EvalResult<Value> {
+        if self.body.is_empty() {
+            return error(self, "Expecting DEF body");
+        }
+        // Functions exist once their definition is evaluated, and the most
+        // recently evaluated definition wins.
+        self.register();
+        Ok(Value::success())
+    }
+}
+
+impl ExprNode for DefExpr {
+    fn add_child(&mut self, child: &Rc<Expression>) -> EvalResult {
+        if self.body.is_empty() {
+            if !child.is_group() {
+                return error(&**child, "Parentheses are required around DEF body");
+            }
+            self.body = Rc::clone(child);
+        } else {
+            return error(&**child, "DEF already has a body");
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DefExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "def {} ({}) {}",
+            self.name,
+            self.params.join(" "),
+            self.body
+        )
+    }
+}
+
+/// SOURCE FILE (or . FILE): evaluate a script in the current scope, so that the variables
+/// and functions it defines persist. Parsing stops after a top-level SOURCE statement, see
+/// Parser::eval, so that the functions are known when parsing the statements that follow.
+#[derive(Debug)]
+struct SourceExpr {
+    name: String,
+    args: Rc<Expression>,
+    loc: Location,
+    scope: Rc<Scope>,
+}
+
+derive_has_location!(SourceExpr);
+
+impl Eval for SourceExpr {
+    fn eval(&self) -> EvalResult<Value> {
+        let args = self.args.to_values()?;
+        let file_name = match args.as_slice() {
+            [file_name] => file_name.to_string(),
+            [] => return error(&*self.args, "Missing FILE argument"),
+            _ => return error(&*self.args, "Too many arguments"),
+        };
+        trace(&self.scope, self.loc, || {
+            format!("{} {}", self.name, trace_word(&file_name))
+        });
+
+        // Errors in the file keep their location in it, see Interp::eval_file
+        let result = fs::read_to_string(&file_name)
+            .map_err(|e| EvalError::new(self.args.loc(), format!("{}: {}", file_name, e)))
+            .and_then(|input| Interp::with_scope(&self.scope).eval_file(&file_name, &input));
+
+        Ok(Value::Stat(Status::new(
+            self.to_string(),
+            &result,
+            &self.scope,
+        )))
+    }
+}
+
+impl ExprNode for SourceExpr {
+    fn add_child(&mut self, child: &Rc<Expression>) -> EvalResult {
+        assert!(child.is_args());
+        assert!(self.args.is_empty());
+        self.args = Rc::clone(child);
+        Ok(())
+    }
+}
+
+impl fmt::Display for SourceExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.name, self.args)
+    }
+}
+
+/// Stand-in for a function that is called in the input being parsed, and defined
+/// with DEF in the same input. Calls that are evaluated before the DEF fail, unless
+/// a command by the same name was already registered.
+struct DeclaredFunction {
+    name: String,
+}
+
+impl Exec for DeclaredFunction {
+    fn exec(
+        &self,
+        name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        match get_command(&self.name) {
+            Some(cmd) => cmd.exec(name, args, scope, streams),
+            None => Err(format!("{}: command not found", self.name)),
+        }
+    }
+
+    fn is_external(&self) -> bool {
+        false
+    }
+
+    fn is_function(&self) -> bool {
+        true
+    }
+
+    fn is_thread_safe(&self) -> bool {
+        false
+    }
+}
+
+/// Alias defined with ALIAS in the input being parsed. It parses as a command, and is
+/// expanded when called, with the arguments appended: the alias is defined only if the
+/// ALIAS command has run by then.
+struct DeclaredAlias {
+    name: String,
+}
+
+impl Exec for DeclaredAlias {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        let expansion =
+            get_alias(&self.name).ok_or_else(|| format!("{}: command not found", self.name))?;
+
+        let mut parser = Parser::new("".chars(), scope);
+        parser.expand_alias(&expansion).map_err(|e| e.to_string())?;
+        // Pending tokens are in reverse order: the arguments go first.
+        let mut tokens: Vec<_> = args
+            .iter()
+            .rev()
+            .map(|arg| Token::Literal((arg.clone(), true)))
+            .collect();
+        tokens.append(&mut parser.pending_tokens);
+        parser.pending_tokens = tokens;
+
+        let mut quit = false;
+        pipe::with_streams(streams, || parser.eval(&mut quit)).map_err(|e| e.to_string())
+    }
+
+    fn is_external(&self) -> bool {
+        false
+    }
+
+    fn is_thread_safe(&self) -> bool {
+        false
+    }
+}
+
+/// User-defined function, invoked like any other command.
+struct Function {
+    name: String,
+    params: Vec<String>,
+    body: String,
+    loc: Location,
+}
+
+impl Exec for Function {
+    fn exec(
+        &self,
+        _name: &str,
+        args: &Vec<String>,
+        scope: &Rc<Scope>,
+        streams: &mut Streams,
+    ) -> Result<Value, String> {
+        if args.len() != self.params.len() {
+            return Err(format!(
+                "{}: expected {} argument(s), got {}",
+                self.name,
+                self.params.len(),
+                args.len()
+            ));
+        }
+
+        // Each call gets its own scope, with the parameters bound to the arguments.
+        let call_scope = Scope::new(Some(Rc::clone(scope)));
+        for (param, arg) in self.params.iter().zip(args) {
+            call_scope.insert(
+                param.clone(),
+                arg.parse::<Value>().map_err(|e| e.to_string())?,
+            );
+        }
+
+        // The AST holds on to the scopes it was parsed in; parse the body anew
+        // on every call, so that recursive calls do not clobber each other.
+        let mut parser = Parser::new(self.body.chars(), &call_scope);
+        parser.loc = self.loc;
+        parser.declare_functions(&self.body);
+
+        let mut quit = false;
+        pipe::with_streams(streams, || parser.eval(&mut quit)).map_err(|e| e.to_string())
+    }
+
+    fn is_external(&self) -> bool {
+        false
+    }
+
+    fn is_function(&self) -> bool {
+        true
+    }
+
+    fn is_thread_safe(&self) -> bool {
+        false
+    }
+
+    fn definition(&self) -> Option<String> {
+        let params = self.params.join(" ");
+        Some(format!("def {} ({}) {}", self.name, params, self.body))
+    }
+}
+
 fn eval_unary<T: HasLocation>(
     loc: &T,
     op: &Op,
@@ -2525,6 +5121,8 @@ fn eval_unary<T: HasLocation>(
             Value::Real(r) => Ok(Value::Real(-r)),
             Value::Str(s) => Ok(Value::Str(format!("-{}", s))),
             Value::Stat(_) => error(loc, "Unary minus not supported for command status"),
+            Value::List(_) => error(loc, "Unary minus not supported for lists"),
+            Value::Map(_) => error(loc, "Unary minus not supported for maps"),
         },
         Op::Not => {
             if let Value::Stat(s) = &val {
@@ -2546,6 +5144,8 @@ impl Eval for Expression {
             Expression::Bin(b) => b.borrow().eval(),
             Expression::Branch(b) => b.borrow().eval(),
             Expression::Cmd(c) => c.borrow().eval(),
+            Expression::Def(d) => d.borrow().eval(),
+            Expression::Source(s) => s.borrow().eval(),
             Expression::Group(g) => g.borrow().eval(),
             Expression::For(f) => f.borrow().eval(),
             Expression::Empty => {
@@ -2553,6 +5153,8 @@ impl Eval for Expression {
             }
             Expression::Leaf(lit) => lit.eval(),
             Expression::Loop(l) => l.borrow().eval(),
+            Expression::Match(m) => m.borrow().eval(),
+            Expression::Try(t) => t.borrow().eval(),
         }
     }
 }
@@ -2580,25 +5182,94 @@ impl Interp {
         }
     }
 
+    /// Interpreter that evaluates in an existing scope, e.g. the caller of SOURCE
+    pub fn with_scope(scope: &Rc<Scope>) -> Self {
+        Self {
+            scope: Rc::clone(scope),
+        }
+    }
+
     pub fn eval(&self, quit: &mut bool, input: &str) -> EvalResult<Value> {
-        let ast = self.parse(quit, input)?;
+        let mut parser = Parser::new(input.chars(), &self.scope);
+        parser.declare_functions(input);
+        parser.eval(quit)
+    }
 
-        if self.scope.lookup("DUMP_AST").is_some() {
-            dbg!(&ast);
+    /// Evaluate the contents of a source file. The variables that the file assigns
+    /// at the top level are copied into the interpreter scope, so that they persist
+    /// after the evaluation; functions are global and persist regardless.
+    pub fn eval_file(&self, file_name: &str, input: &str) -> EvalResult<Value> {
+        let mut parser = Parser::new(input.chars(), &self.scope);
+        parser.loc = Location::with_file(file_name);
+        parser.prev_loc = parser.loc;
+        parser.declare_functions(input);
+
+        let file_scope = Rc::clone(&parser.scope);
+        let mut quit = false;
+        let result = parser.eval(&mut quit);
+
+        for (name, var) in file_scope.vars.borrow().iter() {
+            // Keep the declaration, and the export flag of the variable replaced, e.g. PATH
+            if self
+                .scope
+                .lookup_local(name)
+                .is_some_and(|v| v.is_exported())
+            {
+                var.set_exported(true);
+            }
+            self.scope
+                .vars
+                .borrow_mut()
+                .insert(name.clone(), var.clone());
         }
-
-        Status::check_result(ast.eval())
+        result
     }
 
-    fn parse(&self, quit: &mut bool, input: &str) -> EvalResult<Rc<Expression>> {
+    /// Is more input expected, because of unbalanced parentheses, quotes or brackets?
+    /// Used by the REPL to read multi-line input.
+    pub fn is_incomplete(&self, input: &str) -> bool {
+        // Parse rather than just tokenize the input, so that brackets are read as lists only
+        // where the parser expects lists, e.g. the glob pattern in ls [a-c is not a list.
         let mut parser = Parser::new(input.chars(), &self.scope);
-        parser.parse(quit)
+        let mut quit = false;
+        loop {
+            match parser.parse(&mut quit) {
+                Ok(_) if parser.resume && !quit => {}
+                Ok(_) => return false,
+                Err(e) => {
+                    // Unterminated strings, lists and command substitutions, or open blocks
+                    let unterminated = matches!(
+                        e.message.as_str(),
+                        "Unbalanced quotes"
+                            | "Unbalanced brackets in list"
+                            | "Unbalanced parentheses in command substitution"
+                    );
+                    return parser.chars.peek().is_none()
+                        && (unterminated || parser.has_open_block());
+                }
+            }
+        }
     }
 
     pub fn set_var(&mut self, name: &str, value: String) {
         self.scope.insert(name.to_string(), Value::Str(value))
     }
 
+    /// Set the positional parameters: $0 is the script (or the shell itself),
+    /// $1, $2... are the arguments, also available as the $__args list, and
+    /// $__argc is the number of arguments.
+    pub fn set_args(&mut self, args: &[String]) {
+        for (i, arg) in args.iter().enumerate() {
+            self.set_var(&i.to_string(), arg.clone());
+        }
+        let list = args.iter().skip(1).map(|a| Value::Str(a.clone())).collect();
+        self.scope.insert("__args".to_string(), Value::List(list));
+        self.scope.insert(
+            "__argc".to_string(),
+            Value::Int(args.len().saturating_sub(1) as _),
+        );
+    }
+
     pub fn get_scope(&self) -> Rc<Scope> {
         Rc::clone(&self.scope)
     }
@@ -0,0 +1,457 @@
+use crate::cmds::{get_alias, get_command, Exec};
+use crate::eval::{Scope, KEYWORDS, SOURCE_COMMANDS};
+use colored::Colorize;
+use std::borrow::Cow;
+use std::rc::Rc;
+
+/// Syntax elements that get their own colors in the interactive editor
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Style {
+    Plain,
+    Keyword,
+    Builtin,
+    Function,
+    External,
+    Unknown,
+    Variable,
+    Str,
+    Operator,
+    Comment,
+    Redirect,
+    Bracket,
+}
+
+/// Can the next word be a command?
+#[derive(Clone, Copy, PartialEq)]
+enum CommandPos {
+    Yes,   // Start of statement: unknown words are flagged
+    Maybe, // E.g. right hand-side of assignment: only known commands are colored
+    No,
+}
+
+struct Span {
+    start: usize,
+    end: usize,
+    style: Style,
+}
+
+/// Lightweight lexer for highlighting; unlike the parser's tokenizer, it keeps
+/// track of the position of each token, and never fails on incomplete input.
+struct Lexer<'a> {
+    line: &'a str,
+    chars: Vec<(usize, char)>,
+    spans: Vec<Span>,
+    cmd_pos: CommandPos,
+    redirect: bool, // Next word is the target of a redirect
+}
+
+const OPERATOR_CHARS: &str = ";|&<>=!~";
+const ARITHMETIC_CHARS: &str = "+-*/%^";
+const WORD_DELIMITERS: &str = "()\";|&<>=";
+
+impl<'a> Lexer<'a> {
+    fn new(line: &'a str) -> Self {
+        Self {
+            line,
+            chars: line.char_indices().collect(),
+            spans: Vec::new(),
+            cmd_pos: CommandPos::Yes,
+            redirect: false,
+        }
+    }
+
+    /// Byte offset of the i-th character
+    fn offset(&self, i: usize) -> usize {
+        self.chars
+            .get(i)
+            .map_or(self.line.len(), |&(offset, _)| offset)
+    }
+
+    fn char_at(&self, i: usize) -> Option<char> {
+        self.chars.get(i).map(|&(_, c)| c)
+    }
+
+    fn push(&mut self, start: usize, end: usize, style: Style) {
+        if start < end {
+            self.spans.push(Span {
+                start: self.offset(start),
+                end: self.offset(end),
+                style,
+            });
+        }
+    }
+
+    fn is_word_start(&self, i: usize) -> bool {
+        i == 0
+            || self
+                .char_at(i - 1)
+                .is_some_and(|c| c.is_whitespace() || "(;".contains(c))
+    }
+
+    /// Return the end of the variable reference that starts at i, if any:
+    /// $NAME, $NAME[INDEX]..., $1, ${...}
+    fn variable_end(&self, i: usize) -> Option<usize> {
+        let mut j = i + 1;
+        match self.char_at(j)? {
+            '{' => {
+                while self.char_at(j).is_some_and(|c| c != '}') {
+                    j += 1;
+                }
+                Some((j + 1).min(self.chars.len()))
+            }
+            c if c.is_ascii_digit() => {
+                while self.char_at(j).is_some_and(|c| c.is_ascii_digit()) {
+                    j += 1;
+                }
+                Some(j)
+            }
+            c if c.is_alphabetic() || c == '_' => {
+                while self
+                    .char_at(j)
+                    .is_some_and(|c| c.is_alphanumeric() || c == '_')
+                {
+                    j += 1;
+                }
+                while self.char_at(j) == Some('[') {
+                    while self.char_at(j).is_some_and(|c| c != ']') {
+                        j += 1;
+                    }
+                    j = (j + 1).min(self.chars.len());
+                }
+                Some(j)
+            }
+            _ => None,
+        }
+    }
+
+    /// Add spans for text that may contain variable references, e.g. strings
+    fn push_with_variables(&mut self, start: usize, end: usize, style: Style) {
+        let mut i = start;
+        let mut text_start = start;
+        while i < end {
+            if self.char_at(i) == Some('$') {
+                if let Some(var_end) = self.variable_end(i) {
+                    let var_end = var_end.min(end);
+                    self.push(text_start, i, style);
+                    self.push(i, var_end, Style::Variable);
+                    i = var_end;
+                    text_start = i;
+                    continue;
+                }
+            }
+            i += 1;
+        }
+        self.push(text_start, end, style);
+    }
+
+    fn word_style(&mut self, word: &str, next: usize) -> Style {
+        if KEYWORDS.contains(&word.to_uppercase().as_str()) {
+            // The word after these keywords is not a command
+            self.cmd_pos = match word.to_uppercase().as_str() {
+                "CATCH" | "DEF" | "FOR" | "IN" | "LET" | "MATCH" | "READONLY" => CommandPos::No,
+                _ => CommandPos::Yes,
+            };
+            return Style::Keyword;
+        }
+        if self.redirect {
+            self.redirect = false;
+            return Style::Redirect;
+        }
+        let cmd_pos = self.cmd_pos;
+        self.cmd_pos = CommandPos::No;
+
+        if cmd_pos == CommandPos::No {
+            return Style::Plain;
+        }
+        if get_alias(word).is_some() {
+            return Style::Function;
+        }
+        if SOURCE_COMMANDS.contains(&word) {
+            return Style::Builtin;
+        }
+        match get_command(word) {
+            Some(cmd) if cmd.is_function() => Style::Function,
+            Some(cmd) if cmd.is_external() => Style::External,
+            Some(_) => Style::Builtin,
+            None => {
+                let mut j = next;
+                while self.char_at(j).is_some_and(|c| c == ' ' || c == '\t') {
+                    j += 1;
+                }
+                // Assignments, numbers, and words that may be values are not flagged
+                if cmd_pos == CommandPos::Maybe
+                    || self.char_at(j) == Some('=')
+                    || word.parse::<f64>().is_ok()
+                {
+                    Style::Plain
+                } else {
+                    Style::Unknown
+                }
+            }
+        }
+    }
+
+    fn run(mut self) -> Vec<Span> {
+        let mut i = 0;
+        while let Some(c) = self.char_at(i) {
+            let start = i;
+            if c.is_whitespace() {
+                i += 1;
+            } else if c == '#' && self.is_word_start(i) {
+                while self.char_at(i).is_some_and(|c| c != '\n') {
+                    i += 1;
+                }
+                self.push(start, i, Style::Comment);
+            } else if c == '"' {
+                i += 1;
+                while let Some(c) = self.char_at(i) {
+                    i += 1;
+                    if c == '\\' {
+                        i += 1;
+                    } else if c == '"' {
+                        break;
+                    }
+                }
+                i = i.min(self.chars.len());
+                self.push_with_variables(start, i, Style::Str);
+                self.cmd_pos = CommandPos::No;
+                self.redirect = false;
+            } else if c == '$' && self.char_at(i + 1) == Some('(') {
+                // Command substitution
+                i += 2;
+                self.push(start, start + 1, Style::Operator);
+                self.push(start + 1, i, Style::Bracket);
+                self.cmd_pos = CommandPos::Yes;
+            } else if c == '(' || c == ')' {
+                i += 1;
+                self.push(start, i, Style::Bracket);
+                self.cmd_pos = if c == '(' {
+                    CommandPos::Yes
+                } else {
+                    CommandPos::No
+                };
+            } else if OPERATOR_CHARS.contains(c) {
+                while self.char_at(i).is_some_and(|c| OPERATOR_CHARS.contains(c)) {
+                    i += 1;
+                }
+                let op: String = self.chars[start..i].iter().map(|&(_, c)| c).collect();
+                self.push(start, i, Style::Operator);
+                self.redirect = op == "=>" || op == "=>>";
+                self.cmd_pos = match op.as_str() {
+                    ";" | "|" | "||" | "&&" | "&" | "!" => CommandPos::Yes,
+                    _ if self.redirect => CommandPos::No,
+                    _ => CommandPos::Maybe,
+                };
+            } else if ARITHMETIC_CHARS.contains(c)
+                && self.is_word_start(i)
+                && self.char_at(i + 1).is_none_or(|c| c.is_whitespace())
+            {
+                i += 1;
+                self.push(start, i, Style::Operator);
+                self.cmd_pos = CommandPos::Maybe;
+            } else {
+                while self
+                    .char_at(i)
+                    .is_some_and(|c| !c.is_whitespace() && !WORD_DELIMITERS.contains(c))
+                {
+                    i += 1;
+                }
+                let word = &self.line[self.offset(start)..self.offset(i)];
+                if word.contains('$') {
+                    self.cmd_pos = CommandPos::No;
+                    self.redirect = false;
+                    self.push_with_variables(start, i, Style::Plain);
+                } else {
+                    let style = self.word_style(word, i);
+                    self.push(start, i, style);
+                }
+            }
+        }
+        self.spans
+    }
+}
+
+/// Find the parenthesis matching the one under the cursor, or right before it.
+fn matching_bracket(spans: &[Span], line: &str, pos: usize) -> Option<(usize, usize)> {
+    let brackets: Vec<usize> = spans
+        .iter()
+        .filter(|s| s.style == Style::Bracket)
+        .map(|s| s.end - 1)
+        .collect();
+
+    let current = brackets
+        .iter()
+        .position(|&b| b == pos)
+        .or_else(|| brackets.iter().position(|&b| b + 1 == pos))?;
+
+    let mut pairs = Vec::new();
+    let mut stack = Vec::new();
+    for (i, &b) in brackets.iter().enumerate() {
+        if line.as_bytes()[b] == b'(' {
+            stack.push(i);
+        } else if let Some(open) = stack.pop() {
+            pairs.push((open, i));
+        }
+    }
+    pairs.iter().find_map(|&(open, close)| {
+        if open == current || close == current {
+            Some((brackets[open], brackets[close]))
+        } else {
+            None
+        }
+    })
+}
+
+/// Colorize a command line, unless NO_COLOR is set. The parenthesis under the cursor
+/// (or right before it) and its match are highlighted when show_bracket is true.
+pub fn highlight<'l>(
+    line: &'l str,
+    pos: usize,
+    show_bracket: bool,
+    scope: &Rc<Scope>,
+) -> Cow<'l, str> {
+    if scope.lookup("NO_COLOR").is_some() {
+        return Cow::Borrowed(line);
+    }
+    let spans = Lexer::new(line).run();
+    let matched = if show_bracket {
+        matching_bracket(&spans, line, pos)
+    } else {
+        None
+    };
+
+    let mut result = String::with_capacity(line.len() * 2);
+    let mut offset = 0;
+
+    for span in &spans {
+        result.push_str(&line[offset..span.start]); // whitespace
+        let text = &line[span.start..span.end];
+
+        let colored = match span.style {
+            Style::Plain => text.normal(),
+            Style::Keyword => text.magenta().bold(),
+            Style::Builtin => text.green().bold(),
+            Style::Function => text.cyan().bold(),
+            Style::External => text.green(),
+            Style::Unknown => text.red(),
+            Style::Variable => text.cyan(),
+            Style::Str => text.yellow(),
+            Style::Operator => text.bright_blue(),
+            Style::Comment => text.bright_black(),
+            Style::Redirect => text.underline(),
+            Style::Bracket => {
+                if matched
+                    .is_some_and(|(open, close)| span.end - 1 == open || span.end - 1 == close)
+                {
+                    text.blue().bold()
+                } else {
+                    text.normal()
+                }
+            }
+        };
+        result.push_str(&colored.to_string());
+        offset = span.end;
+    }
+    result.push_str(&line[offset..]);
+    Cow::Owned(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Style::*;
+    use super::*;
+    use crate::eval::{Interp, Value};
+
+    fn styles(line: &str) -> Vec<(&str, Style)> {
+        Lexer::new(line)
+            .run()
+            .iter()
+            .map(|span| (&line[span.start..span.end], span.style))
+            .collect()
+    }
+
+    #[test]
+    fn test_keywords_variables_strings() {
+        assert_eq!(
+            styles("if ($x) (echo \"a $y[0]\") else (ls) # done"),
+            vec![
+                ("if", Keyword),
+                ("(", Bracket),
+                ("$x", Variable),
+                (")", Bracket),
+                ("(", Bracket),
+                ("echo", Builtin),
+                ("\"a ", Str),
+                ("$y[0]", Variable),
+                ("\"", Str),
+                (")", Bracket),
+                ("else", Keyword),
+                ("(", Bracket),
+                ("ls", Builtin),
+                (")", Bracket),
+                ("# done", Comment),
+            ]
+        );
+        // Unterminated strings do not fail
+        assert_eq!(styles("echo \"ab"), vec![("echo", Builtin), ("\"ab", Str)]);
+    }
+
+    #[test]
+    fn test_redirects() {
+        assert_eq!(
+            styles("echo hi => out.txt; cat =>> log.txt"),
+            vec![
+                ("echo", Builtin),
+                ("hi", Plain),
+                ("=>", Operator),
+                ("out.txt", Redirect),
+                (";", Operator),
+                ("cat", Builtin),
+                ("=>>", Operator),
+                ("log.txt", Redirect),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_commands() {
+        assert_eq!(
+            styles("sh -c true | t_no_such_cmd 1; x = t_no_such_cmd"),
+            vec![
+                ("sh", External),
+                ("-c", Plain),
+                ("true", Plain),
+                ("|", Operator),
+                ("t_no_such_cmd", Unknown),
+                ("1", Plain),
+                (";", Operator),
+                ("x", Plain),
+                ("=", Operator),
+                ("t_no_such_cmd", Plain),
+            ]
+        );
+        // Words that follow FOR and IN are not commands
+        assert_eq!(
+            styles("for i in ls; (echo $i)"),
+            vec![
+                ("for", Keyword),
+                ("i", Plain),
+                ("in", Keyword),
+                ("ls", Plain),
+                (";", Operator),
+                ("(", Bracket),
+                ("echo", Builtin),
+                ("$i", Variable),
+                (")", Bracket),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_no_color() {
+        let scope = Interp::new().get_scope();
+        scope.insert("NO_COLOR".to_string(), Value::Int(1));
+        let line = "if (1) (t_no_such_cmd \"x\")";
+        assert!(matches!(highlight(line, 0, true, &scope), Cow::Borrowed(s) if s == line));
+    }
+}
@@ -0,0 +1,205 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The directories where history entries were run, so that hints can prefer commands
+/// used in the current directory; loaded from the history log, see HistoryLog.
+pub struct DirHistory {
+    entries: Vec<(String, String)>, // (directory, command line), oldest first
+    max_size: usize,
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace('\t', "\\t")
+        .replace('\n', "\\n")
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('t') => result.push('\t'),
+                Some('n') => result.push('\n'),
+                Some(c) => result.push(c),
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+impl DirHistory {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            entries: Vec::new(),
+            max_size,
+        }
+    }
+
+    /// Record that the command line was run in the given directory.
+    pub fn add(&mut self, dir: &str, line: &str) {
+        // Keep only the most recent occurrence of a command in a directory
+        self.entries.retain(|(d, l)| d != dir || l != line);
+        self.entries.push((dir.to_string(), line.to_string()));
+
+        if self.entries.len() > self.max_size {
+            let excess = self.entries.len() - self.max_size;
+            self.entries.drain(..excess);
+        }
+    }
+
+    /// Find the most recent command line run in dir that starts with prefix.
+    pub fn find(&self, dir: &str, prefix: &str) -> Option<&str> {
+        self.entries
+            .iter()
+            .rev()
+            .find(|(d, l)| d == dir && l.starts_with(prefix) && l != prefix)
+            .map(|(_, l)| l.as_str())
+    }
+
+    /// Add the entries of the history log, oldest first.
+    pub fn load(&mut self, entries: &[Entry]) {
+        for entry in entries {
+            self.add(&entry.cwd, &entry.line);
+        }
+    }
+}
+
+/// Entries kept in the log file; older ones are dropped when a session starts.
+pub const MAX_LOG_SIZE: usize = 10000;
+
+/// The first line of the log holds the number of entries dropped so far,
+/// so that entries keep their numbers (see !N) when older ones are dropped.
+const DROPPED_HEADER: &str = "#dropped\t";
+
+// Log file of the interactive session, read by the history builtin
+static LOG_PATH: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// A command line, with where and when it ran, and how it went.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub time: i64, // Seconds since the epoch
+    pub cwd: String,
+    pub duration: Duration,
+    pub status: i32,
+    pub line: String,
+}
+
+impl Entry {
+    fn parse(text: &str) -> Option<Self> {
+        let mut fields = text.splitn(5, '\t');
+        Some(Self {
+            time: fields.next()?.parse().ok()?,
+            duration: Duration::from_millis(fields.next()?.parse().ok()?),
+            status: fields.next()?.parse().ok()?,
+            cwd: unescape(fields.next()?),
+            line: unescape(fields.next()?),
+        })
+    }
+
+    fn format(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\n",
+            self.time,
+            self.duration.as_millis(),
+            self.status,
+            escape(&self.cwd),
+            escape(&self.line)
+        )
+    }
+}
+
+/// The history log lives next to the rustyline history file, which only keeps
+/// the command lines. Each entry is appended to the file as a single write, and
+/// the file is locked while written, so that concurrent sessions can share the
+/// log without overwriting each other.
+pub struct HistoryLog {
+    path: Option<PathBuf>,
+}
+
+impl HistoryLog {
+    pub fn new() -> Self {
+        Self { path: None }
+    }
+
+    /// Append entries to the log file from now on, dropping the oldest ones if needed.
+    pub fn open(&mut self, path: &Path) -> io::Result<()> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .read(true)
+            .write(true)
+            .open(path)?;
+        file.lock()?; // Released when the file is closed
+
+        let (dropped, mut entries) = read_entries(&mut file)?;
+        if entries.len() > MAX_LOG_SIZE {
+            // Rewrite in place: other sessions append to the same file once they get the lock
+            let excess = entries.len() - MAX_LOG_SIZE;
+            entries.drain(..excess);
+            let mut text = format!("{}{}\n", DROPPED_HEADER, dropped + excess);
+            text.extend(entries.iter().map(Entry::format));
+            file.set_len(0)?;
+            file.rewind()?;
+            file.write_all(text.as_bytes())?;
+        }
+        self.path = Some(path.to_path_buf());
+        *LOG_PATH.lock().unwrap() = Some(path.to_path_buf());
+        Ok(())
+    }
+
+    pub fn add(&mut self, entry: Entry) -> io::Result<()> {
+        if let Some(path) = &self.path {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            file.lock()?;
+            file.write_all(entry.format().as_bytes())?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the number of entries dropped from the log, and the entries kept.
+fn read_entries(file: &mut File) -> io::Result<(usize, Vec<Entry>)> {
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+
+    let dropped = text
+        .lines()
+        .next()
+        .and_then(|line| line.strip_prefix(DROPPED_HEADER))
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(0);
+    let entries = text.lines().filter_map(Entry::parse).collect();
+    Ok((dropped, entries))
+}
+
+/// Read the log of the interactive session, including the entries added by
+/// other sessions. Returns no entries when not running interactively. The
+/// entries are numbered from the number of entries dropped from the log, plus 1.
+pub fn read_log() -> io::Result<(usize, Vec<Entry>)> {
+    let Some(path) = LOG_PATH.lock().unwrap().clone() else {
+        return Ok((0, Vec::new()));
+    };
+    if !path.exists() {
+        return Ok((0, Vec::new()));
+    }
+    let mut file = File::open(path)?;
+    file.lock_shared()?;
+    read_entries(&mut file)
+}
+
+/// Find the N-th entry of the log, as numbered by read_log.
+pub fn find_entry(n: usize) -> io::Result<Option<Entry>> {
+    let (dropped, mut entries) = read_log()?;
+    match n.checked_sub(dropped + 1) {
+        Some(i) if i < entries.len() => Ok(Some(entries.swap_remove(i))),
+        _ => Ok(None),
+    }
+}
@@ -0,0 +1,503 @@
+// Background jobs and job control. The external programs started by a command
+// or pipeline run in a process group of their own. When the shell is interactive,
+// the group of the foreground job is given the terminal, so that Ctrl+C and Ctrl+Z
+// reach the programs rather than the shell.
+use std::cell::RefCell;
+use std::io;
+use std::process::{Child, Command, ExitStatus};
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering::SeqCst};
+
+#[cfg(unix)]
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+#[cfg(unix)]
+use std::sync::{atomic::AtomicI32, Mutex};
+
+/// Is the shell in control of the terminal?
+static JOB_CONTROL: AtomicBool = AtomicBool::new(false);
+/// Process group of the foreground job, zero if the shell is in the foreground.
+static FOREGROUND: AtomicU32 = AtomicU32::new(0);
+
+#[cfg(unix)]
+static TERMINAL: AtomicI32 = AtomicI32::new(-1);
+#[cfg(unix)]
+static TERMINAL_MODES: Mutex<Option<libc::termios>> = Mutex::new(None);
+
+thread_local! {
+    static JOBS: RefCell<Vec<Job>> = const { RefCell::new(Vec::new()) };
+    /// The job being launched, if any.
+    static LAUNCH: RefCell<Option<Launch>> = const { RefCell::new(None) };
+}
+
+/// Command or pipeline being started.
+struct Launch {
+    command: String,
+    background: bool,
+    pgid: Option<u32>,
+    job: Option<usize>, // Job id, once stopped or sent to the background.
+}
+
+/// External program, or shell process, started by a job.
+struct Process {
+    pid: u32,
+    #[cfg_attr(unix, allow(dead_code))] // Waited for by pid on Unix
+    child: Child,
+    status: Option<ExitStatus>,
+    stopped: bool,
+}
+
+impl Process {
+    fn new(child: Child) -> Self {
+        Self {
+            pid: child.id(),
+            child,
+            status: None,
+            stopped: false,
+        }
+    }
+
+    /// Wait for the program to exit or stop; return None if it is still running.
+    #[cfg(unix)]
+    fn wait(&mut self, block: bool) -> io::Result<Option<ExitStatus>> {
+        let mut options = libc::WUNTRACED | libc::WCONTINUED;
+        if !block {
+            options |= libc::WNOHANG;
+        }
+        loop {
+            let mut raw = 0;
+            let pid = unsafe { libc::waitpid(self.pid as libc::pid_t, &mut raw, options) };
+            if pid < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            if pid == 0 {
+                return Ok(None);
+            }
+            if libc::WIFCONTINUED(raw) {
+                self.stopped = false;
+                continue;
+            }
+            let status = ExitStatus::from_raw(raw);
+            if libc::WIFSTOPPED(raw) {
+                self.stopped = true;
+            } else {
+                self.status = Some(status);
+            }
+            return Ok(Some(status));
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn wait(&mut self, block: bool) -> io::Result<Option<ExitStatus>> {
+        let child = &mut self.child;
+        let status = if block {
+            Some(child.wait()?)
+        } else {
+            child.try_wait()?
+        };
+        if status.is_some() {
+            self.status = status;
+        }
+        Ok(status)
+    }
+}
+
+pub struct Job {
+    pub id: usize,
+    pub command: String,
+    #[cfg_attr(not(unix), allow(dead_code))]
+    pgid: u32,
+    processes: Vec<Process>,
+}
+
+impl Job {
+    pub fn pids(&self) -> Vec<u32> {
+        self.processes.iter().map(|p| p.pid).collect()
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.processes.iter().all(|p| p.status.is_some())
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        !self.is_done() && self.processes.iter().any(|p| p.stopped)
+    }
+
+    /// The exit status of the last program in the job, once done.
+    pub fn status(&self) -> Option<ExitStatus> {
+        self.processes.last().and_then(|p| p.status)
+    }
+
+    pub fn state(&self) -> String {
+        if self.is_stopped() {
+            "Stopped".to_string()
+        } else if !self.is_done() {
+            "Running".to_string()
+        } else {
+            match self.status().and_then(|s| s.code()) {
+                Some(code) if code != 0 => format!("Exit {}", code),
+                _ => "Done".to_string(),
+            }
+        }
+    }
+
+    /// Check on the programs without blocking.
+    fn update(&mut self) -> io::Result<()> {
+        for process in self.processes.iter_mut() {
+            if process.status.is_none() {
+                process.wait(false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Wait for all programs in the job to exit, or for any of them to stop.
+    fn wait(&mut self) -> io::Result<Option<ExitStatus>> {
+        for process in self.processes.iter_mut() {
+            if process.status.is_none() {
+                process.wait(true)?;
+                if process.stopped {
+                    break;
+                }
+            }
+        }
+        Ok(self.status())
+    }
+
+    /// Send a signal to the process group of the job.
+    #[cfg(unix)]
+    pub fn signal(&mut self, sig: i32) -> Result<(), String> {
+        if unsafe { libc::kill(-(self.pgid as libc::pid_t), sig) } < 0 {
+            return Err(format!("[{}]: {}", self.id, io::Error::last_os_error()));
+        }
+        if sig == libc::SIGCONT {
+            self.processes.iter_mut().for_each(|p| p.stopped = false);
+        }
+        Ok(())
+    }
+
+    /// There are no signals other than kill outside of Unix.
+    #[cfg(not(unix))]
+    pub fn signal(&mut self, _sig: i32) -> Result<(), String> {
+        for process in self.processes.iter_mut() {
+            if process.status.is_none() {
+                process.child.kill().map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Continue the job if stopped.
+    fn resume(&mut self) -> Result<(), String> {
+        #[cfg(unix)]
+        return self.signal(libc::SIGCONT);
+        #[cfg(not(unix))]
+        Ok(())
+    }
+}
+
+/// Take control of the terminal. Called once, when the shell runs interactively.
+pub fn init() {
+    #[cfg(unix)]
+    unsafe {
+        if libc::isatty(libc::STDIN_FILENO) == 0 {
+            return;
+        }
+        // Put the shell in its own process group. Ignore the signals that stop
+        // it, when taking the terminal back from a job, or when Ctrl+Z is pressed.
+        libc::setpgid(0, 0);
+        for sig in [libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU] {
+            libc::signal(sig, libc::SIG_IGN);
+        }
+        // Keep a handle to the terminal that child processes can use before exec,
+        // after their own standard input is set up.
+        let fd = libc::fcntl(libc::STDIN_FILENO, libc::F_DUPFD_CLOEXEC, 10);
+        if fd < 0 {
+            return;
+        }
+        TERMINAL.store(fd, SeqCst);
+
+        let mut modes = std::mem::zeroed();
+        if libc::tcgetattr(fd, &mut modes) == 0 {
+            *TERMINAL_MODES.lock().unwrap() = Some(modes);
+        }
+        JOB_CONTROL.store(true, SeqCst);
+        set_foreground(None);
+    }
+}
+
+pub fn job_control() -> bool {
+    JOB_CONTROL.load(SeqCst)
+}
+
+/// Give the terminal to the process group of a job, or take it back if None.
+fn set_foreground(pgid: Option<u32>) {
+    if !job_control() {
+        return;
+    }
+    FOREGROUND.store(pgid.unwrap_or(0), SeqCst);
+
+    #[cfg(unix)]
+    unsafe {
+        let fd = TERMINAL.load(SeqCst);
+        match pgid {
+            Some(pgid) => {
+                libc::tcsetpgrp(fd, pgid as libc::pid_t);
+            }
+            None => {
+                libc::tcsetpgrp(fd, libc::getpgrp());
+                // Restore the terminal modes, in case the job has changed them.
+                if let Some(modes) = TERMINAL_MODES.lock().unwrap().as_ref() {
+                    libc::tcsetattr(fd, libc::TCSADRAIN, modes);
+                }
+            }
+        }
+    }
+}
+
+/// Forward an interrupt to the foreground job. Called from the Ctrl+C handler.
+#[cfg_attr(test, allow(dead_code))]
+pub fn interrupt() {
+    let pgid = FOREGROUND.load(SeqCst);
+    if pgid != 0 {
+        #[cfg(unix)]
+        unsafe {
+            libc::kill(-(pgid as libc::pid_t), libc::SIGINT);
+        }
+    }
+}
+
+/// Launch a command or pipeline as a job: the external programs it spawns
+/// share a process group, which is placed in the foreground, unless background.
+pub fn launch<R>(command: String, background: bool, f: impl FnOnce() -> R) -> R {
+    let launch = Launch {
+        command,
+        background,
+        pgid: None,
+        job: None,
+    };
+    let outer = LAUNCH.replace(Some(launch));
+    let result = f();
+    let launch = LAUNCH.replace(outer).unwrap();
+
+    if !background && launch.pgid.is_some() {
+        // Hand the terminal back to the enclosing job, if still in the foreground.
+        let pgid = LAUNCH.with_borrow(|outer| match outer {
+            Some(outer) if !outer.background && outer.job.is_none() => outer.pgid,
+            _ => None,
+        });
+        set_foreground(pgid);
+    }
+    result
+}
+
+/// Set up the process group of an external program that is about to be spawned.
+pub fn configure(command: &mut Command) {
+    let background = LAUNCH.with_borrow(|launch| match launch {
+        Some(launch) if job_control() || launch.background => Some(launch.background),
+        _ => None,
+    });
+
+    #[cfg(unix)]
+    if let Some(background) = background {
+        let pgid = LAUNCH.with_borrow(|launch| launch.as_ref().unwrap().pgid);
+        command.process_group(pgid.unwrap_or(0) as i32);
+
+        if job_control() {
+            let fd = TERMINAL.load(SeqCst);
+            unsafe {
+                command.pre_exec(move || {
+                    // Take the terminal in the child too, so that the program
+                    // does not get to read from it before the parent gives it.
+                    if !background {
+                        libc::tcsetpgrp(fd, libc::getpgrp());
+                    }
+                    for sig in [libc::SIGTSTP, libc::SIGTTIN, libc::SIGTTOU] {
+                        libc::signal(sig, libc::SIG_DFL);
+                    }
+                    Ok(())
+                });
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (command, background);
+}
+
+/// Record the process group of a program spawned by the job being launched.
+pub fn started(child: &Child) {
+    let foreground = LAUNCH.with_borrow_mut(|launch| match launch {
+        Some(launch) => {
+            let pgid = *launch.pgid.get_or_insert(child.id());
+            (!launch.background).then_some(pgid)
+        }
+        None => None,
+    });
+    if let Some(pgid) = foreground {
+        set_foreground(Some(pgid));
+    }
+}
+
+fn next_id(jobs: &[Job]) -> usize {
+    jobs.iter().map(|job| job.id).max().unwrap_or(0) + 1
+}
+
+/// Move programs of the job being launched to the job table; return the job id.
+fn add_to_job(processes: Vec<Process>) -> usize {
+    LAUNCH.with_borrow_mut(|launch| {
+        let launch = launch.as_mut().expect("no job being launched");
+        JOBS.with_borrow_mut(|jobs| {
+            if let Some(job) = jobs.iter_mut().find(|job| Some(job.id) == launch.job) {
+                job.processes.extend(processes);
+                return job.id;
+            }
+            let id = next_id(jobs);
+            jobs.push(Job {
+                id,
+                command: launch.command.clone(),
+                pgid: launch.pgid.unwrap_or_default(),
+                processes,
+            });
+            launch.job = Some(id);
+            id
+        })
+    })
+}
+
+/// Add the programs spawned by a background launch to the job table.
+pub fn background(children: Vec<Child>) -> usize {
+    add_background(children.into_iter().map(Process::new).collect())
+}
+
+fn add_background(processes: Vec<Process>) -> usize {
+    let pids = processes
+        .iter()
+        .map(|p| p.pid.to_string())
+        .collect::<Vec<_>>();
+    let id = add_to_job(processes);
+    if job_control() {
+        eprintln!("[{}] {}", id, pids.join(" "));
+    }
+    id
+}
+
+/// Wait for a program in the foreground. Under job control, the program may be
+/// stopped by Ctrl+Z instead of exiting, in which case it is moved to the job table.
+pub fn wait(mut child: Child) -> io::Result<ExitStatus> {
+    if !job_control() || LAUNCH.with_borrow(|launch| launch.is_none()) {
+        return child.wait();
+    }
+    let mut process = Process::new(child);
+    let status = process.wait(true)?.unwrap_or_default();
+    if process.stopped {
+        let (command, is_new) = LAUNCH.with_borrow(|launch| {
+            let launch = launch.as_ref().unwrap();
+            (launch.command.clone(), launch.job.is_none())
+        });
+        let id = add_to_job(vec![process]);
+        if is_new {
+            eprintln!("\n[{}]+  Stopped    {}", id, command);
+        }
+    }
+    Ok(status)
+}
+
+/// Bring a job to the foreground, continuing it if stopped, and wait for it.
+pub fn foreground(id: usize) -> Result<Option<ExitStatus>, String> {
+    let mut job = take(id)?;
+    set_foreground(Some(job.pgid));
+    let result = job
+        .resume()
+        .and_then(|_| job.wait().map_err(|e| e.to_string()));
+    set_foreground(None);
+
+    if job.is_stopped() {
+        eprintln!("\n[{}]+  Stopped    {}", job.id, job.command);
+        JOBS.with_borrow_mut(|jobs| jobs.push(job));
+    }
+    result
+}
+
+/// Continue a stopped job in the background.
+pub fn resume(id: usize) -> Result<(), String> {
+    with_job(id, |job| job.resume())
+}
+
+/// Wait for a job to complete, and remove it from the table.
+pub fn wait_job(id: usize) -> Result<Option<ExitStatus>, String> {
+    let mut job = take(id)?;
+    let result = job.wait().map_err(|e| e.to_string());
+    if !job.is_done() {
+        JOBS.with_borrow_mut(|jobs| jobs.push(job));
+    }
+    result
+}
+
+/// Apply a function to a job in the table.
+pub fn with_job<R>(id: usize, f: impl FnOnce(&mut Job) -> Result<R, String>) -> Result<R, String> {
+    JOBS.with_borrow_mut(|jobs| match jobs.iter_mut().find(|job| job.id == id) {
+        Some(job) => f(job),
+        None => Err(format!("%{}: no such job", id)),
+    })
+}
+
+fn take(id: usize) -> Result<Job, String> {
+    JOBS.with_borrow_mut(|jobs| match jobs.iter().position(|job| job.id == id) {
+        Some(i) => Ok(jobs.remove(i)),
+        None => Err(format!("%{}: no such job", id)),
+    })
+}
+
+/// Ids of the jobs in the table, most recent last.
+pub fn ids() -> Vec<usize> {
+    JOBS.with_borrow(|jobs| jobs.iter().map(|job| job.id).collect())
+}
+
+/// Parse a job specification: %N, or %% and %+ for the current (most recent) job.
+pub fn parse_spec(spec: &str) -> Result<usize, String> {
+    match spec {
+        "%" | "%%" | "%+" => ids().last().copied().ok_or("No current job".to_string()),
+        _ => spec
+            .strip_prefix('%')
+            .unwrap_or(spec)
+            .parse::<usize>()
+            .map_err(|_| format!("{}: invalid job specification", spec)),
+    }
+}
+
+/// Check on the jobs without blocking; call f with each of them, then remove
+/// the jobs that are done.
+pub fn update(mut f: impl FnMut(&Job, bool)) {
+    JOBS.with_borrow_mut(|jobs| {
+        let current = jobs.last().map(|job| job.id);
+        for job in jobs.iter_mut() {
+            if let Err(e) = job.update() {
+                // The programs can no longer be waited for; drop the job.
+                eprintln!("[{}]: {}", job.id, e);
+                for process in job.processes.iter_mut() {
+                    process.status.get_or_insert_with(ExitStatus::default);
+                }
+            }
+            f(job, Some(job.id) == current);
+        }
+        jobs.retain(|job| !job.is_done());
+    });
+}
+
+/// Report the background jobs that have completed since last checked.
+pub fn notify() {
+    update(|job, current| {
+        if job.is_done() {
+            let marker = if current { '+' } else { ' ' };
+            eprintln!(
+                "[{}]{}  {:<10} {}",
+                job.id,
+                marker,
+                job.state(),
+                job.command
+            );
+        }
+    });
+}
@@ -1,4 +1,3 @@
-
 #[macro_export]
 macro_rules! my_dbg {
     ($($arg:tt)*) => {
@@ -10,39 +9,39 @@ macro_rules! my_dbg {
     };
 }
 
-/// Write to stdout without panic.
+/// Write line to output stream without panic.
 /// More robust than built-in when redirect stdout to pipe.
 #[macro_export]
 macro_rules! my_println {
     // Version with arguments
-    ($($arg:tt)*) => {{
-        use std::io::Write;
+    ($out:expr, $($arg:tt)*) => {{
+        #[allow(unused_imports)]
+        use std::io::Write; // not needed for dyn Write
 
         // Create a formatted string
         let output = format!($($arg)*);
-        // Attempt to write to stdout
-        std::io::stdout().lock()
-            .write_all(output.as_bytes())
-            .and_then(|_| std::io::stdout().write_all(b"\n"))
+        // Attempt to write to the stream
+        $out.write_all(output.as_bytes())
+            .and_then(|_| $out.write_all(b"\n"))
             .map_err(|e| e.to_string())?;
 
         Ok(()) as Result<(), String>
     }};
 }
 
-/// Write to stdout without newline and without panic.
+/// Write to output stream without newline and without panic.
 /// More robust than built-in when redirect stdout to pipe.
 #[macro_export]
 macro_rules! my_print {
     // Version with arguments
-    ($($arg:tt)*) => {{
-        use std::io::Write;
+    ($out:expr, $($arg:tt)*) => {{
+        #[allow(unused_imports)]
+        use std::io::Write; // not needed for dyn Write
 
         // Create a formatted string
         let output = format!($($arg)*);
-        // Attempt to write to stdout
-        std::io::stdout().lock()
-            .write_all(output.as_bytes())
+        // Attempt to write to the stream
+        $out.write_all(output.as_bytes())
             .map_err(|e| e.to_string())?;
 
         Ok(()) as Result<(), String>
@@ -1,47 +1,62 @@
-use cmds::{get_command, list_registered_commands};
+use cmds::{
+    exit_code, get_alias, get_command, list_aliases, list_registered_commands, ArgType, Exec,
+    ShellCommand,
+};
+use colored::Colorize;
 use directories::UserDirs;
-use eval::{EvalError, Interp, Scope, KEYWORDS};
+use eval::{EvalError, Interp, Scope, KEYWORDS, SOURCE_COMMANDS};
+use history::{read_log, DirHistory, Entry, HistoryLog};
 use rustyline::completion::{self, FilenameCompleter};
 use rustyline::error::ReadlineError;
-use rustyline::highlight::MatchingBracketHighlighter;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
 use rustyline::history::{DefaultHistory, SearchDirection};
-use rustyline::{Context, Editor, Helper, Highlighter, Hinter, Validator};
+use rustyline::validate::{ValidationContext, ValidationResult, Validator};
+use rustyline::{
+    Cmd, ConditionalEventHandler, Context, Editor, Event, EventContext, EventHandler, Helper,
+    KeyEvent, RepeatCount,
+};
+use std::borrow::Cow;
+use std::cell::Cell;
 use std::env;
 use std::fs::{self, File};
 use std::io::{self, BufRead, BufReader, Cursor};
-use std::path::PathBuf;
+use std::path::{PathBuf, MAIN_SEPARATOR};
 use std::rc::Rc;
 use std::sync::atomic::{AtomicBool, Ordering::SeqCst};
+use std::time::{Duration, Instant};
 
 #[macro_use]
 mod macros;
 
 mod cmds;
 mod eval;
+mod highlight;
+mod history;
+mod jobs;
+mod pipe;
 mod prompt;
 mod testeval;
 
-#[derive(Helper, Highlighter, Hinter, Validator)]
+#[derive(Helper)]
 struct CmdLineHelper {
-    #[rustyline(Completer)]
     completer: FilenameCompleter,
-    #[rustyline(Highlighter)]
-    highlighter: MatchingBracketHighlighter,
+    show_bracket: Cell<bool>, // Highlight the matching bracket (while editing only)
     keywords: Vec<String>,
     scope: Rc<Scope>,
+    dir_history: DirHistory, // Where history entries ran, for hints
+    history_log: HistoryLog,
 }
 
 impl CmdLineHelper {
     fn new(scope: Rc<Scope>) -> Self {
-        let mut keywords = list_registered_commands(false);
-
-        keywords.extend(KEYWORDS.iter().map(|s| s.to_string()));
-
         Self {
             completer: FilenameCompleter::new(),
-            highlighter: MatchingBracketHighlighter::new(),
-            keywords,
+            show_bracket: Cell::new(false),
+            keywords: KEYWORDS.iter().map(|s| s.to_string()).collect(),
             scope: Rc::clone(&scope),
+            dir_history: DirHistory::new(HISTORY_SIZE),
+            history_log: HistoryLog::new(),
         }
     }
 
@@ -99,6 +114,37 @@ fn split_delim(line: &str) -> (String, String) {
     }
 }
 
+/// Look up a command by name, or by the alias that expands to it.
+fn resolve_command(name: &str) -> Option<ShellCommand> {
+    match get_alias(name) {
+        Some(expansion) => expansion.split_whitespace().next().and_then(get_command),
+        None => get_command(name),
+    }
+}
+
+/// Complete the flags of a builtin command; the help text of each flag is in the display.
+/// A lone dash lists all flags, a double dash matches long names.
+fn complete_flags(cmd: &ShellCommand, word: &str) -> Vec<completion::Pair> {
+    let Some(flags) = cmd.cli_flags() else {
+        return vec![];
+    };
+    flags
+        .flags()
+        .iter()
+        .filter_map(|flag| {
+            let repl = if word.starts_with("--") {
+                format!("--{}", flag.long)
+            } else {
+                format!("-{}", flag.short)
+            };
+            repl.starts_with(word).then(|| completion::Pair {
+                display: format!("-{}, --{:16} {}", flag.short, flag.long, flag.help),
+                replacement: format!("{} ", repl),
+            })
+        })
+        .collect()
+}
+
 impl completion::Completer for CmdLineHelper {
     type Candidate = completion::Pair;
 
@@ -130,6 +176,7 @@ impl completion::Completer for CmdLineHelper {
         let mut kw_pos = pos;
 
         let (head, tail) = split_delim(line);
+        let mut dirs_only = false;
 
         if tail.starts_with("~") {
             // TODO: revisit; this may conflict with the rustyline built-in TAB completion, which
@@ -153,11 +200,17 @@ impl completion::Completer for CmdLineHelper {
         } else {
             let tok = head.split_ascii_whitespace().next();
 
-            if tok.is_none() || tok.is_some_and(|tok| get_command(&tok).is_none()) {
+            if tok.is_none() || tok.is_some_and(|tok| resolve_command(tok).is_none()) {
                 // Expand keywords and commands if the line does not start with a command
                 kw_pos = 0;
 
-                for kw in &self.keywords {
+                // Commands are looked up on every completion, to pick up user-defined functions.
+                let mut candidates = list_registered_commands(false);
+                candidates.extend(list_aliases());
+                candidates.extend(SOURCE_COMMANDS.iter().map(|s| s.to_string()));
+                candidates.extend(self.keywords.iter().cloned());
+
+                for kw in &candidates {
                     if kw.to_lowercase().starts_with(&tail) {
                         let repl = format!("{}{} ", head, kw);
                         keywords.push(completion::Pair {
@@ -166,6 +219,25 @@ impl completion::Completer for CmdLineHelper {
                         });
                     }
                 }
+            } else if let Some(cmd) = tok.and_then(resolve_command) {
+                let word = &line[head.len()..];
+
+                if word.starts_with('-') {
+                    kw_pos -= word.len();
+                    keywords = complete_flags(&cmd, word);
+                } else if cmd.arg_type() == ArgType::Command {
+                    // Arguments are command names, e.g. help, which
+                    kw_pos = 0;
+                    for name in list_registered_commands(false) {
+                        if name.to_lowercase().starts_with(&tail) {
+                            keywords.push(completion::Pair {
+                                display: String::default(),
+                                replacement: format!("{}{} ", head, name),
+                            });
+                        }
+                    }
+                }
+                dirs_only = cmd.arg_type() == ArgType::Dir;
             }
         }
 
@@ -173,7 +245,10 @@ impl completion::Completer for CmdLineHelper {
             // Try the file completer next ...
             let completions = self.completer.complete(line, pos, ctx);
 
-            if let Ok((start, v)) = completions {
+            if let Ok((start, mut v)) = completions {
+                if dirs_only {
+                    v.retain(|candidate| candidate.replacement.ends_with(MAIN_SEPARATOR));
+                }
                 if !v.is_empty() {
                     // Replace unescaped \ with \\ in each completion's replacement
                     let escaped_completions: Vec<Self::Candidate> = v
@@ -194,20 +269,94 @@ impl completion::Completer for CmdLineHelper {
     }
 }
 
+impl Hinter for CmdLineHelper {
+    type Hint = String;
+
+    // Suggest the most recent history entry that starts with the input,
+    // preferring entries that were run in the current directory.
+    fn hint(&self, line: &str, pos: usize, ctx: &Context<'_>) -> Option<String> {
+        if line.is_empty() || pos < line.len() {
+            return None;
+        }
+        if let Some(entry) = current_dir()
+            .ok()
+            .and_then(|dir| self.dir_history.find(&dir, line))
+        {
+            return Some(entry[pos..].to_owned());
+        }
+        self.search_history(line, pos, ctx)
+    }
+}
+
+/// Accept the hint with Ctrl+F when the cursor is at the end of the line,
+/// same as the right arrow; otherwise move forward as usual.
+struct AcceptHint;
+
+impl ConditionalEventHandler for AcceptHint {
+    fn handle(&self, _: &Event, _: RepeatCount, _: bool, ctx: &EventContext) -> Option<Cmd> {
+        if ctx.has_hint() && ctx.pos() == ctx.line().len() {
+            Some(Cmd::CompleteHint)
+        } else {
+            None
+        }
+    }
+}
+
+impl Highlighter for CmdLineHelper {
+    fn highlight<'l>(&self, line: &'l str, pos: usize) -> Cow<'l, str> {
+        highlight::highlight(line, pos, self.show_bracket.get(), &self.scope)
+    }
+
+    fn highlight_hint<'h>(&self, hint: &'h str) -> Cow<'h, str> {
+        if self.scope.lookup("NO_COLOR").is_some() {
+            return Cow::Borrowed(hint);
+        }
+        Cow::Owned(hint.bright_black().to_string())
+    }
+
+    fn highlight_char(&self, _line: &str, _pos: usize, forced: bool) -> bool {
+        // Refresh on every key; forced is true when the line is accepted.
+        self.show_bracket.set(!forced);
+        true
+    }
+}
+
+impl Validator for CmdLineHelper {
+    // Keep editing, on a new line, while the parentheses, quotes or brackets
+    // in the input are unbalanced; the whole block is kept as one history entry.
+    fn validate(&self, ctx: &mut ValidationContext) -> rustyline::Result<ValidationResult> {
+        if Interp::with_scope(&self.scope).is_incomplete(ctx.input()) {
+            Ok(ValidationResult::Incomplete)
+        } else {
+            Ok(ValidationResult::Valid(None))
+        }
+    }
+}
+
 type CmdLineEditor = Editor<CmdLineHelper, DefaultHistory>;
 
+const HISTORY_SIZE: usize = 1024;
+
 struct Shell {
     source: Option<Box<dyn BufRead>>,
     interactive: bool,
     interp: Interp,
     home_dir: Option<PathBuf>,
     history_path: Option<PathBuf>,
+    rc_path: Option<PathBuf>, // Startup file given with --rc
+    no_rc: bool,
     edit_config: rustyline::config::Config,
     prompt: String,
+    last_status: i32,        // Status of the last evaluation, for the prompt
+    last_duration: Duration, // How long the last evaluation took
 }
 
 fn search_history<H: Helper>(rl: &Editor<H, DefaultHistory>, line: &str) -> Option<String> {
     let search = &line[1..];
+    // !N evaluates the N-th entry listed by the history command
+    if let Ok(n) = search.parse::<usize>() {
+        return history::find_entry(n).ok()?.map(|entry| entry.line);
+    }
     rl.history()
         .iter()
         .rev()
@@ -221,6 +370,7 @@ impl Shell {
         {
             ctrlc::set_handler(|| {
                 INTERRUPT.store(true, SeqCst);
+                jobs::interrupt();
             })
             .expect("Error setting Ctrl+C handler");
         }
@@ -231,20 +381,33 @@ impl Shell {
             interp,
             home_dir: None,
             history_path: None,
+            rc_path: None,
+            no_rc: false,
             edit_config: rustyline::Config::builder()
                 .edit_mode(rustyline::EditMode::Emacs)
                 .behavior(rustyline::Behavior::PreferTerm)
                 .history_ignore_dups(true)
                 .unwrap()
-                .max_history_size(1024)
+                .max_history_size(HISTORY_SIZE)
                 .unwrap()
                 .build(),
             prompt: String::default(),
+            last_status: 0,
+            last_duration: Duration::default(),
         }
     }
 
     fn prompt(&mut self) -> &str {
-        self.prompt = format!("{}> ", current_dir().unwrap());
+        let scope = self.interp.get_scope();
+        self.prompt = match scope.lookup_value("PROMPT") {
+            Some(template) => prompt::expand_prompt(
+                &template.to_string(),
+                &scope,
+                self.last_status,
+                self.last_duration,
+            ),
+            None => format!("{}> ", current_dir().unwrap()),
+        };
         &self.prompt
     }
 
@@ -286,8 +449,47 @@ impl Shell {
 
     fn save_history(&mut self, rl: &mut CmdLineEditor) -> Result<(), String> {
         let hist_path = self.get_history_path()?;
-        rl.save_history(&hist_path)
-            .map_err(|e| format!("Could not save {}: {}", hist_path.to_string_lossy(), e))
+        // Append rather than overwrite, to merge with concurrent sessions
+        rl.append_history(&hist_path)
+            .map_err(|e| format!("Could not save {}: {}", hist_path.to_string_lossy(), e))?;
+        Ok(())
+    }
+
+    // Add an entry to the history, and record the directory where it runs.
+    fn add_history_entry(rl: &mut CmdLineEditor, entry: &str) -> Result<(), String> {
+        rl.add_history_entry(entry).map_err(|e| e.to_string())?;
+        if let (Some(helper), Ok(dir)) = (rl.helper_mut(), current_dir()) {
+            helper.dir_history.add(&dir, entry);
+        }
+        Ok(())
+    }
+
+    // Evaluate the startup file, ~/.mysh/init.my unless overridden with --rc.
+    fn load_rc(&mut self) -> Result<(), String> {
+        if self.no_rc {
+            return Ok(());
+        }
+        let path = match self.rc_path.take() {
+            Some(path) => path,
+            None => {
+                let mut path = self.get_history_path()?.clone();
+                path.set_file_name("init.my");
+                if !path.exists() {
+                    return Ok(());
+                }
+                path
+            }
+        };
+        match fs::read_to_string(&path) {
+            Ok(input) => {
+                // Errors are reported, but do not prevent the session from starting.
+                if let Err(e) = self.interp.eval_file(&path.to_string_lossy(), &input) {
+                    self.show_error(&input, &e);
+                }
+            }
+            Err(e) => eprintln!("{}: {}", path.display(), e),
+        }
+        Ok(())
     }
 
     fn read_input(&mut self) -> Result<(), String> {
@@ -301,35 +503,49 @@ impl Shell {
     fn read_lines<R: BufRead>(&mut self, mut reader: R) -> Result<(), String> {
         let mut quit = false;
         if self.interactive {
+            jobs::init();
+
             // Set up rustyline
             let mut rl = CmdLineEditor::with_config(self.edit_config)
                 .map_err(|e| format!("Failed to create editor: {}", e))?;
-            let h = CmdLineHelper::new(self.interp.get_scope());
+            let mut h = CmdLineHelper::new(self.interp.get_scope());
+            let hist_path = self.get_history_path()?;
+            if let Err(e) = h
+                .history_log
+                .open(&hist_path.with_file_name("history_log.txt"))
+            {
+                eprintln!("Could not open history log: {}", e);
+            }
+            // The log records where the entries of all sessions ran
+            match read_log() {
+                Ok((_, entries)) => h.dir_history.load(&entries),
+                Err(e) => eprintln!("Could not load history directories: {}", e),
+            }
             rl.set_helper(Some(h));
             rl.load_history(&self.get_history_path()?).unwrap();
+            rl.bind_sequence(
+                KeyEvent::ctrl('F'),
+                EventHandler::Conditional(Box::new(AcceptHint)),
+            );
+
+            self.load_rc()?;
 
             while !quit {
                 // run interactive read-evaluate loop
+                jobs::notify();
                 let readline = rl.readline(self.prompt());
                 match readline {
                     Ok(line) => {
                         if line.starts_with("!") {
                             if let Some(history_entry) = search_history(&rl, &line) {
                                 eprintln!("{}", &history_entry);
-                                // Make the entry found in history the most recent
-                                rl.add_history_entry(&history_entry)
-                                    .map_err(|e| e.to_string())?;
-                                // Evaluate the line from history
-                                self.eval(&mut quit, &history_entry);
+                                // Make the entry found in history the most recent, and evaluate it
+                                self.eval_interactive(&mut rl, &mut quit, &history_entry)?;
                             } else {
                                 println!("No match.");
                             }
                         } else {
-                            rl.add_history_entry(line.as_str())
-                                .map_err(|e| e.to_string())?;
-
-                            self.save_history(&mut rl)?;
-                            self.eval(&mut quit, &line);
+                            self.eval_interactive(&mut rl, &mut quit, &line)?;
                         }
                     }
                     Err(ReadlineError::Interrupted) => {
@@ -356,7 +572,14 @@ impl Shell {
     fn eval(&mut self, quit: &mut bool, input: &String) {
         INTERRUPT.store(false, SeqCst);
 
-        match self.interp.eval(quit, input) {
+        let start = Instant::now();
+        let result = self.interp.eval(quit, input);
+        self.last_duration = start.elapsed();
+        self.last_status = result
+            .as_ref()
+            .map_or_else(|e| exit_code(&e.message), |_| 0);
+
+        match result {
             Ok(result) => {
                 my_dbg!(&result);
             }
@@ -369,6 +592,35 @@ impl Shell {
         }
     }
 
+    // Add the input to the history, evaluate it, and record the outcome in the history log.
+    fn eval_interactive(
+        &mut self,
+        rl: &mut CmdLineEditor,
+        quit: &mut bool,
+        input: &String,
+    ) -> Result<(), String> {
+        Self::add_history_entry(rl, input)?;
+        self.save_history(rl)?;
+
+        let time = chrono::Local::now().timestamp();
+        let cwd = current_dir().unwrap_or_default(); // Before evaluating, which may change it
+        self.eval(quit, input);
+
+        let entry = Entry {
+            time,
+            cwd,
+            duration: self.last_duration,
+            status: self.last_status,
+            line: input.clone(),
+        };
+        if let Some(helper) = rl.helper_mut() {
+            if let Err(e) = helper.history_log.add(entry) {
+                eprintln!("Could not update history log: {}", e);
+            }
+        }
+        Ok(())
+    }
+
     fn show_error(&self, input: &String, e: &EvalError) {
         e.show(input);
     }
@@ -385,25 +637,36 @@ fn parse_cmd_line() -> Result<Shell, String> {
     let mut shell = Shell::new(None, true, Interp::new());
 
     let args: Vec<String> = env::args().collect();
-    for (i, arg) in args.iter().enumerate().skip(1) {
+    let mut positional = vec![args[0].clone()];
+
+    let mut iter = args.iter().enumerate().skip(1);
+    while let Some((i, arg)) = iter.next() {
         if arg.starts_with("-") {
-            if arg == "-c" {
-                if !shell.interactive {
-                    Err("cannot specify -c command and scripts at the same time")?;
-                }
-                shell.source = Some(Box::new(Cursor::new(format!(
-                    "{}",
-                    args[i + 1..].join(" ")
-                ))));
+            if arg == "--norc" {
+                shell.no_rc = true;
+            } else if arg == "--rc" {
+                let (_, path) = iter.next().ok_or("--rc requires a file")?;
+                shell.rc_path = Some(PathBuf::from(path));
+            } else if arg == "-x" {
+                shell.interp.set_var("TRACE", "1".to_string());
+            } else if arg == "-c" {
+                // mysh -c COMMAND [ARGS...]
+                let cmd = args.get(i + 1).ok_or("-c requires a command")?;
+                shell.source = Some(Box::new(Cursor::new(cmd.clone())));
                 shell.interactive = false;
+                positional.extend_from_slice(&args[i + 2..]);
                 break;
             }
         } else {
+            // mysh SCRIPT [ARGS...], also used by #!/usr/bin/env mysh
             let file = File::open(&arg).map_err(|e| format!("{}: {}", arg, e))?;
             shell.source = Some(Box::new(BufReader::new(file)));
             shell.interactive = false;
+            positional = args[i..].to_vec();
+            break;
         }
     }
+    shell.interp.set_args(&positional);
 
     if shell.source.is_none() {
         shell.source = Some(Box::new(BufReader::new(io::stdin())));
@@ -0,0 +1,264 @@
+// In-process plumbing for pipelines and redirects: the output of the commands
+// evaluated by the interpreter goes to the innermost capture buffer, redirect or
+// pipe, and their input comes from the current pipeline stage, if any.
+use os_pipe::{PipeReader, PipeWriter};
+use std::cell::RefCell;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Cursor, IsTerminal, Read, Write};
+
+thread_local! {
+    /// Capture buffers and redirects; output goes to the process stdout when empty.
+    static OUTPUT: RefCell<Vec<Output>> = const { RefCell::new(Vec::new()) };
+    /// Inputs of the pipeline stages being evaluated, innermost last.
+    static INPUT: RefCell<Vec<Input>> = const { RefCell::new(Vec::new()) };
+    /// Capture buffers for diagnostics, e.g. traces; they go to stderr when empty.
+    static ERRORS: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Input stream of a command.
+pub enum Input {
+    /// The input of the current pipeline stage if any, stdin otherwise.
+    /// Resolved on first read, so that only commands that read consume it.
+    Inherit,
+    /// The standard input of the shell process.
+    Stdin,
+    /// In-memory data, e.g. the output of the previous pipeline stage.
+    Buffer(Cursor<Vec<u8>>),
+    /// The output of an external program running in the previous pipeline stage.
+    Pipe(PipeReader),
+}
+
+impl Input {
+    /// Input that has already been consumed.
+    pub fn empty() -> Self {
+        Input::Buffer(Cursor::default())
+    }
+
+    /// Resolve Inherit to the input of the current pipeline stage, or stdin.
+    pub fn resolve(&mut self) {
+        if let Input::Inherit = self {
+            *self = take_input().unwrap_or(Input::Stdin);
+        }
+    }
+
+    /// Take the remaining data, if reading from memory.
+    pub fn take_data(&mut self) -> Option<Vec<u8>> {
+        self.resolve();
+        match self {
+            Input::Buffer(cursor) => {
+                let pos = cursor.position() as usize;
+                let mut data = std::mem::take(cursor.get_mut());
+                data.drain(..pos.min(data.len()));
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn reader(&mut self) -> Box<dyn BufRead + '_> {
+        self.resolve();
+        match self {
+            Input::Buffer(cursor) => Box::new(cursor),
+            Input::Pipe(reader) => Box::new(BufReader::new(reader)),
+            _ => Box::new(io::stdin().lock()),
+        }
+    }
+}
+
+impl Read for Input {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.resolve();
+        match self {
+            Input::Buffer(cursor) => cursor.read(buf),
+            Input::Pipe(reader) => reader.read(buf),
+            _ => io::stdin().read(buf),
+        }
+    }
+}
+
+/// Output stream of a command.
+pub enum Output {
+    /// Write to the enclosing capture buffer or redirect, if any, or to stdout.
+    /// Never pushed onto the OUTPUT stack itself.
+    Inherit,
+    Stderr,
+    File(File),
+    Buffer(Vec<u8>),
+    /// The input of an external program running in the next pipeline stage.
+    Pipe(PipeWriter),
+    Null,
+}
+
+impl Output {
+    pub fn is_terminal(&self) -> bool {
+        match self {
+            Output::Inherit => !is_redirected() && io::stdout().is_terminal(),
+            Output::Stderr => io::stderr().is_terminal(),
+            _ => false,
+        }
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Output::Inherit => write_output(buf).map(|_| buf.len()),
+            Output::Stderr => io::stderr().write(buf),
+            Output::File(file) => file.write(buf),
+            Output::Buffer(buffer) => buffer.write(buf),
+            Output::Pipe(writer) => writer.write(buf),
+            Output::Null => Ok(buf.len()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Output::Inherit => Ok(()),
+            Output::Stderr => io::stderr().flush(),
+            Output::File(file) => file.flush(),
+            Output::Pipe(writer) => writer.flush(),
+            Output::Buffer(_) | Output::Null => Ok(()),
+        }
+    }
+}
+
+/// Input, output and error streams of a command invocation.
+pub struct Streams {
+    pub stdin: Input,
+    pub stdout: Output,
+    pub stderr: Output,
+}
+
+/// Call f, capturing all output written with write_output in the meantime.
+pub fn capture<R, F: FnOnce() -> R>(f: F) -> (R, Vec<u8>) {
+    let (result, output) = redirect(Output::Buffer(Vec::new()), f);
+    match output {
+        Output::Buffer(buffer) => (result, buffer),
+        _ => (result, Vec::new()),
+    }
+}
+
+/// Call f, sending all output written with write_output to the given stream.
+pub fn redirect<R, F: FnOnce() -> R>(output: Output, f: F) -> (R, Output) {
+    OUTPUT.with(|stack| stack.borrow_mut().push(output));
+    let result = f();
+    let output = OUTPUT.with(|stack| stack.borrow_mut().pop().unwrap_or(Output::Null));
+    (result, output)
+}
+
+/// Call f with the commands it evaluates reading from and writing to streams.
+pub fn with_streams<R, F: FnOnce() -> R>(streams: &mut Streams, f: F) -> R {
+    let input = match streams.stdin {
+        Input::Inherit => None, // the commands in f take it when they read
+        _ => Some(std::mem::replace(&mut streams.stdin, Input::empty())),
+    };
+    let output = std::mem::replace(&mut streams.stdout, Output::Inherit);
+
+    let eval = || match output {
+        Output::Inherit => f(),
+        output => {
+            let (result, output) = redirect(output, f);
+            streams.stdout = output;
+            result
+        }
+    };
+
+    match input {
+        Some(input) => with_input(input, eval),
+        None => eval(),
+    }
+}
+
+/// Call f with the input of commands fed from the given stream rather than from stdin.
+pub fn with_input<R, F: FnOnce() -> R>(stream: Input, f: F) -> R {
+    INPUT.with(|input| input.borrow_mut().push(stream));
+    let result = f();
+    INPUT.with(|input| input.borrow_mut().pop());
+    result
+}
+
+/// Is output currently going to a memory buffer or file?
+pub fn is_redirected() -> bool {
+    OUTPUT.with(|stack| !stack.borrow().is_empty())
+}
+
+/// Is output currently going to a memory buffer?
+pub fn is_capturing() -> bool {
+    OUTPUT.with(|stack| matches!(stack.borrow().last(), Some(Output::Buffer(_))))
+}
+
+/// Duplicate the file of the innermost redirect, if any (used for child processes).
+pub fn redirect_file() -> Option<io::Result<File>> {
+    OUTPUT.with(|stack| match stack.borrow().last() {
+        Some(Output::File(file)) => Some(file.try_clone()),
+        _ => None,
+    })
+}
+
+/// Duplicate the innermost redirect, if any, for writing from another thread.
+/// Capture buffers are per thread, and cannot be shared: None if capturing.
+pub fn share_output() -> Option<io::Result<Output>> {
+    OUTPUT.with(|stack| match stack.borrow().last() {
+        None => Some(Ok(Output::Inherit)), // the process stdout
+        Some(Output::File(file)) => Some(file.try_clone().map(Output::File)),
+        Some(Output::Pipe(writer)) => Some(writer.try_clone().map(Output::Pipe)),
+        Some(Output::Stderr) => Some(Ok(Output::Stderr)),
+        Some(Output::Null) => Some(Ok(Output::Null)),
+        Some(Output::Buffer(_) | Output::Inherit) => None,
+    })
+}
+
+/// Write to the innermost capture buffer or redirect, or to stdout if none.
+pub fn write_output(buf: &[u8]) -> io::Result<()> {
+    let written = OUTPUT.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        stack.last_mut().map(|output| output.write_all(buf))
+    });
+
+    match written {
+        Some(result) => result,
+        None => io::stdout().lock().write_all(buf),
+    }
+}
+
+/// Call f, capturing all diagnostics written with write_error in the meantime.
+#[cfg_attr(not(test), allow(dead_code))]
+pub fn capture_errors<R, F: FnOnce() -> R>(f: F) -> (R, Vec<u8>) {
+    ERRORS.with(|stack| stack.borrow_mut().push(Vec::new()));
+    let result = f();
+    let errors = ERRORS.with(|stack| stack.borrow_mut().pop().unwrap_or_default());
+    (result, errors)
+}
+
+/// Write diagnostics to the innermost error capture buffer, or to stderr if none.
+pub fn write_error(buf: &[u8]) -> io::Result<()> {
+    let captured = ERRORS.with(|stack| {
+        let mut stack = stack.borrow_mut();
+        stack.last_mut().map(|errors| errors.extend_from_slice(buf))
+    });
+
+    match captured {
+        Some(()) => Ok(()),
+        None => io::stderr().lock().write_all(buf),
+    }
+}
+
+/// Take the input of the current pipeline stage, if any. The input is consumed
+/// by the first reader, subsequent readers in the same stage get empty input.
+pub fn take_input() -> Option<Input> {
+    INPUT.with(|input| {
+        let mut input = input.borrow_mut();
+        input
+            .last_mut()
+            .map(|stream| std::mem::replace(stream, Input::empty()))
+    })
+}
+
+/// Input for the interpreter itself: pipeline input if any, stdin otherwise.
+pub fn stdin() -> Box<dyn BufRead> {
+    match take_input() {
+        Some(Input::Buffer(cursor)) => Box::new(cursor),
+        Some(Input::Pipe(reader)) => Box::new(BufReader::new(reader)),
+        _ => Box::new(io::stdin().lock()),
+    }
+}
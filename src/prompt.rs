@@ -1,7 +1,12 @@
+use crate::cmds::dir_stack_depth;
 use crate::eval::Scope;
-use colored::Colorize;
+use colored::{ColoredString, Colorize};
+use std::env;
+use std::fs;
 use std::io::{self, Write};
+use std::path::{Path, MAIN_SEPARATOR};
 use std::rc::Rc;
+use std::time::Duration;
 
 #[derive(PartialEq)]
 pub enum Answer {
@@ -56,3 +61,175 @@ pub fn confirm(prompt: String, scope: &Rc<Scope>, many: bool) -> io::Result<Answ
     }
     Ok(Answer::No)
 }
+
+/// Expand the escapes in the PROMPT variable:
+/// %w working directory, with ~ for $HOME    %W last component of the working directory
+/// %u user    %h host    %t time (HH:MM:SS)  %g git branch
+/// %? exit status of the last command         %D duration of the last command
+/// %p depth of the pushd directory stack     %% percent sign
+/// %{COLOR} switch to COLOR (black, red, green, yellow, blue, magenta, cyan, white, bold, reset)
+pub fn expand_prompt(template: &str, scope: &Rc<Scope>, status: i32, duration: Duration) -> String {
+    let use_colors = scope.lookup("NO_COLOR").is_none();
+    let mut colors: Vec<String> = Vec::new(); // In effect since the last reset
+    let mut text = String::new(); // Expanded since the last color switch
+    let mut result = String::new();
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            text.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('w') => text.push_str(&working_dir(scope)),
+            Some('W') => text.push_str(&last_component(&working_dir(scope))),
+            Some('u') => text.push_str(&user_name(scope)),
+            Some('h') => text.push_str(&host_name()),
+            Some('t') => text.push_str(&chrono::Local::now().format("%H:%M:%S").to_string()),
+            Some('g') => text.push_str(&current_git_branch().unwrap_or_default()),
+            Some('?') => text.push_str(&status.to_string()),
+            Some('D') => text.push_str(&format_duration(duration)),
+            Some('p') => text.push_str(&dir_stack_depth().to_string()),
+            Some('{') => {
+                let color: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                if use_colors && paint("", &color).is_some() {
+                    result.push_str(&paint_all(&std::mem::take(&mut text), &colors));
+                    if color == "reset" {
+                        colors.clear();
+                    } else {
+                        colors.push(color);
+                    }
+                }
+            }
+            Some(c) => {
+                // Unknown escapes, and %%, are kept verbatim, minus the percent sign
+                text.push(c);
+            }
+            None => text.push('%'),
+        }
+    }
+    result.push_str(&paint_all(&text, &colors));
+    result
+}
+
+/// Apply the colors in order, so that e.g. %{bold}%{red} is bold red.
+fn paint_all(text: &str, colors: &[String]) -> String {
+    if text.is_empty() {
+        return String::new();
+    }
+    let painted = colors
+        .iter()
+        .fold(text.normal(), |text, color| paint(text, color).unwrap());
+    painted.to_string()
+}
+
+fn paint<S: Colorize>(text: S, color: &str) -> Option<ColoredString> {
+    match color {
+        "black" => Some(text.black()),
+        "red" => Some(text.red()),
+        "green" => Some(text.green()),
+        "yellow" => Some(text.yellow()),
+        "blue" => Some(text.blue()),
+        "magenta" => Some(text.magenta()),
+        "cyan" => Some(text.cyan()),
+        "white" => Some(text.white()),
+        "bold" => Some(text.bold()),
+        "reset" => Some(text.normal()),
+        _ => None,
+    }
+}
+
+fn working_dir(scope: &Rc<Scope>) -> String {
+    let cwd = crate::current_dir().unwrap_or_default();
+
+    if let Some(home) = scope.lookup_value("HOME").map(|v| v.to_string()) {
+        if !home.is_empty() {
+            if let Some(rest) = cwd.strip_prefix(&home) {
+                if rest.is_empty() || rest.starts_with(MAIN_SEPARATOR) {
+                    return format!("~{}", rest);
+                }
+            }
+        }
+    }
+    cwd
+}
+
+fn last_component(path: &str) -> String {
+    match path
+        .trim_end_matches(MAIN_SEPARATOR)
+        .rsplit(MAIN_SEPARATOR)
+        .next()
+    {
+        Some(name) if !name.is_empty() => name.to_string(),
+        _ => path.to_string(),
+    }
+}
+
+fn user_name(scope: &Rc<Scope>) -> String {
+    scope
+        .lookup_value("USER")
+        .or_else(|| scope.lookup_value("USERNAME"))
+        .map(|v| v.to_string())
+        .unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn host_name() -> String {
+    let mut buf = [0u8; 256];
+    let ret = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+    if ret != 0 {
+        return String::default();
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    let host = String::from_utf8_lossy(&buf[..len]);
+    host.split('.').next().unwrap_or_default().to_string()
+}
+
+#[cfg(windows)]
+fn host_name() -> String {
+    env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+pub fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else if secs > 0 {
+        format!("{:.2}s", duration.as_secs_f64())
+    } else {
+        format!("{}ms", duration.as_millis())
+    }
+}
+
+fn current_git_branch() -> Option<String> {
+    git_branch(&env::current_dir().ok()?)
+}
+
+/// Find the git branch by looking for .git/HEAD in the directory and its
+/// ancestors. Detached heads show as abbreviated hashes.
+pub fn git_branch(dir: &Path) -> Option<String> {
+    let mut dir = dir.to_path_buf();
+    loop {
+        let git = dir.join(".git");
+        let head_path = if git.is_dir() {
+            git.join("HEAD")
+        } else if git.is_file() {
+            // Worktrees and submodules: .git is a file pointing to the git directory
+            let content = fs::read_to_string(&git).ok()?;
+            dir.join(content.strip_prefix("gitdir:")?.trim())
+                .join("HEAD")
+        } else {
+            if !dir.pop() {
+                return None;
+            }
+            continue;
+        };
+        let head = fs::read_to_string(head_path).ok()?;
+        let head = head.trim();
+
+        return match head.strip_prefix("ref: refs/heads/") {
+            Some(branch) => Some(branch.to_string()),
+            None => Some(head.chars().take(7).collect()),
+        };
+    }
+}
@@ -1,6 +1,7 @@
 #[cfg(test)]
 mod tests {
     use crate::eval::*;
+    use crate::pipe;
     use std::{io, str::FromStr};
 
     fn eval(input: &str) -> EvalResult<Value> {
@@ -260,6 +261,272 @@ mod tests {
         assert_eval_ok!("1 - 2 * 2 + 3", Value::Int(0));
     }
 
+    #[test]
+    fn test_int_div_mod() {
+        assert_eval_ok!("7 // 2", Value::Int(3));
+        assert_eval_ok!("-7 // 2", Value::Int(-4));
+        assert_eval_ok!("-7 % 3", Value::Int(2));
+        assert_eval_ok!("7.5 % 2", Value::Real(1.5));
+        assert_eval_err!("7 // 0", "Division by zero");
+        assert_eval_err!("7 % 0", "Division by zero");
+    }
+
+    #[test]
+    fn test_bitwise() {
+        assert_eval_ok!("0xff & 0o17", Value::Int(15));
+        assert_eval_ok!("0b1010 | 1", Value::Int(11));
+        assert_eval_ok!("6 ^ 3", Value::Int(5));
+        assert_eval_ok!("1 << 10", Value::Int(1024));
+        assert_eval_ok!("x = 0o755; $x >> 6", Value::Int(7));
+        assert_eval_ok!("(1 + 2) | 4", Value::Int(7));
+        assert_eval_err!("1.5 & 1", "Operator & expects integers");
+        assert_eval_err!("1 << 64", "Invalid shift: 64");
+        // Precedence: | lowest, then ^, &, shifts, and + - above them
+        assert_eval_ok!("6 & 3 | 8", Value::Int(10));
+        assert_eval_ok!("1 | 2 ^ 3", Value::Int(1));
+        assert_eval_ok!("1 << 2 + 3", Value::Int(32));
+        assert_eval_ok!("5000 + 4095 & -4096", Value::Int(8192));
+        // Radix prefixes apply to operands only, not to command arguments or output
+        assert_eval_ok!(
+            "echo 0x10 0b1 | x; $x",
+            Value::from_str("0x10 0b1").unwrap()
+        );
+        assert_eval_ok!("/bin/echo 0x10 | x; $x", Value::from_str("0x10").unwrap());
+    }
+
+    #[test]
+    fn test_regex_match() {
+        assert_eval_ok!(
+            "x = \"ERROR 42 disk\"; if ($x =~ \"^ERROR (\\\\d+)\") ($__match1 + 1)",
+            Value::Int(43)
+        );
+        assert_eval_ok!("x = abc; $x =~ ^b", Value::Int(0));
+        assert_eval_ok!(
+            "x = abc; $x =~ \"(a)(b)\"; $x =~ c; $__match + $__match1",
+            Value::from_str("c").unwrap()
+        );
+        assert_eval_err!(
+            "x = abc; $x =~ \"(\"",
+            "Invalid regex: regex parse error:\n    (\n    ^\nerror: unclosed group"
+        );
+    }
+
+    #[test]
+    fn test_match() {
+        assert_eval_ok!(
+            "x = main.rs; match $x (*.py => (1); *.rs => (2); _ => (3))",
+            Value::Int(2)
+        );
+        assert_eval_ok!("x = b; match $x (a => (1); [b-c] => (2))", Value::Int(2));
+        assert_eval_ok!("x = zz; match $x (\"a\" => (1); _ => (3))", Value::Int(3));
+        assert_eval_ok!(
+            "x = v42; match $x (=~ \"^v(\\\\d+)\" => ($__match1 + 1); _ => (0))",
+            Value::Int(43)
+        );
+        assert_eval_ok!(
+            "x = 2; y = match $x (1 => (10); 2 => (20)); $y",
+            Value::Int(20)
+        );
+        assert_eval_err!("match 1 (echo)", "Expecting PATTERN => (BODY) in MATCH arm");
+        assert_eval_err!("match 1 echo", "Parentheses are required around MATCH arms");
+    }
+
+    #[test]
+    fn test_try() {
+        assert_eval_ok!(
+            "try (cp; x = 1) catch e ($e[cmd])",
+            Value::from_str("cp").unwrap()
+        );
+        assert_eval_ok!("try (echo; cp) catch e ($e[line])", Value::Int(1));
+        assert_eval_ok!("e = 1; try (cp) catch e ($e[message]); $e", Value::Int(1));
+        assert_eval_ok!(
+            "try (cp) catch t_err (x = 0); $t_err",
+            Value::from_str("").unwrap()
+        );
+        assert_eval_ok!(
+            "try (cp) catch e (\"[$e[file]]\")",
+            Value::from_str("[]").unwrap()
+        );
+        assert_eval_ok!("try (1 / 0) catch (2)", Value::Int(2));
+        assert_eval_ok!(
+            "x = 0; try (1 / 0) catch (2) finally ($x = 3); $x",
+            Value::Int(3)
+        );
+        assert_eval_ok!(
+            "x = 0; (for i in 1 2 3; (try (if ($i == 2) (break)) finally ($x = $x + $i))); $x",
+            Value::Int(3)
+        );
+        assert_eval_err!("x = 0; try (1 / 0) finally (x = 1)", "Division by zero");
+        assert_eval_err!("try (1 / 0) catch (1 / 0) finally ()", "Division by zero");
+        assert_eval_err!("try (1)", "Expecting CATCH or FINALLY");
+        assert_eval_err!("try (1) finally (2) catch (3)", "Unexpected CATCH");
+        assert_eval_err!("catch (1)", "CATCH without TRY");
+    }
+
+    #[test]
+    fn test_args() {
+        let mut interp = Interp::new();
+        let args = ["deploy.my", "prod", "41"].map(String::from);
+        interp.set_args(&args);
+        let mut quit = false;
+
+        let result = interp.eval(&mut quit, "$2 + $__argc - 1");
+        assert!(matches!(result, Ok(Value::Int(42))));
+        let result = interp.eval(&mut quit, "\"$0 $1\"");
+        assert_eq!(result.unwrap(), Value::from_str("deploy.my prod").unwrap());
+        let result = interp.eval(&mut quit, "$__args[0]");
+        assert_eq!(result.unwrap(), Value::from_str("prod").unwrap());
+    }
+
+    #[test]
+    fn test_source() {
+        let dir = std::env::temp_dir();
+        let lib = dir.join("mysh_test_lib.my");
+        std::fs::write(&lib, "x = 40;\ndef add (a b) ($a + $b)").unwrap();
+        let bad = dir.join("mysh_test_bad.my");
+        std::fs::write(&bad, "x = 1;\n\ny = 1 / 0").unwrap();
+        let (lib, bad) = (lib.to_string_lossy(), bad.to_string_lossy());
+
+        let interp = Interp::new();
+        let mut quit = false;
+
+        let input = format!("source {}; $x + 2", lib);
+        assert!(matches!(interp.eval(&mut quit, &input), Ok(Value::Int(42))));
+
+        let input = format!(". {}; add 1 $x", lib);
+        assert!(matches!(interp.eval(&mut quit, &input), Ok(Value::Int(41))));
+
+        let input = format!("t_lib = {}; source $t_lib; add $x 2", lib);
+        assert!(matches!(interp.eval(&mut quit, &input), Ok(Value::Int(42))));
+
+        let input = format!("try (source {}) catch e (\"$e[file] $e[line]\")", bad);
+        let expected = format!("{} 3", bad);
+        let result = interp.eval(&mut quit, &input);
+        assert_eq!(result.unwrap(), Value::from_str(&expected).unwrap());
+
+        let input = format!("x = 0; source {}", bad);
+        let err = interp.eval(&mut quit, &input).unwrap_err();
+        assert_eq!((err.loc.file, err.loc.line), (Some(&*bad), 3));
+        assert_eq!(err.message, "Division by zero");
+
+        // Later errors with the same message are located in the input
+        let err = interp.eval(&mut quit, "y = 1 / 0").unwrap_err();
+        assert_eq!((err.loc.file, err.loc.line), (None, 1));
+
+        assert_eval_err!("source", "Missing FILE argument");
+        assert_eval_err!("source a b", "Too many arguments");
+    }
+
+    #[test]
+    fn test_dir_history() {
+        use crate::history::{DirHistory, Entry};
+        use std::time::Duration;
+
+        let mut history = DirHistory::new(3);
+        history.add("/a", "echo in a");
+        history.add("/b", "echo in b");
+        history.add("/a", "echo\tmulti\nline");
+        assert_eq!(history.find("/a", "echo"), Some("echo\tmulti\nline"));
+        assert_eq!(history.find("/b", "echo"), Some("echo in b"));
+        assert_eq!(history.find("/b", "echo in b"), None);
+        assert_eq!(history.find("/c", "echo"), None);
+
+        // Loaded from the entries of the history log, of all sessions
+        let entry = |cwd: &str, line: &str| Entry {
+            time: 0,
+            cwd: cwd.to_string(),
+            duration: Duration::ZERO,
+            status: 0,
+            line: line.to_string(),
+        };
+        let mut loaded = DirHistory::new(3);
+        loaded.load(&[
+            entry("/a", "echo in a"),
+            entry("/b", "echo in b"),
+            entry("/a", "echo\tmulti\nline"),
+            entry("/b", "ls"),
+        ]);
+        assert_eq!(loaded.find("/a", "echo"), Some("echo\tmulti\nline"));
+        assert_eq!(loaded.find("/a", "echo in"), None); // Dropped, over max size
+    }
+
+    #[test]
+    fn test_history() {
+        use crate::history::{find_entry, read_log, Entry, HistoryLog, MAX_LOG_SIZE};
+        use std::time::Duration;
+
+        let dir = std::env::temp_dir().join(format!("mysh_test_history_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("history_log.txt");
+        let mut log = HistoryLog::new();
+        log.open(&path).unwrap();
+
+        let entry = |cwd: &str, line: &str, status| Entry {
+            time: 0,
+            cwd: cwd.to_string(),
+            duration: Duration::from_millis(5),
+            status,
+            line: line.to_string(),
+        };
+        log.add(entry("/a", "echo in a", 0)).unwrap();
+        log.add(entry("/b", "echo\tmulti\nline", 1)).unwrap();
+        log.add(entry("/a", "ls", 0)).unwrap();
+
+        // Entries are appended to the file as they are added
+        let (_, entries) = read_log().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[1], entry("/b", "echo\tmulti\nline", 1));
+
+        let interp = Interp::new();
+        let mut quit = false;
+        let result = interp.eval(&mut quit, "history --failed | x; $x").unwrap();
+        assert!(result.to_string().starts_with("2  "));
+        assert!(result.to_string().contains("  1      5ms  /b  echo"));
+        let result = interp
+            .eval(&mut quit, "history -n 1 -g echo | x; $x")
+            .unwrap();
+        assert!(result.to_string().starts_with("2  "));
+        assert_eval_err!("history -n x", "Invalid number of entries: x");
+
+        // Entries keep their numbers when the oldest ones are dropped
+        let text: String = (0..MAX_LOG_SIZE + 2)
+            .map(|i| format!("0\t5\t{}\t/a\techo {}\n", i % 2, i))
+            .collect();
+        std::fs::write(&path, text).unwrap();
+        log.open(&path).unwrap();
+        let (dropped, entries) = read_log().unwrap();
+        assert_eq!((dropped, entries.len()), (2, MAX_LOG_SIZE));
+        assert_eq!(find_entry(2).unwrap(), None);
+        assert_eq!(find_entry(3).unwrap().unwrap().line, "echo 2");
+        log.add(entry("/a", "ls", 3)).unwrap();
+        log.open(&path).unwrap();
+        assert_eq!(find_entry(3).unwrap(), None);
+        assert_eq!(find_entry(4).unwrap().unwrap().line, "echo 3");
+        let result = interp.eval(&mut quit, "history -n 1 | x; $x").unwrap();
+        assert!(result
+            .to_string()
+            .starts_with(&format!("{}  ", MAX_LOG_SIZE + 3)));
+        assert!(result.to_string().contains("  3      5ms  /a  ls"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_incomplete() {
+        let interp = Interp::new();
+        assert!(interp.is_incomplete("while ($i > 0) ("));
+        assert!(interp.is_incomplete("echo \"abc"));
+        assert!(interp.is_incomplete("x = [1 2"));
+        assert!(interp.is_incomplete("echo $(ls"));
+        assert!(!interp.is_incomplete("while ($i > 0) (echo $i)"));
+        assert!(!interp.is_incomplete("echo \"(\" # ("));
+        assert!(!interp.is_incomplete("echo )"));
+        assert!(!interp.is_incomplete("ls [a-c"));
+        assert!(!interp.is_incomplete("[ -d /tmp"));
+        assert!(interp.is_incomplete("for x in [1 2"));
+        assert!(interp.is_incomplete("if (ls [a-c) ("));
+    }
+
     #[test]
     fn test_error() {
         assert_eval_ok!(
@@ -320,6 +587,205 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_def() {
+        assert_eval_ok!("def t_add (a b) ($a + $b); t_add 2 3", Value::Int(5));
+        assert_eval_ok!(
+            "def t_greet (name) (echo Hello $name); t_greet World | x; $x",
+            Value::from_str("Hello World").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_def_scope() {
+        assert_eval_ok!(
+            "x = 1; def t_scope (y) (x = $y); t_scope 2; $x",
+            Value::Int(1)
+        );
+    }
+
+    #[test]
+    fn test_def_recursion() {
+        assert_eval_ok!(
+            "def t_count (n) (if ($n > 0) (echo $n; t_count ($n - 1))); t_count 3 | x; $x",
+            Value::from_str("3\n2\n1").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_def_eval() {
+        // Functions are defined when the DEF is evaluated, not when parsed
+        assert_eval_err!(
+            "t_later; def t_later () (echo hi)",
+            "t_later: command not found"
+        );
+        assert_eval_err!(
+            "if (0) (def t_dead () (echo defined)); t_dead",
+            "t_dead: command not found"
+        );
+        assert_eval_ok!(
+            "def t_ping (n) (if ($n > 0) (t_pong ($n - 1)) else (echo done)); def t_pong (n) (t_ping $n); t_ping 3 | x; $x",
+            Value::from_str("done").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_alias() {
+        assert_eval_ok!(
+            "alias t_hello = \"echo Hello\"; t_hello World | x; $x",
+            Value::from_str("Hello World").unwrap()
+        );
+        // The expansion is not expanded again
+        assert_eval_ok!(
+            "def t_wc (a) (echo $a); alias t_wc = \"t_wc wrapped\"; t_wc | x; $x",
+            Value::from_str("wrapped").unwrap()
+        );
+        assert_eval_ok!(
+            "alias t_two = \"echo a; echo b\"; (t_two) | x; $x",
+            Value::from_str("a\nb").unwrap()
+        );
+        assert_eval_ok!(
+            "alias t_list = \"echo listed\"; alias t_list | x; $x",
+            Value::from_str("alias t_list = \"echo listed\"").unwrap()
+        );
+        assert_eval_err!("unalias t_none", "t_none: alias not found");
+        // Aliases are defined when ALIAS runs, not when parsed
+        assert_eval_err!(
+            "if (0) (alias t_leak = \"echo leaked\"); t_leak hi",
+            "t_leak: command not found"
+        );
+        assert_eval_err!(
+            "def t_def () (alias t_leak = \"echo leaked\"); t_leak hi",
+            "t_leak: command not found"
+        );
+        assert_eval_err!("alias t_leak", "t_leak: alias not found");
+        // Aliases are global, do not leak them into other tests
+        assert_eval_ok!(
+            "unalias t_hello t_wc t_two t_list; alias | grep t_ | x; $x",
+            Value::from_str("").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_export() {
+        assert_eval_ok!(
+            "export t_exp = 1; t_local = 2; export | grep \"^t_\" | x; $x",
+            Value::from_str("t_exp=1").unwrap()
+        );
+        // Overrides are exported to the command only
+        assert_eval_ok!(
+            "def t_show () (export | grep T_OVR); T_OVR=5 t_show | x; $x",
+            Value::from_str("T_OVR=5").unwrap()
+        );
+        assert_eval_ok!("t_x=1; $t_x", Value::Int(1));
+        assert_eval_err!("t_y = 1; unset t_y; $t_y = 2", "Variable not found: $t_y");
+        assert_eval_err!("export t_none", "Variable not found: $t_none");
+    }
+
+    #[test]
+    fn test_declarations() {
+        assert_eval_err!(
+            "readonly t_prefix = /opt; $t_prefix = /tmp",
+            "Cannot assign to read-only variable: $t_prefix"
+        );
+        assert_eval_err!(
+            "readonly t_prefix = /opt; (t_prefix = /tmp)",
+            "Cannot assign to read-only variable: $t_prefix"
+        );
+        assert_eval_err!(
+            "readonly t_prefix = /opt; unset t_prefix",
+            "Cannot unset read-only variable: $t_prefix"
+        );
+        assert_eval_ok!("let t_n: int = 1; $t_n = $t_n + 1; $t_n", Value::Int(2));
+        assert_eval_err!(
+            "let t_n: int = 1; $t_n = hello",
+            "Cannot assign str to int variable: $t_n"
+        );
+        assert_eval_ok!("let t_r: real = 1; $t_r / 2", Value::Real(0.5));
+        assert_eval_err!("let t_x: float = 1", "Unknown type: float");
+
+        // Assignments by pipe, FOR and regex match are checked too
+        assert_eval_err!(
+            "readonly t_p = 1; echo hi | t_p",
+            "Cannot assign to read-only variable: $t_p"
+        );
+        assert_eval_err!(
+            "let t_n: int = 1; echo abc | t_n",
+            "Cannot assign str to int variable: $t_n"
+        );
+        assert_eval_ok!("let t_n: int = 1; echo 5 | t_n; $t_n", Value::Int(5));
+        assert_eval_err!(
+            "readonly t_i = 1; for t_i in 1 2; ()",
+            "Cannot assign to read-only variable: $t_i"
+        );
+        assert_eval_err!(
+            "readonly __match = 1; abc =~ b",
+            "Cannot assign to read-only variable: $__match"
+        );
+
+        // Digit strings in str variables
+        assert_eval_ok!(
+            "let t_s: str = \"010\"; vars | grep \"^t_s=\" | x; $x",
+            Value::from_str("t_s=010").unwrap()
+        );
+        assert_eval_ok!(
+            "let t_s: str = a; sh -c \"echo 007\" | t_s; vars | grep \"^t_s=\" | x; $x",
+            Value::from_str("t_s=007").unwrap()
+        );
+        assert_eval_err!(
+            "let t_s: str = 10",
+            "Cannot assign int to str variable: $t_s"
+        );
+    }
+
+    #[test]
+    fn test_trace() {
+        let (result, errors) = pipe::capture_errors(|| {
+            eval("TRACE = 1; i = 2;\nwhile ($i > 0) (\n    $i = $i - 1);\necho hi | t_x; $t_x")
+        });
+        assert_eq!(result.unwrap(), Value::from_str("hi").unwrap());
+        assert_eq!(
+            String::from_utf8(errors).unwrap(),
+            "+ [1:12] i = 2\n\
+             + [2:1] while ( $i > 0 ) -> true\n\
+             + [3:5] $i = 1\n\
+             + [2:1] while ( $i > 0 ) -> true\n\
+             + [3:5] $i = 0\n\
+             + [2:1] while ( $i > 0 ) -> false\n\
+             + [4:1] echo hi\n\
+             + [4:11] t_x = hi\n"
+        );
+
+        // Assignments are traced before they take effect
+        let (result, errors) =
+            pipe::capture_errors(|| eval("TRACE = 1; let t_r: int = 1; $t_r = abc"));
+        assert!(result.is_err());
+        assert_eq!(
+            String::from_utf8(errors).unwrap(),
+            "+ [1:25] t_r = 1\n+ [1:30] $t_r = abc\n"
+        );
+
+        // Nothing is traced without $TRACE
+        let (_, errors) = pipe::capture_errors(|| eval("i = 1; echo $i | t_x"));
+        assert!(errors.is_empty());
+    }
+
+    #[test]
+    fn test_def_args() {
+        assert_eval_err!(
+            "def t_pair (a b) (echo $a $b); t_pair 1",
+            "t_pair: expected 2 argument(s), got 1"
+        );
+    }
+
+    #[test]
+    fn test_def_no_group() {
+        assert_eval_err!(
+            "def t_bad (a) echo $a",
+            "Parentheses are required around DEF body"
+        );
+    }
+
     #[test]
     fn test_dash_parse() {
         assert_eval_ok!(
@@ -327,4 +793,216 @@ mod tests {
             Value::from_str(&"---Hello---").unwrap()
         );
     }
+
+    #[test]
+    fn test_command_streams() {
+        use crate::cmds::{get_command, Exec};
+        use crate::pipe::{Input, Output, Streams};
+
+        let scope = Interp::new().get_scope();
+        let mut streams = Streams {
+            stdin: Input::Buffer(io::Cursor::new(b"foo\nbar\nbaz\n".to_vec())),
+            stdout: Output::Buffer(Vec::new()),
+            stderr: Output::Null,
+        };
+        let grep = get_command("grep").unwrap();
+        let args = vec!["-n".to_string(), "ba".to_string()];
+        assert!(grep.exec("grep", &args, &scope, &mut streams).is_ok());
+        assert!(matches!(streams.stdout, Output::Buffer(ref buf) if buf == b"2:bar\n3:baz\n"));
+    }
+
+    #[test]
+    fn test_command_flags() {
+        use crate::cmds::{get_command, ArgType, Exec};
+
+        let _ = Interp::new(); // Register the builtins
+        let ls = get_command("ls").unwrap();
+        let flags = ls.cli_flags().unwrap().flags();
+        assert!(flags.iter().any(|f| f.short == 'l' && f.long == "long"));
+        assert_eq!(ls.arg_type(), ArgType::Path);
+        assert_eq!(get_command("cd").unwrap().arg_type(), ArgType::Dir);
+        assert_eq!(get_command("which").unwrap().arg_type(), ArgType::Command);
+        assert!(get_command("echo").unwrap().cli_flags().is_none());
+    }
+
+    #[test]
+    fn test_pipe_status() {
+        assert_eval_ok!(
+            "cp | echo | x; $__pipestatus",
+            Value::from_str("1 0").unwrap()
+        );
+        assert_eval_ok!(
+            "cp | echo | x; $__errors",
+            Value::from_str("cp: Missing source and destination").unwrap()
+        );
+        // Set in the scope that checks the status
+        assert_eval_ok!(
+            "PIPEFAIL = 1; if (cp | echo) (ok) else ($__pipestatus)",
+            Value::from_str("1 0").unwrap()
+        );
+        // The exit code of the program that failed
+        assert_eval_ok!(
+            "def t_exit () (sh -c \"exit 3\"); t_exit | echo | x; $__pipestatus",
+            Value::from_str("3 0").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_pipe_concurrent() {
+        // The stages run at the same time: the endless producer stops once head is done
+        assert_eval_ok!(
+            "while (1) (echo y) | head -l 2 | x; $x",
+            Value::from_str("y\ny").unwrap()
+        );
+        assert_eval_ok!(
+            "while (1) (echo y) | cat | head -l 2 | x; $x",
+            Value::from_str("y\ny").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_pipefail() {
+        assert_eval_ok!("cp | echo Hello | x; $x", Value::from_str("Hello").unwrap());
+        assert_eval_ok!(
+            "PIPEFAIL = 0; cp | echo Hello | x; $x",
+            Value::from_str("Hello").unwrap()
+        );
+        assert_eval_err!(
+            "PIPEFAIL = 1; cp | echo Hello | x",
+            "Missing source and destination"
+        );
+    }
+
+    #[test]
+    fn test_command_substitution() {
+        assert_eval_ok!(
+            "x = \"$(echo Hello   World)!\"; $x",
+            Value::from_str("Hello World!").unwrap()
+        );
+        assert_eval_ok!(
+            "\"$(echo \"in $(echo nested)\")\"",
+            Value::from_str("in nested").unwrap()
+        );
+        assert_eval_ok!(
+            "i = 0; for x in $(echo 1 2 3); ($i = $i + $x)",
+            Value::Int(6)
+        );
+        assert_eval_err!("cp $(echo a b) c", "Extraneous argument");
+        assert_eval_err!(
+            "echo $(echo",
+            "Unbalanced parentheses in command substitution"
+        );
+    }
+
+    #[test]
+    fn test_list() {
+        assert_eval_ok!("x = [1 2 \"three four\"]; ${#x}", Value::Int(3));
+        assert_eval_ok!("x = [a b c]; $x[-1]", Value::from_str("c").unwrap());
+        assert_eval_ok!("x = [a b c d]; $x[1:3] == [b c]", Value::Int(1));
+        assert_eval_ok!("x = [1 [2 3]]; $x[1][1]", Value::Int(3));
+        assert_eval_ok!("x = [1] + [2] + 3; ${#x}", Value::Int(3));
+        assert_eval_ok!("i = 0; for x in [1 \"2 3\"]; ($i = $i + 1)", Value::Int(2));
+        assert_eval_err!("x = [a b c]; cp $x", "Extraneous argument");
+        assert_eval_err!("x = [1 2]; $x[2]", "Index out of range: 2");
+        assert_eval_err!("x = [1 2", "Unbalanced brackets in list");
+        // Only lists and maps are indexed, unless braced; e.g. glob character classes
+        assert_eval_ok!(
+            "p = ab; echo $p[0-9]* | x; $x",
+            Value::from_str("ab[0-9]*").unwrap()
+        );
+        assert_eval_ok!("s = abc; ${s[-1]}", Value::from_str("c").unwrap());
+    }
+
+    #[test]
+    fn test_map() {
+        assert_eval_ok!(
+            "m = {dev: localhost prod: example.com}; $m[prod]",
+            Value::from_str("example.com").unwrap()
+        );
+        assert_eval_ok!(
+            "m = {a: 1}; $m[b] = {c: 2}; $m[b][c] + ${#m}",
+            Value::Int(4)
+        );
+        assert_eval_ok!("m = {a: 1 b: 2}; $m[a] = ; $m == {b: 2}", Value::Int(1));
+        assert_eval_ok!(
+            "m = {a: 1 b: 2}; s = x; for k in keys $m; ($s = $s + $k)",
+            Value::from_str("xab").unwrap()
+        );
+        assert_eval_err!("m = {a: 1}; $m[b]", "Key not found: b");
+        assert_eval_err!("m = {a: 1}; $m[b] = ", "Key not found: b");
+        assert_eval_err!(
+            "x = 1; for k in keys $x; (echo $k)",
+            "Expecting map after KEYS"
+        );
+        assert_eval_err!("m = {a 1}", "Expecting key: value in map: {a 1}");
+    }
+
+    #[test]
+    fn test_background() {
+        assert_eval_err!("i = 1 &", "Expecting command or pipeline before &");
+        assert_eval_err!("fg %1", "%1: no such job");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_background_wait() {
+        assert_eval_ok!(
+            "sleep 0 & wait %1; jobs | x; $x",
+            Value::from_str("").unwrap()
+        );
+        assert_eval_err!("false & wait %1", "exit code: 1");
+    }
+
+    #[test]
+    fn test_prompt() {
+        use crate::prompt::{expand_prompt, git_branch};
+        use colored::Colorize;
+        use std::path::MAIN_SEPARATOR;
+        use std::time::Duration;
+
+        let interp = Interp::new();
+        let mut quit = false;
+        interp.eval(&mut quit, "unset NO_COLOR").unwrap();
+        let scope = interp.get_scope();
+        let expand = |template| expand_prompt(template, &scope, 1, Duration::from_millis(5));
+
+        assert_eq!(expand("%? %D 100%% %x%"), "1 5ms 100% x%");
+
+        let cwd = std::env::current_dir().unwrap();
+        let name = cwd.file_name().unwrap().to_string_lossy().to_string();
+        let parent = cwd.parent().unwrap().to_string_lossy().to_string();
+        scope.insert("HOME".to_string(), Value::Str(parent));
+        assert_eq!(expand("%w"), format!("~{}{}", MAIN_SEPARATOR, name));
+        assert_eq!(expand("%W"), name);
+        scope.insert(
+            "HOME".to_string(),
+            Value::Str(cwd.to_string_lossy().to_string()),
+        );
+        assert_eq!(expand("%w"), "~");
+        scope.insert(
+            "HOME".to_string(),
+            Value::Str(format!("{}x", cwd.display())),
+        );
+        assert_eq!(expand("%w"), cwd.to_string_lossy());
+
+        assert_eq!(
+            expand("%{red}err%{reset} ok"),
+            format!("{} ok", "err".red())
+        );
+        assert_eq!(expand("%{bold}%{red}x"), "x".bold().red().to_string());
+        assert_eq!(expand("%{nope}x"), "x");
+        scope.insert("NO_COLOR".to_string(), Value::Int(1));
+        assert_eq!(expand("%{red}x%{reset}"), "x");
+
+        let dir = std::env::temp_dir().join(format!("mysh_test_git_{}", std::process::id()));
+        let sub = dir.join("a").join("b");
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::create_dir_all(&sub).unwrap();
+        assert_eq!(git_branch(&sub), None);
+        std::fs::write(dir.join(".git").join("HEAD"), "ref: refs/heads/main\n").unwrap();
+        assert_eq!(git_branch(&sub).as_deref(), Some("main"));
+        std::fs::write(dir.join(".git").join("HEAD"), "0123456789abcdef\n").unwrap();
+        assert_eq!(git_branch(&dir).as_deref(), Some("0123456"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }